@@ -1,8 +1,11 @@
 use crate::send_establish_introduction::send_establish_introduction;
 use crate::send_establish_rendezvous::send_establish_rendezvous;
 use crate::{
-    establish_circuit, get_state, send_create, send_data, send_extend, send_introduce1,
-    send_rendezvous1, start_relay, start_user, Logger, Relay, User,
+    add_authorized_client, begin_stream, build_circuit, connect_stream, create_client_credentials,
+    destroy_circuit, end_stream, establish_circuit, get_state, ping_circuit, read_stream,
+    remove_authorized_client, remove_client_credentials, resolve_hostname, send_create, send_data,
+    send_drop, send_extend, send_introduce1, send_rendezvous1, send_stream_data, start_http_proxy,
+    start_relay, start_socks_proxy, start_user, truncate_circuit, Logger, Relay, User,
 };
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
@@ -51,6 +54,8 @@ impl Api {
                     .app_data(web::Data::new(relays.clone()))
                     .app_data(web::Data::new(users.clone()))
                     .service(start_relay)
+                    .service(start_http_proxy)
+                    .service(start_socks_proxy)
                     .service(start_user)
                     .service(send_create)
                     .service(send_extend)
@@ -62,6 +67,21 @@ impl Api {
                     .service(send_establish_introduction)
                     .service(send_establish_rendezvous)
                     .service(send_begin)
+                    .service(build_circuit)
+                    .service(destroy_circuit)
+                    .service(truncate_circuit)
+                    .service(begin_stream)
+                    .service(send_drop)
+                    .service(ping_circuit)
+                    .service(send_stream_data)
+                    .service(read_stream)
+                    .service(end_stream)
+                    .service(connect_stream)
+                    .service(resolve_hostname)
+                    .service(add_authorized_client)
+                    .service(remove_authorized_client)
+                    .service(create_client_credentials)
+                    .service(remove_client_credentials)
             })
             .disable_signals()
             .bind(address)
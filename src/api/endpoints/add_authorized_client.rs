@@ -0,0 +1,40 @@
+use crate::{Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct AddAuthorizedClientBody {
+    pub name: String,
+    /// PEM encoded RSA public key of the client.
+    pub public_key: String,
+}
+
+#[post("/users/{user_id}/add_authorized_client")]
+pub async fn add_authorized_client(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<AddAuthorizedClientBody>,
+) -> impl Responder {
+    let result: Result<()> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        user.add_authorized_client(body.name.clone(), body.public_key.as_bytes().to_vec())
+            .context("Failed to add authorized client")?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            Logger::error("API", format!("Error in add_authorized_client: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{CircuitId, IsolationKey, Logger, RelayId, StreamId, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct BeginStreamBody {
+    pub begin_relay_id: RelayId,
+    #[serde(default)]
+    pub isolation: IsolationKey,
+}
+
+#[derive(Serialize)]
+pub struct BeginStreamResponse {
+    pub stream_id: StreamId,
+    pub circuit_id: CircuitId,
+}
+
+#[post("/users/{user_id}/begin_stream")]
+pub async fn begin_stream(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<BeginStreamBody>,
+) -> impl Responder {
+    let result: Result<BeginStreamResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let (stream_id, circuit_id) = user
+            .begin_stream(body.isolation.clone(), body.begin_relay_id)
+            .context("Failed to begin stream")?;
+        Ok(BeginStreamResponse {
+            stream_id,
+            circuit_id,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in begin_stream: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
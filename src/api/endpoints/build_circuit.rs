@@ -0,0 +1,46 @@
+use crate::{CircuitId, CircuitPurpose, Logger, RelayId, User, UserId, DEFAULT_CIRCUIT_LENGTH};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct BuildCircuitBody {
+    pub purpose: CircuitPurpose,
+    pub length: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct BuildCircuitResponse {
+    pub circuit_id: CircuitId,
+    pub path: Vec<RelayId>,
+}
+
+#[post("/users/{user_id}/build_circuit")]
+pub async fn build_circuit(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<BuildCircuitBody>,
+) -> impl Responder {
+    let result: Result<BuildCircuitResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let (circuit_id, path) = user
+            .build_circuit_with_length(body.purpose, body.length.unwrap_or(DEFAULT_CIRCUIT_LENGTH))
+            .context("Failed to build circuit")?;
+        Ok(BuildCircuitResponse { circuit_id, path })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in build_circuit: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{CircuitId, IsolationKey, Logger, StreamId, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct ConnectStreamBody {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub isolation: IsolationKey,
+}
+
+#[derive(Serialize)]
+pub struct ConnectStreamResponse {
+    pub stream_id: StreamId,
+    pub circuit_id: CircuitId,
+}
+
+#[post("/users/{user_id}/connect_stream")]
+pub async fn connect_stream(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<ConnectStreamBody>,
+) -> impl Responder {
+    let result: Result<ConnectStreamResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let (stream_id, circuit_id) = user
+            .connect(body.isolation.clone(), &body.host, body.port)
+            .context("Failed to connect stream")?;
+        Ok(ConnectStreamResponse {
+            stream_id,
+            circuit_id,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in connect_stream: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
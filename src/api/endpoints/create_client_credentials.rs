@@ -0,0 +1,48 @@
+use crate::{Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct CreateClientCredentialsBody {
+    /// Address of the restricted onion service.
+    pub address: UserId,
+}
+
+#[derive(Serialize)]
+pub struct CreateClientCredentialsResponse {
+    /// PEM encoded RSA public key for the service to authorize.
+    pub public_key: String,
+}
+
+#[post("/users/{user_id}/create_client_credentials")]
+pub async fn create_client_credentials(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<CreateClientCredentialsBody>,
+) -> impl Responder {
+    let result: Result<CreateClientCredentialsResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let public_key = user
+            .create_client_credentials(body.address)
+            .context("Failed to create client credentials")?;
+        Ok(CreateClientCredentialsResponse {
+            public_key: String::from_utf8(public_key)?,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in create_client_credentials: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{CircuitId, DestroyReason, Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct DestroyCircuitBody {
+    pub circuit_id: CircuitId,
+    pub reason: Option<DestroyReason>,
+}
+
+#[post("/users/{user_id}/destroy_circuit")]
+pub async fn destroy_circuit(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<DestroyCircuitBody>,
+) -> impl Responder {
+    let result: Result<()> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        user.destroy_circuit(
+            body.circuit_id,
+            body.reason.unwrap_or(DestroyReason::Requested),
+        )
+        .context("Failed to destroy circuit")?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            Logger::error("API", format!("Error in destroy_circuit: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
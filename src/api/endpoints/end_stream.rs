@@ -0,0 +1,39 @@
+use crate::{EndReason, Logger, StreamId, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct EndStreamBody {
+    pub stream_id: StreamId,
+    pub reason: Option<EndReason>,
+}
+
+#[post("/users/{user_id}/end_stream")]
+pub async fn end_stream(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<EndStreamBody>,
+) -> impl Responder {
+    let result: Result<()> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        user.end_stream(body.stream_id, body.reason.unwrap_or(EndReason::Done))
+            .context("Failed to end stream")?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            Logger::error("API", format!("Error in end_stream: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
@@ -1,14 +1,14 @@
-use crate::{CircuitId, RelayId, User, UserId};
+use crate::{CircuitId, Logger, RelayId, User, UserId};
 use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
 use serde::Deserialize;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 #[derive(Deserialize)]
 pub struct EstablishCircuitBody {
     pub circuit_id: CircuitId,
-    pub relay_address_1: RelayId,
-    pub relay_address_2: RelayId,
-    pub relay_address_3: RelayId,
+    pub relays: Vec<RelayId>,
 }
 
 #[post("/users/{user_id}/establish_circuit")]
@@ -17,17 +17,23 @@ async fn establish_circuit(
     user_id: web::Path<UserId>,
     body: web::Json<EstablishCircuitBody>,
 ) -> impl Responder {
-    let data_lock = data.lock().unwrap();
-    let user = data_lock
-        .iter()
-        .find(|u| u.user_descriptor.id == *user_id)
-        .unwrap();
-    user.establish_circuit(
-        body.circuit_id,
-        body.relay_address_1,
-        body.relay_address_2,
-        body.relay_address_3,
-    )
-    .unwrap();
-    HttpResponse::Ok().finish()
+    let result: Result<()> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        user.establish_circuit(body.circuit_id, body.relays.clone())
+            .context("Failed to establish circuit")?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            Logger::error("API", format!("Error in establish_circuit: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use crate::{
-    CircuitId, Handshake, IntroductionPointId, Logger, Relay, RelayId, RendezvousCookieId,
-    StreamId, User, UserId,
+    CircuitBuildStats, CircuitId, CircuitLatency, CircuitPurpose, FlowWindow, GuardSet, Handshake,
+    IntroductionPointId, IsolationKey, Logger, Relay, RelayId, RendezvousCookieId, RttStats,
+    StreamId, StreamInfo, User, UserId,
 };
 use actix_web::{get, web, HttpResponse, Responder};
 use serde::Serialize;
@@ -18,11 +19,28 @@ pub struct UserState {
     pub nickname: String,
     pub rsa_public_key: Vec<u8>,
     pub introduction_points: HashMap<IntroductionPointId, RelayId>,
+    /// Whether the introduction points are only published to authorized
+    /// clients.
+    pub restricted: bool,
+    /// Clients allowed to read the introduction points, by name.
+    pub authorized_clients: Vec<String>,
+    /// Restricted onion services this user holds credentials for.
+    pub client_credentials: Vec<UserId>,
+    /// INTRODUCE2s dropped because they were seen before.
+    pub replayed_introductions: u64,
     pub circuits: HashMap<CircuitId, Vec<RelayId>>,
     pub handshakes: HashMap<RelayId, Handshake>,
     pub rendezvous_cookies: HashMap<RendezvousCookieId, RelayId>,
     pub connected_users: HashMap<RendezvousCookieId, Handshake>,
     pub streams: HashMap<StreamId, RelayId>,
+    pub stream_info: HashMap<StreamId, StreamInfo>,
+    pub guards: GuardSet,
+    pub circuit_pool: HashMap<CircuitPurpose, Vec<CircuitId>>,
+    pub circuit_build: CircuitBuildStats,
+    pub circuit_isolation: HashMap<CircuitId, IsolationKey>,
+    pub circuit_latency: HashMap<CircuitId, CircuitLatency>,
+    pub relay_latency: HashMap<RelayId, RttStats>,
+    pub circuit_windows: HashMap<CircuitId, FlowWindow>,
     pub logs: Vec<String>,
 }
 
@@ -32,9 +50,14 @@ pub struct RelayState {
     pub id: RelayId,
     pub is_rendezvous_point: bool,
     pub is_introduction_point: bool,
+    /// INTRODUCE1s dropped because they were seen before.
+    pub replayed_introductions: u64,
     pub nickname: String,
     pub circuits: HashMap<CircuitId, RelayId>,
     pub streams: HashMap<StreamId, RelayId>,
+    pub stream_circuits: HashMap<StreamId, CircuitId>,
+    /// Destination of each stream exiting the network at the relay.
+    pub exit_streams: HashMap<StreamId, String>,
     pub logs: Vec<String>,
 }
 
@@ -1,25 +1,59 @@
+pub mod add_authorized_client;
+pub mod begin_stream;
+pub mod build_circuit;
+pub mod connect_stream;
+pub mod create_client_credentials;
+pub mod destroy_circuit;
+pub mod end_stream;
 pub mod establish_circuit;
 pub mod get_state;
+pub mod ping_circuit;
+pub mod read_stream;
+pub mod remove_authorized_client;
+pub mod remove_client_credentials;
+pub mod resolve_hostname;
 pub mod send_begin;
 pub mod send_create;
 pub mod send_data;
+pub mod send_drop;
 pub mod send_establish_introduction;
 pub mod send_establish_rendezvous;
 pub mod send_extend;
 pub mod send_introduce1;
 pub mod send_rendezvous1;
+pub mod send_stream_data;
+pub mod start_http_proxy;
 pub mod start_relay;
+pub mod start_socks_proxy;
 pub mod start_user;
+pub mod truncate_circuit;
 
+pub use add_authorized_client::*;
+pub use begin_stream::*;
+pub use build_circuit::*;
+pub use connect_stream::*;
+pub use create_client_credentials::*;
+pub use destroy_circuit::*;
+pub use end_stream::*;
 pub use establish_circuit::*;
 pub use get_state::*;
+pub use ping_circuit::*;
+pub use read_stream::*;
+pub use remove_authorized_client::*;
+pub use remove_client_credentials::*;
+pub use resolve_hostname::*;
 pub use send_begin::*;
 pub use send_create::*;
 pub use send_data::*;
+pub use send_drop::*;
 pub use send_establish_introduction::*;
 pub use send_establish_rendezvous::*;
 pub use send_extend::*;
 pub use send_introduce1::*;
 pub use send_rendezvous1::*;
+pub use send_stream_data::*;
+pub use start_http_proxy::*;
 pub use start_relay::*;
+pub use start_socks_proxy::*;
 pub use start_user::*;
+pub use truncate_circuit::*;
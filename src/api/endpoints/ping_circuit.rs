@@ -0,0 +1,54 @@
+use crate::{CircuitId, Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct PingCircuitBody {
+    pub circuit_id: CircuitId,
+    /// Hop to ping, 0 being the guard. Every hop is pinged when missing.
+    pub hop: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct PingCircuitResponse {
+    /// Round trip times in milliseconds, in hop order.
+    pub rtt_ms: Vec<f64>,
+}
+
+#[post("/users/{user_id}/ping_circuit")]
+pub async fn ping_circuit(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<PingCircuitBody>,
+) -> impl Responder {
+    let result: Result<PingCircuitResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let rtts = match body.hop {
+            Some(hop) => user.ping(body.circuit_id, hop).map(|rtt| vec![rtt]),
+            None => user.ping_circuit(body.circuit_id),
+        }
+        .context("Failed to ping circuit")?;
+        Ok(PingCircuitResponse {
+            rtt_ms: rtts
+                .iter()
+                .map(|rtt| rtt.as_micros() as f64 / 1000.0)
+                .collect(),
+        })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in ping_circuit: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
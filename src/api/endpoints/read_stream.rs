@@ -0,0 +1,44 @@
+use crate::{Logger, StreamId, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct ReadStreamBody {
+    pub stream_id: StreamId,
+}
+
+#[derive(Serialize)]
+pub struct ReadStreamResponse {
+    pub data: Vec<u8>,
+}
+
+#[post("/users/{user_id}/read_stream")]
+pub async fn read_stream(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<ReadStreamBody>,
+) -> impl Responder {
+    let result: Result<ReadStreamResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let data = user
+            .read_stream(body.stream_id)
+            .context("Failed to read stream")?;
+        Ok(ReadStreamResponse { data })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in read_stream: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{IsolationKey, Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct ResolveHostnameBody {
+    pub hostname: String,
+    #[serde(default)]
+    pub isolation: IsolationKey,
+}
+
+#[derive(Serialize)]
+pub struct ResolveHostnameResponse {
+    pub addresses: Vec<IpAddr>,
+}
+
+#[post("/users/{user_id}/resolve_hostname")]
+pub async fn resolve_hostname(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<ResolveHostnameBody>,
+) -> impl Responder {
+    let result: Result<ResolveHostnameResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let addresses = user
+            .resolve(body.isolation.clone(), &body.hostname)
+            .context("Failed to resolve hostname")?;
+        Ok(ResolveHostnameResponse { addresses })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in resolve_hostname: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
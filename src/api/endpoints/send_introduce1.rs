@@ -1,5 +1,6 @@
 use crate::{
-    CircuitId, IntroductionPointId, Logger, RelayId, RendezvousCookieId, StreamId, User, UserId,
+    CircuitId, Directory, IntroductionAuth, IntroductionPointId, Logger, RelayId,
+    RendezvousCookieId, StreamId, User, UserId,
 };
 use actix_web::{post, web, HttpResponse, Responder};
 use anyhow::{Context, Result};
@@ -14,6 +15,10 @@ pub struct SendIntroduce1Body {
     pub stream_id: StreamId,
     pub rendezvous_cookie: RendezvousCookieId,
     pub introduction_rsa_public: Vec<u8>,
+    /// Key of the introduction point, looked up in the published
+    /// descriptors when missing.
+    #[serde(default)]
+    pub introduction_key: Option<Vec<u8>>,
     pub circuit_id: CircuitId,
 }
 
@@ -29,9 +34,17 @@ pub async fn send_introduce1(
             .iter()
             .find(|u| u.user_descriptor.id == *user_id)
             .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let introduction_key = match &body.introduction_key {
+            Some(introduction_key) => introduction_key.clone(),
+            None => Directory::get_introduction_key(body.introduction_id)
+                .ok_or_else(|| anyhow::anyhow!("Introduction key not found"))?,
+        };
         user.send_introduce1(
             body.relay_id,
-            body.introduction_id,
+            IntroductionAuth {
+                introduction_id: body.introduction_id,
+                introduction_key,
+            },
             body.stream_id,
             body.rendezvous_cookie,
             body.introduction_rsa_public.clone(),
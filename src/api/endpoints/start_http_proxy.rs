@@ -0,0 +1,45 @@
+use crate::{Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct StartHttpProxyBody {
+    pub address: SocketAddr,
+}
+
+#[derive(Serialize)]
+pub struct StartHttpProxyResponse {
+    pub address: SocketAddr,
+}
+
+#[post("/users/{user_id}/start_http_proxy")]
+pub async fn start_http_proxy(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<StartHttpProxyBody>,
+) -> impl Responder {
+    let result: Result<StartHttpProxyResponse> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let address = user
+            .start_http_proxy(body.address)
+            .context("Failed to start HTTP proxy")?;
+        Ok(StartHttpProxyResponse { address })
+    }
+    .await;
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            Logger::error("API", format!("Error in start_http_proxy: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
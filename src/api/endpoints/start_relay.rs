@@ -1,4 +1,4 @@
-use crate::{Logger, Relay};
+use crate::{ExitPolicy, Logger, Relay};
 use actix_web::{post, web, HttpResponse, Responder};
 use serde::Deserialize;
 use std::sync::Arc;
@@ -7,6 +7,13 @@ use tokio::sync::Mutex;
 #[derive(Deserialize)]
 pub struct StartRelayBody {
     pub nickname: String,
+    /// Whether the relay exits to destinations outside the network.
+    #[serde(default)]
+    pub exit: bool,
+    /// Exit policy rules such as "reject *:25", rejecting private
+    /// destinations if missing.
+    #[serde(default)]
+    pub exit_policy: Vec<String>,
 }
 
 #[post("/start_relay")]
@@ -18,8 +25,21 @@ async fn start_relay(
         "API",
         format!("Starting relay with nickname: {}", body.nickname),
     );
+    let rules: Vec<&str> = body.exit_policy.iter().map(String::as_str).collect();
+    let exit_policy = match ExitPolicy::parse(&rules) {
+        Ok(_) if rules.is_empty() => ExitPolicy::default(),
+        Ok(exit_policy) => exit_policy,
+        Err(e) => {
+            Logger::error("API", format!("Error in start_relay: {}", e));
+            return HttpResponse::InternalServerError()
+                .json(format!("Internal server error: {}", e));
+        }
+    };
     let mut relays = data.lock().await;
-    let relay = Relay::new(body.nickname.clone());
+    let relay = match body.exit {
+        true => Relay::new_exit(body.nickname.clone(), exit_policy),
+        false => Relay::new(body.nickname.clone()),
+    };
     relay.start();
     relays.push(relay);
     HttpResponse::Ok().finish()
@@ -0,0 +1,40 @@
+use crate::{CircuitId, Logger, User, UserId};
+use actix_web::{post, web, HttpResponse, Responder};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize)]
+pub struct TruncateCircuitBody {
+    pub circuit_id: CircuitId,
+    /// Hop that becomes the last one, 0 being the guard.
+    pub hop: usize,
+}
+
+#[post("/users/{user_id}/truncate_circuit")]
+pub async fn truncate_circuit(
+    data: web::Data<Arc<Mutex<Vec<User>>>>,
+    user_id: web::Path<UserId>,
+    body: web::Json<TruncateCircuitBody>,
+) -> impl Responder {
+    let result: Result<()> = async {
+        let data_lock = data.lock().await;
+        let user = data_lock
+            .iter()
+            .find(|u| u.user_descriptor.id == *user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        user.truncate_circuit(body.circuit_id, body.hop)
+            .context("Failed to truncate circuit")?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            Logger::error("API", format!("Error in truncate_circuit: {}", e));
+            HttpResponse::InternalServerError().json(format!("Internal server error: {}", e))
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Timeout used until enough builds were observed to estimate one.
+pub const DEFAULT_BUILD_TIMEOUT: Duration = Duration::from_secs(60);
+pub const MIN_BUILD_TIMEOUT: Duration = Duration::from_millis(10);
+pub const MIN_BUILD_TIMES_TO_OBSERVE: usize = 100;
+pub const MAX_BUILD_TIMES: usize = 1000;
+/// Share of circuits expected to complete before the timeout.
+pub const BUILD_TIME_QUANTILE: f64 = 0.8;
+/// Width of the histogram bins used to find the most common build times.
+const BIN_WIDTH_MS: u64 = 10;
+/// Number of most common bins averaged to estimate the Pareto scale.
+const NUM_MODES: usize = 10;
+/// Paths tried by `User::build_circuit` before giving up.
+pub const MAX_BUILD_ATTEMPTS: usize = 3;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CircuitBuildStats {
+    pub timeout_ms: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub timed_out: u64,
+    pub retries: u64,
+    pub observed_build_times: usize,
+    pub average_build_time_ms: Option<u64>,
+}
+
+/// Tor-style circuit build timeout: build times are fitted to a Pareto
+/// distribution and the timeout is set so that `BUILD_TIME_QUANTILE` of
+/// circuits complete before it.
+pub struct CircuitBuildTimeout {
+    build_times: VecDeque<u64>,
+    timeout: Duration,
+    /// Timeout set by the user, replacing the learned one.
+    fixed_timeout: Option<Duration>,
+    stats: CircuitBuildStats,
+}
+
+impl Default for CircuitBuildTimeout {
+    fn default() -> Self {
+        Self {
+            build_times: VecDeque::new(),
+            timeout: DEFAULT_BUILD_TIMEOUT,
+            fixed_timeout: None,
+            stats: CircuitBuildStats::default(),
+        }
+    }
+}
+
+impl CircuitBuildTimeout {
+    pub fn timeout(&self) -> Duration {
+        self.fixed_timeout.unwrap_or(self.timeout)
+    }
+
+    /// Uses `timeout` for every build instead of the learned timeout, or goes
+    /// back to learning it with None. Build times are recorded either way.
+    pub fn set_fixed_timeout(&mut self, timeout: Option<Duration>) {
+        self.fixed_timeout = timeout;
+    }
+
+    pub fn record_success(&mut self, build_time: Duration) {
+        if self.build_times.len() >= MAX_BUILD_TIMES {
+            self.build_times.pop_front();
+        }
+        self.build_times.push_back(build_time.as_millis() as u64);
+        self.stats.succeeded += 1;
+        if let Some(timeout) = self.estimate() {
+            self.timeout = timeout;
+        }
+    }
+
+    pub fn record_timeout(&mut self) {
+        self.stats.timed_out += 1;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.stats.failed += 1;
+    }
+
+    pub fn record_retry(&mut self) {
+        self.stats.retries += 1;
+    }
+
+    pub fn stats(&self) -> CircuitBuildStats {
+        let count = self.build_times.len();
+        CircuitBuildStats {
+            timeout_ms: self.timeout().as_millis() as u64,
+            observed_build_times: count,
+            average_build_time_ms: (count > 0)
+                .then(|| self.build_times.iter().sum::<u64>() / count as u64),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Scale of the Pareto distribution, estimated as the weighted average of
+    /// the most common build time bins.
+    fn pareto_scale(&self) -> f64 {
+        let mut bins: HashMap<u64, u64> = HashMap::new();
+        for build_time in self.build_times.iter() {
+            *bins.entry(build_time / BIN_WIDTH_MS).or_default() += 1;
+        }
+        let mut bins: Vec<(u64, u64)> = bins.into_iter().collect();
+        bins.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let modes = &bins[..bins.len().min(NUM_MODES)];
+        let total: u64 = modes.iter().map(|(_, count)| count).sum();
+        let weighted: u64 = modes
+            .iter()
+            .map(|(bin, count)| (bin * BIN_WIDTH_MS + BIN_WIDTH_MS / 2) * count)
+            .sum();
+        weighted as f64 / total as f64
+    }
+
+    fn estimate(&self) -> Option<Duration> {
+        if self.build_times.len() < MIN_BUILD_TIMES_TO_OBSERVE {
+            return None;
+        }
+        let scale = self.pareto_scale();
+        // maximum likelihood estimate of the shape, build times below the
+        // scale count as the scale itself
+        let log_sum: f64 = self
+            .build_times
+            .iter()
+            .map(|build_time| (*build_time as f64).max(scale).ln() - scale.ln())
+            .sum();
+        let timeout_ms = if log_sum > 0.0 {
+            let shape = self.build_times.len() as f64 / log_sum;
+            scale / (1.0 - BUILD_TIME_QUANTILE).powf(1.0 / shape)
+        } else {
+            scale
+        };
+        Some(Duration::from_millis(timeout_ms as u64).max(MIN_BUILD_TIMEOUT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build times following a Pareto distribution with the given scale and
+    /// shape, taken at evenly spaced quantiles.
+    fn pareto_build_times(count: usize, scale: f64, shape: f64) -> Vec<Duration> {
+        (0..count)
+            .map(|i| {
+                let quantile = i as f64 / count as f64;
+                let build_time = scale / (1.0 - quantile).powf(1.0 / shape);
+                Duration::from_millis(build_time as u64)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_default_timeout_until_enough_builds() {
+        let mut build_timeout = CircuitBuildTimeout::default();
+        for build_time in pareto_build_times(MIN_BUILD_TIMES_TO_OBSERVE - 1, 100.0, 2.0) {
+            build_timeout.record_success(build_time);
+        }
+        assert_eq!(build_timeout.timeout(), DEFAULT_BUILD_TIMEOUT);
+        build_timeout.record_success(Duration::from_millis(100));
+        assert!(build_timeout.timeout() < DEFAULT_BUILD_TIMEOUT);
+    }
+
+    #[test]
+    fn test_timeout_follows_build_times() {
+        let mut build_timeout = CircuitBuildTimeout::default();
+        for build_time in pareto_build_times(500, 100.0, 2.0) {
+            build_timeout.record_success(build_time);
+        }
+        // the 80th percentile of this distribution is about 224ms
+        let timeout = build_timeout.timeout().as_millis();
+        assert!((180..=300).contains(&timeout), "timeout was {}ms", timeout);
+
+        for build_time in pareto_build_times(MAX_BUILD_TIMES, 1000.0, 2.0) {
+            build_timeout.record_success(build_time);
+        }
+        let timeout = build_timeout.timeout().as_millis();
+        assert!(
+            (1800..=3000).contains(&timeout),
+            "timeout was {}ms",
+            timeout
+        );
+    }
+
+    #[test]
+    fn test_minimum_timeout() {
+        let mut build_timeout = CircuitBuildTimeout::default();
+        for _ in 0..MIN_BUILD_TIMES_TO_OBSERVE {
+            build_timeout.record_success(Duration::ZERO);
+        }
+        assert_eq!(build_timeout.timeout(), MIN_BUILD_TIMEOUT);
+    }
+
+    #[test]
+    fn test_fixed_timeout() {
+        let mut build_timeout = CircuitBuildTimeout::default();
+        build_timeout.set_fixed_timeout(Some(Duration::from_secs(5)));
+        for _ in 0..MIN_BUILD_TIMES_TO_OBSERVE {
+            build_timeout.record_success(Duration::ZERO);
+        }
+        assert_eq!(build_timeout.timeout(), Duration::from_secs(5));
+        build_timeout.set_fixed_timeout(None);
+        assert_eq!(build_timeout.timeout(), MIN_BUILD_TIMEOUT);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut build_timeout = CircuitBuildTimeout::default();
+        build_timeout.record_success(Duration::from_millis(10));
+        build_timeout.record_success(Duration::from_millis(30));
+        build_timeout.record_timeout();
+        build_timeout.record_failure();
+        build_timeout.record_retry();
+        assert_eq!(
+            build_timeout.stats(),
+            CircuitBuildStats {
+                timeout_ms: DEFAULT_BUILD_TIMEOUT.as_millis() as u64,
+                succeeded: 2,
+                failed: 1,
+                timed_out: 1,
+                retries: 1,
+                observed_build_times: 2,
+                average_build_time_ms: Some(20),
+            }
+        );
+    }
+}
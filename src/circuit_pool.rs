@@ -0,0 +1,196 @@
+use crate::{CircuitId, CircuitPurpose};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CircuitPoolConfig {
+    /// Number of ready circuits to keep for each purpose.
+    pub targets: HashMap<CircuitPurpose, usize>,
+    /// Ready circuits older than this are replaced.
+    pub max_circuit_age: Duration,
+    /// How often the pool is checked and topped up.
+    pub check_interval: Duration,
+}
+
+impl Default for CircuitPoolConfig {
+    fn default() -> Self {
+        Self {
+            targets: HashMap::from([
+                (CircuitPurpose::General, 2),
+                (CircuitPurpose::Introduction, 1),
+                (CircuitPurpose::Rendezvous, 1),
+                (CircuitPurpose::HSDir, 1),
+            ]),
+            max_circuit_age: Duration::from_secs(600),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+struct PooledCircuit {
+    circuit_id: CircuitId,
+    built_at: Instant,
+}
+
+/// Circuits built ahead of time, waiting to be handed out by purpose.
+#[derive(Default)]
+pub struct CircuitPool {
+    pub config: Option<CircuitPoolConfig>,
+    ready: HashMap<CircuitPurpose, Vec<PooledCircuit>>,
+}
+
+impl CircuitPool {
+    pub fn is_running(&self) -> bool {
+        self.config.is_some()
+    }
+
+    pub fn add(&mut self, purpose: CircuitPurpose, circuit_id: CircuitId) {
+        self.ready.entry(purpose).or_default().push(PooledCircuit {
+            circuit_id,
+            built_at: Instant::now(),
+        });
+    }
+
+    /// Hands out the most recently built circuit for `purpose`.
+    pub fn take(&mut self, purpose: CircuitPurpose) -> Option<CircuitId> {
+        self.take_matching(purpose, |_| true)
+    }
+
+    /// Hands out the most recently built circuit for `purpose` accepted by
+    /// `is_suitable`, leaving the others in the pool.
+    pub fn take_matching(
+        &mut self,
+        purpose: CircuitPurpose,
+        is_suitable: impl Fn(&CircuitId) -> bool,
+    ) -> Option<CircuitId> {
+        let circuits = self.ready.get_mut(&purpose)?;
+        let index = circuits
+            .iter()
+            .rposition(|circuit| is_suitable(&circuit.circuit_id))?;
+        Some(circuits.remove(index).circuit_id)
+    }
+
+    /// Drops circuits that are no longer alive or are older than the
+    /// configured maximum age. Returns the expired circuits that are still
+    /// alive so the caller can tear them down.
+    pub fn prune(&mut self, is_alive: impl Fn(&CircuitId) -> bool) -> Vec<CircuitId> {
+        let max_circuit_age = match &self.config {
+            Some(config) => config.max_circuit_age,
+            None => return vec![],
+        };
+        let mut expired = vec![];
+        for circuits in self.ready.values_mut() {
+            circuits.retain(|circuit| {
+                if !is_alive(&circuit.circuit_id) {
+                    return false;
+                }
+                if circuit.built_at.elapsed() >= max_circuit_age {
+                    expired.push(circuit.circuit_id);
+                    return false;
+                }
+                true
+            });
+        }
+        expired
+    }
+
+    /// Number of circuits to build for each purpose to reach the targets.
+    pub fn missing(&self) -> Vec<(CircuitPurpose, usize)> {
+        let Some(config) = &self.config else {
+            return vec![];
+        };
+        config
+            .targets
+            .iter()
+            .filter_map(|(purpose, target)| {
+                let ready = self.ready.get(purpose).map_or(0, |c| c.len());
+                (ready < *target).then(|| (*purpose, target - ready))
+            })
+            .collect()
+    }
+
+    pub fn ready_circuits(&self) -> HashMap<CircuitPurpose, Vec<CircuitId>> {
+        self.ready
+            .iter()
+            .map(|(purpose, circuits)| {
+                (
+                    *purpose,
+                    circuits.iter().map(|circuit| circuit.circuit_id).collect(),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_pool(max_circuit_age: Duration) -> CircuitPool {
+        CircuitPool {
+            config: Some(CircuitPoolConfig {
+                targets: HashMap::from([(CircuitPurpose::General, 2)]),
+                max_circuit_age,
+                check_interval: Duration::from_millis(10),
+            }),
+            ready: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_missing_circuits() {
+        let mut pool = create_pool(Duration::from_secs(60));
+        assert_eq!(pool.missing(), vec![(CircuitPurpose::General, 2)]);
+        pool.add(CircuitPurpose::General, CircuitId::new_v4());
+        assert_eq!(pool.missing(), vec![(CircuitPurpose::General, 1)]);
+        pool.add(CircuitPurpose::General, CircuitId::new_v4());
+        assert!(pool.missing().is_empty());
+    }
+
+    #[test]
+    fn test_take_circuit() {
+        let mut pool = create_pool(Duration::from_secs(60));
+        let circuit_id = CircuitId::new_v4();
+        pool.add(CircuitPurpose::General, circuit_id);
+        assert_eq!(pool.take(CircuitPurpose::Rendezvous), None);
+        assert_eq!(pool.take(CircuitPurpose::General), Some(circuit_id));
+        assert_eq!(pool.take(CircuitPurpose::General), None);
+
+        let older = CircuitId::new_v4();
+        let newer = CircuitId::new_v4();
+        pool.add(CircuitPurpose::General, older);
+        pool.add(CircuitPurpose::General, newer);
+        assert_eq!(
+            pool.take_matching(CircuitPurpose::General, |circuit_id| *circuit_id == older),
+            Some(older)
+        );
+        assert_eq!(pool.take_matching(CircuitPurpose::General, |_| false), None);
+        assert_eq!(pool.take(CircuitPurpose::General), Some(newer));
+    }
+
+    #[test]
+    fn test_prune_failed_and_expired_circuits() {
+        let mut pool = create_pool(Duration::from_secs(60));
+        let alive = CircuitId::new_v4();
+        let failed = CircuitId::new_v4();
+        pool.add(CircuitPurpose::General, alive);
+        pool.add(CircuitPurpose::General, failed);
+        assert!(pool.prune(|circuit_id| *circuit_id != failed).is_empty());
+        assert_eq!(pool.ready_circuits()[&CircuitPurpose::General], vec![alive]);
+
+        let mut pool = create_pool(Duration::ZERO);
+        pool.add(CircuitPurpose::General, alive);
+        assert_eq!(pool.prune(|_| true), vec![alive]);
+        assert_eq!(pool.missing(), vec![(CircuitPurpose::General, 2)]);
+    }
+
+    #[test]
+    fn test_stopped_pool() {
+        let mut pool = CircuitPool::default();
+        assert!(!pool.is_running());
+        assert!(pool.missing().is_empty());
+        pool.add(CircuitPurpose::General, CircuitId::new_v4());
+        assert!(pool.prune(|_| true).is_empty());
+    }
+}
@@ -3,10 +3,14 @@ use anyhow::Result;
 use lazy_static::lazy_static;
 use std::{
     collections::HashMap,
-    sync::{mpsc, Mutex},
+    sync::{mpsc, Mutex, MutexGuard},
 };
 use uuid::Uuid;
 
+/// Largest cell accepted by `Communication::send`. Larger messages must be
+/// split into several cells.
+pub const MAX_CELL_SIZE: usize = 64 * 1024;
+
 lazy_static! {
     pub static ref communication: Communication = Communication {
         connections: Mutex::new(HashMap::new()),
@@ -18,14 +22,36 @@ pub struct Communication {
 }
 
 impl Communication {
+    // A thread panicking while holding the lock must not take the whole
+    // network down with it.
+    fn connections() -> MutexGuard<'static, HashMap<Uuid, mpsc::Sender<(Uuid, RelayCell)>>> {
+        communication
+            .connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
     pub fn register(id: Uuid) -> mpsc::Receiver<(Uuid, RelayCell)> {
         let (tx, rx) = mpsc::channel();
-        communication.connections.lock().unwrap().insert(id, tx);
+        Communication::connections().insert(id, tx);
         rx
     }
 
+    /// Drops the channel of `id`, which ends its receive loop once the
+    /// pending cells are read.
+    pub fn unregister(id: Uuid) {
+        Communication::connections().remove(&id);
+    }
+
     pub fn send(sender: Uuid, receiver: Uuid, cell: RelayCell) -> Result<()> {
-        let connections = communication.connections.lock().unwrap();
+        if cell.payload.len() > MAX_CELL_SIZE {
+            return Err(anyhow::anyhow!(
+                "Cell of {} bytes is larger than {} bytes",
+                cell.payload.len(),
+                MAX_CELL_SIZE
+            ));
+        }
+        let connections = Communication::connections();
         if let Some(tx) = connections.get(&receiver) {
             tx.send((sender, cell))?;
             Ok(())
@@ -211,13 +237,18 @@ mod tests {
         let receiver_id = Uuid::new_v4();
         let rx = Communication::register(receiver_id);
 
-        let large_payload = vec![0u8; 1_000_000]; // 1 MB payload
+        let large_payload = vec![0u8; MAX_CELL_SIZE];
         let cell = RelayCell {
             circuit_id: Uuid::new_v4(),
             payload: large_payload.clone(),
         };
 
         Communication::send(sender_id, receiver_id, cell).unwrap();
+        let too_large = RelayCell {
+            circuit_id: Uuid::new_v4(),
+            payload: vec![0u8; 1_000_000], // 1 MB payload
+        };
+        assert!(Communication::send(sender_id, receiver_id, too_large).is_err());
 
         let (received_sender, received_cell) = rx.recv().unwrap();
         assert_eq!(received_sender, sender_id);
@@ -0,0 +1,122 @@
+use crate::{decrypt_buffer_with_aes, encrypt_buffer_with_aes, IntroductionPointId, RelayId};
+use anyhow::{Context, Result};
+use openssl::{
+    pkey::Private,
+    rsa::{Padding, Rsa},
+};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What a descriptor tells clients about the introduction points of a
+/// service.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct IntroductionSection {
+    pub introduction_points: HashMap<IntroductionPointId, RelayId>,
+    /// Keys the INTRODUCE1s for each introduction point are authenticated
+    /// with.
+    pub introduction_keys: HashMap<IntroductionPointId, Vec<u8>>,
+}
+
+/// Introduction points of a restricted onion service, readable only by its
+/// authorized clients. They are encrypted with a random descriptor key, which
+/// is encrypted to the RSA key of each client.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct EncryptedIntroductionPoints {
+    pub client_keys: Vec<Vec<u8>>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Encrypts `introduction_section` for the clients with the PEM encoded
+/// public keys `client_public_keys`.
+pub fn encrypt_introduction_points(
+    introduction_section: &IntroductionSection,
+    client_public_keys: &[&[u8]],
+) -> Result<EncryptedIntroductionPoints> {
+    let descriptor_key = thread_rng().gen::<[u8; 32]>();
+    let mut client_keys = vec![];
+    for client_public_key in client_public_keys {
+        let rsa = Rsa::public_key_from_pem(client_public_key)
+            .context("Failed to parse client public key")?;
+        let mut client_key = vec![0; rsa.size() as usize];
+        rsa.public_encrypt(&descriptor_key, &mut client_key, Padding::PKCS1_OAEP)?;
+        client_keys.push(client_key);
+    }
+    let ciphertext = encrypt_buffer_with_aes(
+        &descriptor_key,
+        &serde_json::to_vec(introduction_section)
+            .context("Failed to serialize introduction points")?,
+    )?;
+    Ok(EncryptedIntroductionPoints {
+        client_keys,
+        ciphertext,
+    })
+}
+
+/// Decrypts `encrypted` with the key of an authorized client. Fails when the
+/// client is not one of them.
+pub fn decrypt_introduction_points(
+    encrypted: &EncryptedIntroductionPoints,
+    client_private_key: &Rsa<Private>,
+) -> Result<IntroductionSection> {
+    let mut descriptor_key = vec![0; client_private_key.size() as usize];
+    let length = encrypted
+        .client_keys
+        .iter()
+        .find_map(|client_key| {
+            client_private_key
+                .private_decrypt(client_key, &mut descriptor_key, Padding::PKCS1_OAEP)
+                .ok()
+        })
+        .ok_or_else(|| anyhow::anyhow!("Client is not authorized"))?;
+    if length != 32 {
+        return Err(anyhow::anyhow!("Invalid descriptor key"));
+    }
+    let introduction_points =
+        decrypt_buffer_with_aes(&descriptor_key[..32], &encrypted.ciphertext)?;
+    serde_json::from_slice(&introduction_points).context("Failed to parse introduction points")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_only_authorized_clients_decrypt() -> Result<()> {
+        let alice = Rsa::generate(2048)?;
+        let bob = Rsa::generate(2048)?;
+        let mallory = Rsa::generate(2048)?;
+        let introduction_id = Uuid::new_v4();
+        let introduction_section = IntroductionSection {
+            introduction_points: HashMap::from([
+                (introduction_id, Uuid::new_v4()),
+                (Uuid::new_v4(), Uuid::new_v4()),
+            ]),
+            introduction_keys: HashMap::from([(introduction_id, vec![7; 32])]),
+        };
+        let encrypted = encrypt_introduction_points(
+            &introduction_section,
+            &[&alice.public_key_to_pem()?, &bob.public_key_to_pem()?],
+        )?;
+        assert_eq!(encrypted.client_keys.len(), 2);
+
+        assert_eq!(
+            decrypt_introduction_points(&encrypted, &alice)?,
+            introduction_section
+        );
+        assert_eq!(
+            decrypt_introduction_points(&encrypted, &bob)?,
+            introduction_section
+        );
+        assert!(decrypt_introduction_points(&encrypted, &mallory).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_client_key() {
+        assert!(
+            encrypt_introduction_points(&IntroductionSection::default(), &[b"not a key"]).is_err()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{IntroductionPointId, OnionSkin, RelayId, RendezvousCookieId};
+use anyhow::Result;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use rand::{thread_rng, Rng};
+
+pub const INTRODUCTION_KEY_LENGTH: usize = 32;
+
+/// Introduction point of a service as its descriptor tells clients, with
+/// the key their INTRODUCE1s to it are authenticated with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntroductionAuth {
+    pub introduction_id: IntroductionPointId,
+    pub introduction_key: Vec<u8>,
+}
+
+/// Parts of an INTRODUCE1 that reach the service.
+pub struct IntroductionMacInput<'a> {
+    pub introduction_id: IntroductionPointId,
+    pub rendezvous_cookie: RendezvousCookieId,
+    pub rendezvous_point: RelayId,
+    pub onion_skin: &'a OnionSkin,
+}
+
+/// Key INTRODUCE1s sent to a new introduction point are authenticated with.
+pub fn generate_introduction_key() -> Vec<u8> {
+    thread_rng().gen::<[u8; INTRODUCTION_KEY_LENGTH]>().to_vec()
+}
+
+/// HMAC-SHA256 over `input`, keyed with the key of the introduction point.
+pub fn introduction_mac(introduction_key: &[u8], input: &IntroductionMacInput) -> Result<Vec<u8>> {
+    let key = PKey::hmac(introduction_key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(input.introduction_id.as_bytes())?;
+    signer.update(input.rendezvous_cookie.as_bytes())?;
+    signer.update(input.rendezvous_point.as_bytes())?;
+    signer.update(&input.onion_skin.rsa_encrypted_aes_key)?;
+    signer.update(&input.onion_skin.aes_encrypted_dh_key)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn verify_introduction_mac(
+    introduction_key: &[u8],
+    input: &IntroductionMacInput,
+    mac: &[u8],
+) -> bool {
+    introduction_mac(introduction_key, input)
+        .is_ok_and(|expected| expected.len() == mac.len() && memcmp::eq(&expected, mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_introduction_mac() -> Result<()> {
+        let key = generate_introduction_key();
+        let onion_skin = OnionSkin {
+            rsa_encrypted_aes_key: vec![1; 256],
+            aes_encrypted_dh_key: vec![2; 256],
+        };
+        let input = IntroductionMacInput {
+            introduction_id: Uuid::new_v4(),
+            rendezvous_cookie: Uuid::new_v4(),
+            rendezvous_point: Uuid::new_v4(),
+            onion_skin: &onion_skin,
+        };
+        let mac = introduction_mac(&key, &input)?;
+        assert!(verify_introduction_mac(&key, &input, &mac));
+
+        // another key, another rendezvous point or a truncated MAC fail
+        assert!(!verify_introduction_mac(
+            &generate_introduction_key(),
+            &input,
+            &mac
+        ));
+        let moved = IntroductionMacInput {
+            rendezvous_point: Uuid::new_v4(),
+            ..input
+        };
+        assert!(!verify_introduction_mac(&key, &moved, &mac));
+        assert!(!verify_introduction_mac(&key, &input, &mac[..16]));
+        Ok(())
+    }
+}
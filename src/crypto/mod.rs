@@ -1,7 +1,11 @@
 pub mod aes;
+pub mod client_auth;
+pub mod introduction_mac;
 pub mod keys;
 pub mod onion_skin;
 
 pub use aes::*;
+pub use client_auth::*;
+pub use introduction_mac::*;
 pub use keys::*;
 pub use onion_skin::*;
@@ -103,7 +103,12 @@ mod tests {
         let onion_skin = OnionSkin::new(
             bob_public_key,
             aes_key,
-            dh_alice.public_key().to_vec().try_into().unwrap(),
+            dh_alice
+                .public_key()
+                .to_vec_padded(256)
+                .unwrap()
+                .try_into()
+                .unwrap(),
         )
         .unwrap();
 
@@ -1,5 +1,60 @@
 use super::PayloadType;
+use crate::CircuitId;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-#[derive(PartialEq, Eq, Debug)]
-pub struct Event(pub PayloadType, pub Uuid);
+/// A payload of a given type received from a relay on a circuit.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Event(pub PayloadType, pub Uuid, pub CircuitId);
+
+/// Events are dropped oldest first once this many are waiting to be consumed.
+pub const MAX_PENDING_EVENTS: usize = 1024;
+
+/// Queue of received events that any number of threads can wait on. Each
+/// waiter only consumes the event it is waiting for.
+#[derive(Default)]
+pub struct EventQueue {
+    events: Mutex<VecDeque<Event>>,
+    condvar: Condvar,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= MAX_PENDING_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+        self.condvar.notify_all();
+    }
+
+    /// Blocks until `event` is received, then removes it from the queue.
+    /// Returns false if it did not arrive within `timeout`.
+    pub fn wait_for(&self, event: &Event, timeout: Duration) -> bool {
+        self.wait_for_any(std::slice::from_ref(event), timeout)
+            .is_some()
+    }
+
+    /// Blocks until one of `expected` is received, then removes it from the
+    /// queue and returns it. Returns None if none arrived within `timeout`.
+    pub fn wait_for_any(&self, expected: &[Event], timeout: Duration) -> Option<Event> {
+        let deadline = Instant::now() + timeout;
+        let mut events = self.events.lock().unwrap();
+        loop {
+            if let Some(index) = events.iter().position(|e| expected.contains(e)) {
+                return events.remove(index);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            events = self.condvar.wait_timeout(events, remaining).unwrap().0;
+        }
+    }
+}
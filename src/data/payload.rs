@@ -13,15 +13,25 @@ pub enum Payload {
     Extended(ExtendedPayload),
     Begin(BeginPayload),
     Connected(ConnectedPayload),
+    End(EndPayload),
     Introduce1(Introduce1Payload),
     Introduce2(Introduce2Payload),
     IntroduceAck(IntroduceAckPayload),
     Rendezvous1(Rendezvous1Payload),
     Rendezvous2(Rendezvous2Payload),
     Data(DataPayload),
+    Destroy(DestroyPayload),
+    Truncate(TruncatePayload),
+    Truncated(TruncatedPayload),
+    Drop(DropPayload),
+    Ping(PingPayload),
+    Pong(PongPayload),
+    Sendme(SendmePayload),
+    Resolve(ResolvePayload),
+    Resolved(ResolvedPayload),
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum PayloadType {
     Create,
     Created,
@@ -33,12 +43,22 @@ pub enum PayloadType {
     EstablishedIntroduction,
     Begin,
     Connected,
+    End,
     Introduce1,
     Introduce2,
     IntroduceAck,
     Rendezvous1,
     Rendezvous2,
     Data,
+    Destroy,
+    Truncate,
+    Truncated,
+    Drop,
+    Ping,
+    Pong,
+    Sendme,
+    Resolve,
+    Resolved,
 }
 
 impl Payload {
@@ -54,12 +74,22 @@ impl Payload {
             Payload::EstablishedIntroduction(_) => PayloadType::EstablishedIntroduction,
             Payload::Begin(_) => PayloadType::Begin,
             Payload::Connected(_) => PayloadType::Connected,
+            Payload::End(_) => PayloadType::End,
             Payload::Introduce1(_) => PayloadType::Introduce1,
             Payload::Introduce2(_) => PayloadType::Introduce2,
             Payload::IntroduceAck(_) => PayloadType::IntroduceAck,
             Payload::Rendezvous1(_) => PayloadType::Rendezvous1,
             Payload::Rendezvous2(_) => PayloadType::Rendezvous2,
             Payload::Data(_) => PayloadType::Data,
+            Payload::Destroy(_) => PayloadType::Destroy,
+            Payload::Truncate(_) => PayloadType::Truncate,
+            Payload::Truncated(_) => PayloadType::Truncated,
+            Payload::Drop(_) => PayloadType::Drop,
+            Payload::Ping(_) => PayloadType::Ping,
+            Payload::Pong(_) => PayloadType::Pong,
+            Payload::Sendme(_) => PayloadType::Sendme,
+            Payload::Resolve(_) => PayloadType::Resolve,
+            Payload::Resolved(_) => PayloadType::Resolved,
         }
     }
 }
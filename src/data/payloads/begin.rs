@@ -3,8 +3,27 @@ use uuid::Uuid;
 
 use crate::relay::RelayDescriptor;
 
+/// What the hop receiving a BEGIN should connect the stream to.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum BeginTarget {
+    /// Another relay, used to reach an introduction point.
+    Relay(RelayDescriptor),
+    /// A TCP destination outside the network, reached by an exit relay.
+    Host { host: String, port: u16 },
+}
+
+impl BeginTarget {
+    /// "host:port" or the id of the relay, used as the stream's destination.
+    pub fn destination(&self) -> String {
+        match self {
+            BeginTarget::Relay(relay_descriptor) => relay_descriptor.id.to_string(),
+            BeginTarget::Host { host, port } => format!("{}:{}", host, port),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct BeginPayload {
     pub stream_id: Uuid,
-    pub relay_descriptor: RelayDescriptor,
+    pub target: BeginTarget,
 }
@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct ConnectedPayload {}
+pub struct ConnectedPayload {
+    pub stream_id: uuid::Uuid,
+}
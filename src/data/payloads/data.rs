@@ -1,7 +1,17 @@
+use crate::Fragment;
 use serde::{Deserialize, Serialize};
 
+/// Bytes of data carried by one DATA cell, the body of a Tor DATA cell.
+pub const MAX_DATA_LENGTH: usize = 498;
+
+/// Data on a stream, or data between two users joined at a rendezvous point,
+/// encrypted end to end with the key of `rendezvous_cookie`.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct DataPayload {
     pub data: Vec<u8>,
-    pub rendezvous_cookie: uuid::Uuid,
+    pub stream_id: Option<uuid::Uuid>,
+    pub rendezvous_cookie: Option<uuid::Uuid>,
+    /// Where `data` goes in a message sent over several cells.
+    #[serde(default)]
+    pub fragment: Option<Fragment>,
 }
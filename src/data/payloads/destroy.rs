@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestroyReason {
+    None,
+    Protocol,
+    Internal,
+    Requested,
+    ResourceLimit,
+    ConnectFailed,
+    ChannelClosed,
+    Finished,
+    Timeout,
+    Destroyed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DestroyPayload {
+    pub reason: DestroyReason,
+}
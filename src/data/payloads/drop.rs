@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+/// Padding addressed to a single hop, which silently discards it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DropPayload {
+    pub padding: Vec<u8>,
+}
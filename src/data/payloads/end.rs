@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// Why a stream was closed, following Tor's RELAY_END reasons.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    Misc,
+    ResolveFailed,
+    ConnectRefused,
+    ExitPolicy,
+    Destroy,
+    Done,
+    Timeout,
+    NoRoute,
+    Internal,
+    ResourceLimit,
+    ConnReset,
+    Protocol,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EndPayload {
+    pub stream_id: uuid::Uuid,
+    pub reason: EndReason,
+}
@@ -4,4 +4,6 @@ use serde::{Deserialize, Serialize};
 pub struct EstablishIntroductionPayload {
     pub rsa_publickey: Vec<u8>,
     pub introduction_id: uuid::Uuid,
+    /// Key INTRODUCE1s for this introduction point are authenticated with.
+    pub introduction_key: Vec<u8>,
 }
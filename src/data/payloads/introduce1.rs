@@ -6,5 +6,9 @@ pub struct Introduce1Payload {
     pub stream_id: uuid::Uuid,
     pub introduction_id: uuid::Uuid,
     pub rendezvous_cookie: uuid::Uuid,
+    /// Relay the service should build its rendezvous circuit to.
+    pub rendezvous_point: uuid::Uuid,
     pub onion_skin: OnionSkin,
+    /// MAC of the introduction with the key of the introduction point.
+    pub mac: Vec<u8>,
 }
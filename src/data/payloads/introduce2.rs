@@ -3,6 +3,10 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Introduce2Payload {
+    pub introduction_id: uuid::Uuid,
     pub rendezvous_cookie: uuid::Uuid,
+    pub rendezvous_point: uuid::Uuid,
     pub onion_skin: OnionSkin,
+    /// MAC of the INTRODUCE1, checked again by the service.
+    pub mac: Vec<u8>,
 }
@@ -3,6 +3,9 @@ pub mod connected;
 pub mod create;
 pub mod created;
 pub mod data;
+pub mod destroy;
+pub mod drop;
+pub mod end;
 pub mod establish_introduction;
 pub mod establish_rendezvous;
 pub mod established_introduction;
@@ -12,14 +15,24 @@ pub mod extended;
 pub mod introduce1;
 pub mod introduce2;
 pub mod introduction_ack;
+pub mod ping;
+pub mod pong;
 pub mod rendezvous1;
 pub mod rendezvous2;
+pub mod resolve;
+pub mod resolved;
+pub mod sendme;
+pub mod truncate;
+pub mod truncated;
 
 pub use begin::*;
 pub use connected::*;
 pub use create::*;
 pub use created::*;
 pub use data::*;
+pub use destroy::*;
+pub use drop::*;
+pub use end::*;
 pub use establish_introduction::*;
 pub use establish_rendezvous::*;
 pub use established_introduction::*;
@@ -29,5 +42,12 @@ pub use extended::*;
 pub use introduce1::*;
 pub use introduce2::*;
 pub use introduction_ack::*;
+pub use ping::*;
+pub use pong::*;
 pub use rendezvous1::*;
 pub use rendezvous2::*;
+pub use resolve::*;
+pub use resolved::*;
+pub use sendme::*;
+pub use truncate::*;
+pub use truncated::*;
@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+/// Asks the hop it is addressed to to answer with a PONG carrying `nonce`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PingPayload {
+    pub nonce: u64,
+}
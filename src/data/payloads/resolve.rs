@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+/// Asks the exit of a circuit to resolve `hostname`, so the name is never
+/// looked up by the user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResolvePayload {
+    /// Identifies the request, like the stream of a BEGIN.
+    pub stream_id: uuid::Uuid,
+    pub hostname: String,
+}
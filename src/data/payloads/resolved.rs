@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedPayload {
+    pub stream_id: uuid::Uuid,
+    /// Empty when the name could not be resolved.
+    pub addresses: Vec<IpAddr>,
+}
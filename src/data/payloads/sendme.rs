@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// Acknowledges DATA cells, letting the other end of a circuit send more.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendmePayload {
+    /// Stream whose window is increased, or None for the circuit window.
+    pub stream_id: Option<uuid::Uuid>,
+}
@@ -0,0 +1,5 @@
+use serde::{Deserialize, Serialize};
+
+/// Asks the hop it is addressed to to tear down everything after it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TruncatePayload {}
@@ -0,0 +1,9 @@
+use super::DestroyReason;
+use serde::{Deserialize, Serialize};
+
+/// Sent back by the hop that is now the last one of the circuit, either after
+/// a TRUNCATE or because its next hop failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TruncatedPayload {
+    pub reason: DestroyReason,
+}
@@ -0,0 +1,235 @@
+use crate::{EndReason, Logger, RendezvousCookieId, StreamId, User};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, StreamExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Chunks of data buffered in each direction, beyond which reads and writes
+/// wait for the other side.
+const CHANNEL_CAPACITY: usize = 16;
+/// Largest chunk taken by one write, so that session messages stay well
+/// below `MAX_MESSAGE_SIZE`.
+const MAX_WRITE_SIZE: usize = 64 * 1024;
+/// How long the reader waits for data before checking whether the stream
+/// was dropped.
+const READ_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a `DataStream` carries bytes over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataStreamEndpoint {
+    /// A stream opened at an exit.
+    Exit(StreamId),
+    /// A session with a user joined at a rendezvous point.
+    Session(RendezvousCookieId),
+}
+
+impl DataStreamEndpoint {
+    fn recv(&self, user: &User) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            DataStreamEndpoint::Exit(stream_id) => user.recv_stream(*stream_id, READ_POLL_INTERVAL),
+            DataStreamEndpoint::Session(rendezvous_cookie) => Ok(user
+                .recv_messages(*rendezvous_cookie, READ_POLL_INTERVAL)?
+                .map(|messages| messages.concat())),
+        }
+    }
+
+    fn send(&self, user: &User, data: Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            DataStreamEndpoint::Exit(stream_id) => user.send_stream_data(*stream_id, data),
+            DataStreamEndpoint::Session(rendezvous_cookie) => {
+                user.send_message(*rendezvous_cookie, data)
+            }
+        }
+    }
+
+    fn end(&self, user: &User) -> anyhow::Result<()> {
+        match self {
+            DataStreamEndpoint::Exit(stream_id) => user.end_stream(*stream_id, EndReason::Done),
+            DataStreamEndpoint::Session(rendezvous_cookie) => user.end_session(*rendezvous_cookie),
+        }
+    }
+}
+
+/// Byte stream over an exit stream or a rendezvous session, for async code.
+/// Shutting it down or dropping it sends END, and reads return EOF once the
+/// other end sent END and everything it sent before was read.
+pub struct DataStream {
+    endpoint: DataStreamEndpoint,
+    incoming: mpsc::Receiver<Vec<u8>>,
+    /// Received chunk being read, and how much of it was read.
+    pending: Vec<u8>,
+    position: usize,
+    /// None once shut down.
+    outgoing: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl DataStream {
+    /// Starts moving bytes between the returned stream and `endpoint`, which
+    /// must already be open.
+    pub fn new(user: User, endpoint: DataStreamEndpoint) -> Self {
+        let (mut incoming_sender, incoming) = mpsc::channel(CHANNEL_CAPACITY);
+        let (outgoing, mut outgoing_receiver) = mpsc::channel::<Vec<u8>>(CHANNEL_CAPACITY);
+
+        let reader = user.clone();
+        thread::spawn(move || loop {
+            match endpoint.recv(&reader) {
+                Ok(Some(data)) if data.is_empty() => {
+                    if incoming_sender.is_closed() {
+                        return;
+                    }
+                }
+                Ok(Some(data)) => {
+                    if block_on(incoming_sender.send(data)).is_err() {
+                        return;
+                    }
+                }
+                // dropping the sender reports EOF
+                Ok(None) => return,
+                Err(e) => {
+                    Logger::warn(
+                        &reader.user_descriptor.nickname,
+                        format!("Stopped reading {:?}: {}", endpoint, e),
+                    );
+                    return;
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            while let Some(data) = block_on(outgoing_receiver.next()) {
+                if let Err(e) = endpoint.send(&user, data) {
+                    // dropping the receiver fails later writes
+                    Logger::warn(
+                        &user.user_descriptor.nickname,
+                        format!("Stopped writing {:?}: {}", endpoint, e),
+                    );
+                    return;
+                }
+            }
+            // closed by the other end if this fails
+            let _ = endpoint.end(&user);
+        });
+
+        Self {
+            endpoint,
+            incoming,
+            pending: vec![],
+            position: 0,
+            outgoing: Some(outgoing),
+        }
+    }
+
+    pub fn endpoint(&self) -> DataStreamEndpoint {
+        self.endpoint
+    }
+}
+
+impl AsyncRead for DataStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.position == self.pending.len() {
+            match ready!(self.incoming.poll_next_unpin(cx)) {
+                Some(data) => {
+                    self.pending = data;
+                    self.position = 0;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let length = buf.remaining().min(self.pending.len() - self.position);
+        buf.put_slice(&self.pending[self.position..self.position + length]);
+        self.position += length;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for DataStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Some(outgoing) = self.outgoing.as_mut() else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "stream was shut down",
+            )));
+        };
+        let sent = ready!(outgoing.poll_ready(cx)).and_then(|_| {
+            let length = buf.len().min(MAX_WRITE_SIZE);
+            outgoing.start_send(buf[..length].to_vec())?;
+            Ok(length)
+        });
+        Poll::Ready(sent.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "stream is closed")))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the writer sends END once it sent what is left
+        self.outgoing = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{echo, start_network, start_tcp_server};
+    use crate::IsolationKey;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn open_stream(user: &User, port: u16) -> DataStream {
+        let user = user.clone();
+        tokio::task::spawn_blocking(move || {
+            user.open_stream(IsolationKey::default(), "127.0.0.1", port)
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exit_stream_round_trip() {
+        let user = start_network("TestDataStreamUser");
+        let mut stream = open_stream(&user, start_tcp_server(echo)).await;
+        let DataStreamEndpoint::Exit(stream_id) = stream.endpoint() else {
+            panic!("not an exit stream");
+        };
+
+        let message: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        stream.write_all(&message).await.unwrap();
+        let mut received = vec![0; message.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, message);
+
+        // shutting down sends END, after which reads end too
+        stream.shutdown().await.unwrap();
+        assert_eq!(stream.read(&mut received).await.unwrap(), 0);
+        assert!(user.get_state().stream_info[&stream_id].is_closed());
+        assert!(stream.write_all(b"more").await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_end_from_exit_is_eof() {
+        let user = start_network("TestDataStreamUser");
+        let port = start_tcp_server(|mut socket| {
+            let _ = socket.write_all(b"goodbye");
+        });
+        let mut stream = open_stream(&user, port).await;
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"goodbye");
+    }
+}
@@ -50,15 +50,26 @@ impl Directory {
         relays.push(relay);
     }
 
+    /// Adds the descriptor of `user`, or replaces the one it published before.
     pub fn publish_user(user: UserDescriptor) {
         Logger::info(
             "Directory",
             format!("Publishing a new user {}", user.nickname),
         );
         let mut users = directory.users.lock().unwrap();
+        users.retain(|u| u.id != user.id);
         users.push(user);
     }
 
+    /// Key of the introduction point `introduction_id`, if a descriptor
+    /// publishes it in the clear.
+    pub fn get_introduction_key(introduction_id: IntroductionPointId) -> Option<Vec<u8>> {
+        let users = directory.users.lock().unwrap();
+        users
+            .iter()
+            .find_map(|user| user.introduction_keys.get(&introduction_id).cloned())
+    }
+
     pub fn add_user_introduction_point(
         user_id: Uuid,
         introduction_points: IntroductionPointId,
@@ -0,0 +1,115 @@
+use crate::{EndReason, ExitPolicy, Resolver, SendmePayload, MAX_DATA_LENGTH, STREAM_WINDOW_START};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc::SyncSender;
+use std::time::Duration;
+
+/// How long an exit relay tries to connect to a destination.
+pub const EXIT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes read from a destination into each DATA cell, the body of a Tor DATA
+/// cell.
+pub const EXIT_READ_SIZE: usize = MAX_DATA_LENGTH;
+
+/// DATA cells queued for the destination of an exit stream. The user may
+/// not have more cells of a stream unacknowledged, so only a user ignoring
+/// flow control fills the queue up.
+pub const EXIT_WRITE_QUEUE_SIZE: usize = STREAM_WINDOW_START as usize;
+
+/// TCP connection of a stream exiting the network at this relay.
+pub struct ExitConnection {
+    /// "host:port" the stream was opened to.
+    pub destination: String,
+    pub socket: TcpStream,
+    /// Queue of the thread writing to `socket`. Dropping it stops the thread.
+    pub writer: SyncSender<ExitWrite>,
+}
+
+/// Data of a DATA cell for the destination of an exit stream.
+pub struct ExitWrite {
+    pub data: Vec<u8>,
+    /// SENDMEs owed for the cell, sent once the data is written.
+    pub sendmes: Vec<SendmePayload>,
+}
+
+/// Addresses of `host` found with `resolver`, unless it already is one.
+pub fn resolve_host(host: &str, resolver: &dyn Resolver) -> Result<Vec<IpAddr>, EndReason> {
+    if let Ok(address) = host.trim_matches(['[', ']']).parse() {
+        return Ok(vec![address]);
+    }
+    match resolver.resolve(host) {
+        Ok(addresses) if !addresses.is_empty() => Ok(addresses),
+        _ => Err(EndReason::ResolveFailed),
+    }
+}
+
+/// Opens the TCP connection of an exit stream to an address `exit_policy`
+/// accepts, or returns the reason to end the stream with.
+pub fn connect_to_destination(
+    host: &str,
+    port: u16,
+    exit_policy: &ExitPolicy,
+    resolver: &dyn Resolver,
+) -> Result<TcpStream, EndReason> {
+    let addresses: Vec<_> = resolve_host(host, resolver)?
+        .into_iter()
+        .map(|address| SocketAddr::new(address, port))
+        .collect();
+    let mut reason = EndReason::ResolveFailed;
+    // a name may resolve to addresses the policy rejects
+    let addresses: Vec<_> = addresses
+        .into_iter()
+        .filter(|address| {
+            let allowed = exit_policy.allows(address.ip(), port);
+            if !allowed {
+                reason = EndReason::ExitPolicy;
+            }
+            allowed
+        })
+        .collect();
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, EXIT_CONNECT_TIMEOUT) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                reason = match e.kind() {
+                    ErrorKind::ConnectionRefused => EndReason::ConnectRefused,
+                    ErrorKind::TimedOut | ErrorKind::WouldBlock => EndReason::Timeout,
+                    _ => EndReason::NoRoute,
+                }
+            }
+        }
+    }
+    Err(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StaticResolver;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    #[test]
+    fn test_connect_to_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut resolver = StaticResolver::default();
+        resolver.insert("service.test", vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        let accept_all = ExitPolicy::accept_all();
+        assert!(connect_to_destination("127.0.0.1", port, &accept_all, &resolver).is_ok());
+        let reject_local = ExitPolicy::parse(&["reject 127.0.0.0/8:*", "reject [::1]:*"]).unwrap();
+        assert_eq!(
+            connect_to_destination("service.test", port, &reject_local, &resolver).err(),
+            Some(EndReason::ExitPolicy)
+        );
+        assert!(connect_to_destination("service.test", port, &accept_all, &resolver).is_ok());
+        assert_eq!(
+            connect_to_destination("unknown.test", port, &accept_all, &resolver).err(),
+            Some(EndReason::ResolveFailed)
+        );
+        drop(listener);
+        assert_eq!(
+            connect_to_destination("127.0.0.1", port, &accept_all, &resolver).err(),
+            Some(EndReason::ConnectRefused)
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Destinations an exit rejects unless its policy says otherwise: this
+/// network, loopback, private, shared and link-local addresses, like Tor's
+/// ExitPolicyRejectPrivate.
+pub const REJECT_PRIVATE_RULES: &[&str] = &[
+    "reject 0.0.0.0/8:*",
+    "reject 10.0.0.0/8:*",
+    "reject 100.64.0.0/10:*",
+    "reject 127.0.0.0/8:*",
+    "reject 169.254.0.0/16:*",
+    "reject 172.16.0.0/12:*",
+    "reject 192.168.0.0/16:*",
+    "reject [::]/128:*",
+    "reject [::1]/128:*",
+    "reject [fc00::]/7:*",
+    "reject [fe80::]/10:*",
+];
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ExitPolicyAction {
+    Accept,
+    Reject,
+}
+
+/// One rule of an exit policy, written like Tor's `accept 10.0.0.0/8:80-443`
+/// or `reject *:*`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExitPolicyRule {
+    pub action: ExitPolicyAction,
+    /// Network and prefix length the rule applies to, any address if None.
+    pub network: Option<(IpAddr, u8)>,
+    pub min_port: u16,
+    pub max_port: u16,
+}
+
+impl ExitPolicyRule {
+    pub fn matches_address(&self, address: IpAddr) -> bool {
+        let Some((network, prefix)) = self.network else {
+            return true;
+        };
+        match (network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+
+    pub fn matches_port(&self, port: u16) -> bool {
+        (self.min_port..=self.max_port).contains(&port)
+    }
+}
+
+impl FromStr for ExitPolicyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let (action, pattern) = rule
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Exit policy rule {:?} has no pattern", rule))?;
+        let action = match action {
+            "accept" => ExitPolicyAction::Accept,
+            "reject" => ExitPolicyAction::Reject,
+            _ => return Err(anyhow::anyhow!("Unknown exit policy action {:?}", action)),
+        };
+        let (address, ports) = pattern
+            .trim()
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Exit policy rule {:?} has no ports", rule))?;
+        let network = match address {
+            "*" => None,
+            _ => {
+                let (address, prefix) = address.split_once('/').unwrap_or((address, ""));
+                let address: IpAddr = address
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .with_context(|| format!("Invalid address in exit policy rule {:?}", rule))?;
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    "" => max_prefix,
+                    _ => prefix
+                        .parse()
+                        .ok()
+                        .filter(|prefix| *prefix <= max_prefix)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("Invalid prefix in exit policy rule {:?}", rule)
+                        })?,
+                };
+                Some((address, prefix))
+            }
+        };
+        let (min_port, max_port) = match ports {
+            "*" => (1, u16::MAX),
+            _ => {
+                let (min_port, max_port) = ports.split_once('-').unwrap_or((ports, ports));
+                let parse_port = |port: &str| {
+                    port.parse::<u16>()
+                        .with_context(|| format!("Invalid port in exit policy rule {:?}", rule))
+                };
+                (parse_port(min_port)?, parse_port(max_port)?)
+            }
+        };
+        if min_port > max_port {
+            return Err(anyhow::anyhow!(
+                "Empty port range in exit policy rule {:?}",
+                rule
+            ));
+        }
+        Ok(Self {
+            action,
+            network,
+            min_port,
+            max_port,
+        })
+    }
+}
+
+/// Ordered rules deciding which destinations an exit relay connects to. The
+/// first rule matching a destination decides; destinations matched by no
+/// rule are accepted. The default policy rejects private destinations.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExitPolicy {
+    pub rules: Vec<ExitPolicyRule>,
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        Self::parse(REJECT_PRIVATE_RULES).unwrap()
+    }
+}
+
+impl ExitPolicy {
+    pub fn parse(rules: &[&str]) -> Result<Self> {
+        Ok(Self {
+            rules: rules
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn accept_all() -> Self {
+        Self { rules: vec![] }
+    }
+
+    pub fn reject_all() -> Self {
+        Self::parse(&["reject *:*"]).unwrap()
+    }
+
+    pub fn allows(&self, address: IpAddr, port: u16) -> bool {
+        // an IPv4-mapped IPv6 address reaches the IPv4 host
+        let address = address.to_canonical();
+        self.rules
+            .iter()
+            .find(|rule| rule.matches_port(port) && rule.matches_address(address))
+            .is_none_or(|rule| rule.action == ExitPolicyAction::Accept)
+    }
+
+    /// Whether `host:port` may be accepted. When `host` is a name the
+    /// address is unknown until the exit resolves it, so only rules that
+    /// reject every address of the port rule it out, as in Tor.
+    pub fn allows_destination(&self, host: &str, port: u16) -> bool {
+        if let Ok(address) = host.parse() {
+            return self.allows(address, port);
+        }
+        for rule in self.rules.iter().filter(|rule| rule.matches_port(port)) {
+            match rule.action {
+                ExitPolicyAction::Accept => return true,
+                ExitPolicyAction::Reject if rule.network.is_none() => return false,
+                ExitPolicyAction::Reject => {}
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        let rule: ExitPolicyRule = "accept 10.0.0.0/8:80-443".parse().unwrap();
+        assert_eq!(rule.action, ExitPolicyAction::Accept);
+        assert_eq!(rule.network, Some(("10.0.0.0".parse().unwrap(), 8)));
+        assert_eq!((rule.min_port, rule.max_port), (80, 443));
+        let rule: ExitPolicyRule = "reject *:*".parse().unwrap();
+        assert_eq!(rule.network, None);
+        assert_eq!((rule.min_port, rule.max_port), (1, u16::MAX));
+        let rule: ExitPolicyRule = "reject [::1]:22".parse().unwrap();
+        assert_eq!(rule.network, Some(("::1".parse().unwrap(), 128)));
+
+        for invalid in [
+            "allow *:*",
+            "accept *",
+            "accept 10.0.0.0/33:80",
+            "accept *:443-80",
+            "accept nowhere:80",
+        ] {
+            assert!(invalid.parse::<ExitPolicyRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let policy = ExitPolicy::parse(&[
+            "reject 10.1.0.0/16:*",
+            "accept 10.0.0.0/8:80",
+            "reject *:80",
+        ])
+        .unwrap();
+        let address = |address: &str| address.parse().unwrap();
+        assert!(policy.allows(address("10.2.3.4"), 80));
+        assert!(!policy.allows(address("10.1.3.4"), 80));
+        assert!(!policy.allows(address("192.168.0.1"), 80));
+        assert!(policy.allows(address("192.168.0.1"), 443));
+        assert!(policy.allows(address("::1"), 443));
+        assert!(ExitPolicy::accept_all().allows(address("10.1.3.4"), 80));
+        assert!(!ExitPolicy::reject_all().allows(address("10.2.3.4"), 80));
+    }
+
+    #[test]
+    fn test_allows_destination_by_name() {
+        let policy =
+            ExitPolicy::parse(&["reject 10.0.0.0/8:*", "accept *:443", "reject *:*"]).unwrap();
+        assert!(policy.allows_destination("example.com", 443));
+        assert!(!policy.allows_destination("example.com", 80));
+        assert!(!policy.allows_destination("10.0.0.1", 443));
+        assert!(policy.allows_destination("192.168.0.1", 443));
+    }
+
+    #[test]
+    fn test_default_policy_rejects_private_destinations() {
+        let policy = ExitPolicy::default();
+        assert!(!policy.allows_destination("127.0.0.1", 22));
+        for address in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!policy.allows_destination(address, 443), "{}", address);
+        }
+        assert!(policy.allows_destination("93.184.216.34", 443));
+        assert!(policy.allows_destination("2606:2800:220:1::1", 443));
+        assert!(policy.allows_destination("example.com", 443));
+    }
+}
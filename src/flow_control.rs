@@ -0,0 +1,197 @@
+use crate::{SendmePayload, StreamId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// DATA cells an end of a circuit may send before it is acknowledged.
+pub const CIRCUIT_WINDOW_START: u32 = 1000;
+/// DATA cells acknowledged by a circuit-level SENDME.
+pub const CIRCUIT_SENDME_INCREMENT: u32 = 100;
+/// DATA cells an end of a stream may send before it is acknowledged.
+pub const STREAM_WINDOW_START: u32 = 500;
+/// DATA cells acknowledged by a stream-level SENDME.
+pub const STREAM_SENDME_INCREMENT: u32 = 50;
+
+/// Tor-style flow control window of one end of a circuit or stream. Only
+/// DATA cells of streams count against it.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FlowWindow {
+    /// DATA cells that may still be sent before a SENDME comes back.
+    pub package: u32,
+    /// DATA cells that may still be received before a SENDME is sent.
+    pub deliver: u32,
+    start: u32,
+    increment: u32,
+}
+
+/// A peer sent more than its window allows, or acknowledged cells that were
+/// never sent. The circuit it happened on must be closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowControlError {
+    WindowExceeded,
+    UnexpectedSendme,
+}
+
+impl fmt::Display for FlowControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlowControlError::WindowExceeded => write!(f, "DATA cell past the end of the window"),
+            FlowControlError::UnexpectedSendme => write!(f, "SENDME for cells that were not sent"),
+        }
+    }
+}
+
+impl std::error::Error for FlowControlError {}
+
+impl FlowWindow {
+    fn new(start: u32, increment: u32) -> Self {
+        Self {
+            package: start,
+            deliver: start,
+            start,
+            increment,
+        }
+    }
+
+    pub fn circuit() -> Self {
+        Self::new(CIRCUIT_WINDOW_START, CIRCUIT_SENDME_INCREMENT)
+    }
+
+    pub fn stream() -> Self {
+        Self::new(STREAM_WINDOW_START, STREAM_SENDME_INCREMENT)
+    }
+
+    pub fn can_package(&self) -> bool {
+        self.package > 0
+    }
+
+    pub fn record_packaged(&mut self) {
+        self.package = self.package.saturating_sub(1);
+    }
+
+    /// Counts a received DATA cell.
+    pub fn record_delivered(&mut self) -> Result<(), FlowControlError> {
+        if self.deliver == 0 {
+            return Err(FlowControlError::WindowExceeded);
+        }
+        self.deliver -= 1;
+        Ok(())
+    }
+
+    /// Whether enough cells were received to send a SENDME, in which case
+    /// the window is increased as if it was sent.
+    pub fn take_sendme(&mut self) -> bool {
+        if self.deliver > self.start - self.increment {
+            return false;
+        }
+        self.deliver += self.increment;
+        true
+    }
+
+    /// Counts a received SENDME, which lets `increment` more cells be sent.
+    pub fn record_sendme(&mut self) -> Result<(), FlowControlError> {
+        if self.package + self.increment > self.start {
+            return Err(FlowControlError::UnexpectedSendme);
+        }
+        self.package += self.increment;
+        Ok(())
+    }
+}
+
+/// Takes room for one DATA cell in both the circuit and the stream window,
+/// if both have some left.
+pub fn package_data_cell(circuit_window: &mut FlowWindow, stream_window: &mut FlowWindow) -> bool {
+    if !circuit_window.can_package() || !stream_window.can_package() {
+        return false;
+    }
+    circuit_window.record_packaged();
+    stream_window.record_packaged();
+    true
+}
+
+/// Counts a DATA cell received on `stream_id` against both windows and
+/// returns the SENDMEs owed for them right away.
+pub fn deliver_data_cell(
+    circuit_window: &mut FlowWindow,
+    stream_window: &mut FlowWindow,
+    stream_id: StreamId,
+) -> Result<Vec<SendmePayload>, FlowControlError> {
+    circuit_window.record_delivered()?;
+    stream_window.record_delivered()?;
+    let mut sendmes = vec![];
+    if circuit_window.take_sendme() {
+        sendmes.push(SendmePayload { stream_id: None });
+    }
+    if stream_window.take_sendme() {
+        sendmes.push(SendmePayload {
+            stream_id: Some(stream_id),
+        });
+    }
+    Ok(sendmes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_package_until_window_is_exhausted() {
+        let mut circuit_window = FlowWindow::circuit();
+        let mut stream_window = FlowWindow::stream();
+        for _ in 0..STREAM_WINDOW_START {
+            assert!(package_data_cell(&mut circuit_window, &mut stream_window));
+        }
+        assert!(!package_data_cell(&mut circuit_window, &mut stream_window));
+        assert_eq!(
+            circuit_window.package,
+            CIRCUIT_WINDOW_START - STREAM_WINDOW_START
+        );
+
+        stream_window.record_sendme().unwrap();
+        assert_eq!(stream_window.package, STREAM_SENDME_INCREMENT);
+        assert!(package_data_cell(&mut circuit_window, &mut stream_window));
+    }
+
+    #[test]
+    fn test_sendme_every_increment() {
+        let stream_id = Uuid::new_v4();
+        let mut circuit_window = FlowWindow::circuit();
+        let mut stream_window = FlowWindow::stream();
+        let mut sendmes = vec![];
+        for _ in 0..CIRCUIT_SENDME_INCREMENT {
+            sendmes.extend(
+                deliver_data_cell(&mut circuit_window, &mut stream_window, stream_id).unwrap(),
+            );
+        }
+        assert_eq!(
+            sendmes,
+            vec![
+                SendmePayload {
+                    stream_id: Some(stream_id)
+                },
+                SendmePayload { stream_id: None },
+                SendmePayload {
+                    stream_id: Some(stream_id)
+                },
+            ]
+        );
+        assert_eq!(circuit_window.deliver, CIRCUIT_WINDOW_START);
+        assert_eq!(stream_window.deliver, STREAM_WINDOW_START);
+    }
+
+    #[test]
+    fn test_misbehaving_peers() {
+        let mut window = FlowWindow::stream();
+        assert_eq!(
+            window.record_sendme(),
+            Err(FlowControlError::UnexpectedSendme)
+        );
+        for _ in 0..STREAM_WINDOW_START {
+            window.record_delivered().unwrap();
+        }
+        assert_eq!(
+            window.record_delivered(),
+            Err(FlowControlError::WindowExceeded)
+        );
+    }
+}
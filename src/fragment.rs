@@ -0,0 +1,233 @@
+use crate::MAX_DATA_LENGTH;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+
+/// Largest message reassembled from fragments.
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// Fragments of incomplete messages kept at most, across all messages.
+pub const MAX_FRAGMENTS_IN_FLIGHT: usize = 16 * 1024;
+
+pub type MessageId = Uuid;
+
+/// Position of the data of a DATA cell in a message split across cells.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    pub message_id: MessageId,
+    /// Starts at 0.
+    pub sequence: u32,
+    pub count: u32,
+}
+
+/// Splits `message` into chunks that each fit in a DATA cell.
+pub fn fragment_message(message: &[u8]) -> Vec<(Fragment, Vec<u8>)> {
+    let message_id = MessageId::new_v4();
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        vec![&[]]
+    } else {
+        message.chunks(MAX_DATA_LENGTH).collect()
+    };
+    let count = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(sequence, chunk)| {
+            let fragment = Fragment {
+                message_id,
+                sequence: sequence as u32,
+                count,
+            };
+            (fragment, chunk.to_vec())
+        })
+        .collect()
+}
+
+/// Why a fragment was refused. The message it belongs to is dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReassemblyError {
+    MessageTooLarge,
+    TooManyFragments,
+    InvalidFragment,
+}
+
+impl fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReassemblyError::MessageTooLarge => write!(f, "message is too large"),
+            ReassemblyError::TooManyFragments => write!(f, "too many fragments in flight"),
+            ReassemblyError::InvalidFragment => write!(f, "invalid fragment"),
+        }
+    }
+}
+
+impl std::error::Error for ReassemblyError {}
+
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+/// Puts messages back together from their fragments, in any order.
+pub struct Reassembler {
+    max_message_size: usize,
+    max_fragments_in_flight: usize,
+    messages: HashMap<MessageId, PartialMessage>,
+    fragments_in_flight: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_SIZE, MAX_FRAGMENTS_IN_FLIGHT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(max_message_size: usize, max_fragments_in_flight: usize) -> Self {
+        Self {
+            max_message_size,
+            max_fragments_in_flight,
+            messages: HashMap::new(),
+            fragments_in_flight: 0,
+        }
+    }
+
+    pub fn fragments_in_flight(&self) -> usize {
+        self.fragments_in_flight
+    }
+
+    fn drop_message(&mut self, message_id: MessageId) {
+        if let Some(message) = self.messages.remove(&message_id) {
+            self.fragments_in_flight -= message.received;
+        }
+    }
+
+    /// Adds a fragment, returning its message once all fragments arrived.
+    pub fn add(
+        &mut self,
+        fragment: Fragment,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, ReassemblyError> {
+        let message_id = fragment.message_id;
+        let count = fragment.count as usize;
+        let error = if fragment.sequence >= fragment.count || data.len() > MAX_DATA_LENGTH {
+            Some(ReassemblyError::InvalidFragment)
+        } else if (count - 1) * MAX_DATA_LENGTH >= self.max_message_size {
+            // every fragment but the last is full
+            Some(ReassemblyError::MessageTooLarge)
+        } else if self.fragments_in_flight >= self.max_fragments_in_flight {
+            Some(ReassemblyError::TooManyFragments)
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            self.drop_message(message_id);
+            return Err(error);
+        }
+
+        let message = self
+            .messages
+            .entry(message_id)
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; count],
+                received: 0,
+            });
+        let sequence = fragment.sequence as usize;
+        if message.fragments.len() != count || message.fragments[sequence].is_some() {
+            // a duplicate, or a count that changed
+            self.drop_message(message_id);
+            return Err(ReassemblyError::InvalidFragment);
+        }
+        message.fragments[sequence] = Some(data);
+        message.received += 1;
+        self.fragments_in_flight += 1;
+        if message.received < count {
+            return Ok(None);
+        }
+
+        let message = self.messages.remove(&message_id).unwrap();
+        self.fragments_in_flight -= message.received;
+        let message: Vec<u8> = message.fragments.into_iter().flatten().flatten().collect();
+        if message.len() > self.max_message_size {
+            return Err(ReassemblyError::MessageTooLarge);
+        }
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let message: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let mut fragments = fragment_message(&message);
+        assert_eq!(fragments.len(), 5);
+        assert!(fragments
+            .iter()
+            .all(|(_, data)| data.len() <= MAX_DATA_LENGTH));
+        fragments.reverse();
+
+        let mut reassembler = Reassembler::default();
+        let last = fragments.pop().unwrap();
+        for (fragment, data) in fragments {
+            assert_eq!(reassembler.add(fragment, data), Ok(None));
+        }
+        assert_eq!(reassembler.fragments_in_flight(), 4);
+        assert_eq!(reassembler.add(last.0, last.1), Ok(Some(message)));
+        assert_eq!(reassembler.fragments_in_flight(), 0);
+
+        let (fragment, data) = fragment_message(&[]).pop().unwrap();
+        assert_eq!(reassembler.add(fragment, data), Ok(Some(vec![])));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut reassembler = Reassembler::new(3 * MAX_DATA_LENGTH, 4);
+        let too_large = fragment_message(&vec![0; 3 * MAX_DATA_LENGTH + 1]);
+        let (fragment, data) = too_large[0].clone();
+        assert_eq!(
+            reassembler.add(fragment, data),
+            Err(ReassemblyError::MessageTooLarge)
+        );
+
+        for _ in 0..2 {
+            for (fragment, data) in fragment_message(&vec![0; 3 * MAX_DATA_LENGTH])
+                .into_iter()
+                .take(2)
+            {
+                assert_eq!(reassembler.add(fragment, data), Ok(None));
+            }
+        }
+        let (fragment, data) = fragment_message(&[0; 2 * MAX_DATA_LENGTH]).remove(0);
+        assert_eq!(
+            reassembler.add(fragment, data),
+            Err(ReassemblyError::TooManyFragments)
+        );
+        assert_eq!(reassembler.fragments_in_flight(), 4);
+    }
+
+    #[test]
+    fn test_invalid_fragments_drop_the_message() {
+        let mut reassembler = Reassembler::default();
+        let fragments = fragment_message(&[0; 3 * MAX_DATA_LENGTH]);
+        let (fragment, data) = fragments[0].clone();
+        assert_eq!(reassembler.add(fragment, data.clone()), Ok(None));
+        assert_eq!(
+            reassembler.add(fragment, data),
+            Err(ReassemblyError::InvalidFragment)
+        );
+        assert_eq!(reassembler.fragments_in_flight(), 0);
+
+        let (fragment, data) = fragments[1].clone();
+        let out_of_range = Fragment {
+            sequence: 3,
+            ..fragment
+        };
+        assert_eq!(
+            reassembler.add(out_of_range, data),
+            Err(ReassemblyError::InvalidFragment)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{select_relay, RelayDescriptor, RelayFlag, RelayId};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub const MAX_SAMPLED_GUARDS: usize = 10;
+pub const NUM_PRIMARY_GUARDS: usize = 3;
+/// Seconds before an unreachable guard is tried again.
+pub const GUARD_RETRY_DELAY: i64 = 60;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum GuardReachability {
+    Unknown,
+    Reachable,
+    Unreachable,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GuardEntry {
+    pub relay_id: RelayId,
+    pub reachability: GuardReachability,
+    pub failures: u32,
+    /// Unix timestamp of the last failed connection attempt.
+    pub last_failure: Option<i64>,
+}
+
+impl GuardEntry {
+    fn new(relay_id: RelayId) -> Self {
+        Self {
+            relay_id,
+            reachability: GuardReachability::Unknown,
+            failures: 0,
+            last_failure: None,
+        }
+    }
+
+    /// A guard is usable unless it failed recently.
+    pub fn is_usable(&self, now: i64) -> bool {
+        match (self.reachability, self.last_failure) {
+            (GuardReachability::Unreachable, Some(last_failure)) => {
+                now - last_failure >= GUARD_RETRY_DELAY
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Tor-style guard state: a sample of Guard-flagged relays, the guards a
+/// circuit was successfully built through (in order of confirmation), and the
+/// primary guards derived from both.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GuardSet {
+    pub sampled: Vec<GuardEntry>,
+    pub confirmed: Vec<RelayId>,
+    pub primary: Vec<RelayId>,
+}
+
+impl GuardSet {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path).context("Failed to read guard state")?;
+        serde_json::from_slice(&contents).context("Failed to parse guard state")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(self).context("Failed to serialize guards")?;
+        std::fs::write(path, contents).context("Failed to write guard state")
+    }
+
+    pub fn get_guard(&self, relay_id: RelayId) -> Option<&GuardEntry> {
+        self.sampled.iter().find(|g| g.relay_id == relay_id)
+    }
+
+    fn get_guard_mut(&mut self, relay_id: RelayId) -> Option<&mut GuardEntry> {
+        self.sampled.iter_mut().find(|g| g.relay_id == relay_id)
+    }
+
+    /// Drops sampled guards that left the directory or lost their Guard flag,
+    /// then samples new ones, weighted by bandwidth, up to `MAX_SAMPLED_GUARDS`.
+    pub fn refresh(&mut self, relays: &[RelayDescriptor]) {
+        let is_guard = |relay_id: &RelayId| {
+            relays
+                .iter()
+                .any(|r| r.id == *relay_id && r.has_flag(RelayFlag::Guard))
+        };
+        self.sampled.retain(|g| is_guard(&g.relay_id));
+        self.confirmed.retain(is_guard);
+
+        while self.sampled.len() < MAX_SAMPLED_GUARDS {
+            let candidates: Vec<RelayDescriptor> = relays
+                .iter()
+                .filter(|r| self.get_guard(r.id).is_none())
+                .cloned()
+                .collect();
+            match select_relay(&candidates, &[], &[RelayFlag::Guard]) {
+                Ok(relay) => self.sampled.push(GuardEntry::new(relay.id)),
+                Err(_) => break,
+            }
+        }
+        self.update_primary();
+    }
+
+    /// Primary guards are the first confirmed guards, topped up with sampled
+    /// guards in sampling order.
+    fn update_primary(&mut self) {
+        let mut primary: Vec<RelayId> = self
+            .confirmed
+            .iter()
+            .take(NUM_PRIMARY_GUARDS)
+            .cloned()
+            .collect();
+        for guard in self.sampled.iter() {
+            if primary.len() >= NUM_PRIMARY_GUARDS {
+                break;
+            }
+            if !primary.contains(&guard.relay_id) {
+                primary.push(guard.relay_id);
+            }
+        }
+        self.primary = primary;
+    }
+
+    /// Returns the guard to use as the first hop of the next circuit: the first
+    /// usable primary guard, then confirmed guards, then the rest of the sample.
+    pub fn choose_guard(&mut self, relays: &[RelayDescriptor]) -> Result<RelayId> {
+        self.choose_guard_excluding(relays, &[])
+    }
+
+    /// Chooses a guard like `choose_guard`, other than the relays of
+    /// `excluded`, such as the relay a circuit must end at.
+    pub fn choose_guard_excluding(
+        &mut self,
+        relays: &[RelayDescriptor],
+        excluded: &[RelayId],
+    ) -> Result<RelayId> {
+        self.refresh(relays);
+        let now = chrono::Utc::now().timestamp();
+        let candidates = self
+            .primary
+            .iter()
+            .chain(self.confirmed.iter())
+            .chain(self.sampled.iter().map(|g| &g.relay_id));
+        for relay_id in candidates.filter(|relay_id| !excluded.contains(relay_id)) {
+            if self
+                .get_guard(*relay_id)
+                .is_some_and(|guard| guard.is_usable(now))
+            {
+                return Ok(*relay_id);
+            }
+        }
+        Err(anyhow::anyhow!("No usable guard available"))
+    }
+
+    pub fn record_success(&mut self, relay_id: RelayId) {
+        if let Some(guard) = self.get_guard_mut(relay_id) {
+            guard.reachability = GuardReachability::Reachable;
+            guard.last_failure = None;
+            if !self.confirmed.contains(&relay_id) {
+                self.confirmed.push(relay_id);
+            }
+            self.update_primary();
+        }
+    }
+
+    pub fn record_failure(&mut self, relay_id: RelayId) {
+        if let Some(guard) = self.get_guard_mut(relay_id) {
+            guard.reachability = GuardReachability::Unreachable;
+            guard.failures += 1;
+            guard.last_failure = Some(chrono::Utc::now().timestamp());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::create_relays;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_refresh_samples_only_guard_relays() {
+        let mut relays = create_relays(4);
+        relays[0].flags = vec![RelayFlag::Fast];
+        let mut guards = GuardSet::default();
+        guards.refresh(&relays);
+        assert_eq!(guards.sampled.len(), 3);
+        assert!(guards.get_guard(relays[0].id).is_none());
+        assert_eq!(guards.primary.len(), NUM_PRIMARY_GUARDS);
+    }
+
+    #[test]
+    fn test_choose_guard_skips_unreachable_primary() {
+        let relays = create_relays(4);
+        let mut guards = GuardSet::default();
+        let first = guards.choose_guard(&relays).unwrap();
+        assert_eq!(first, guards.primary[0]);
+
+        guards.record_failure(first);
+        let second = guards.choose_guard(&relays).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(second, guards.primary[1]);
+    }
+
+    #[test]
+    fn test_choose_guard_excluding() {
+        let relays = create_relays(4);
+        let mut guards = GuardSet::default();
+        let first = guards.choose_guard(&relays).unwrap();
+        let other = guards.choose_guard_excluding(&relays, &[first]).unwrap();
+        assert_ne!(first, other);
+        assert_eq!(guards.choose_guard(&relays).unwrap(), first);
+    }
+
+    #[test]
+    fn test_confirmed_guards_become_primary() {
+        let relays = create_relays(6);
+        let mut guards = GuardSet::default();
+        guards.refresh(&relays);
+        let last_sampled = guards.sampled.last().unwrap().relay_id;
+        guards.record_success(last_sampled);
+        assert_eq!(guards.confirmed, vec![last_sampled]);
+        assert_eq!(guards.primary[0], last_sampled);
+        assert_eq!(guards.choose_guard(&relays).unwrap(), last_sampled);
+    }
+
+    #[test]
+    fn test_no_usable_guard() {
+        let relays = create_relays(2);
+        let mut guards = GuardSet::default();
+        guards.refresh(&relays);
+        for relay in relays.iter() {
+            guards.record_failure(relay.id);
+        }
+        assert!(guards.choose_guard(&relays).is_err());
+    }
+
+    #[test]
+    fn test_guards_dropped_when_relay_leaves() {
+        let mut relays = create_relays(3);
+        let mut guards = GuardSet::default();
+        guards.refresh(&relays);
+        guards.record_success(relays[0].id);
+        let removed = relays.remove(0);
+        guards.refresh(&relays);
+        assert!(guards.get_guard(removed.id).is_none());
+        assert!(!guards.confirmed.contains(&removed.id));
+        assert!(!guards.primary.contains(&removed.id));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let relays = create_relays(3);
+        let mut guards = GuardSet::default();
+        guards.refresh(&relays);
+        guards.record_success(relays[1].id);
+        let path = std::env::temp_dir().join(format!("guards-{}.json", Uuid::new_v4()));
+        guards.save(&path).unwrap();
+        let loaded = GuardSet::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(guards, loaded);
+    }
+}
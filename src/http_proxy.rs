@@ -0,0 +1,251 @@
+use crate::{credentials_token, proxy_stream, EndReason, IsolationKey, StreamClosedError, User};
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// Longest request head a client may send before its tunnel is refused.
+pub const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// Reads the request line and headers, one byte at a time so that nothing
+/// the client sends after them is lost.
+fn read_request_head(client: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_HEAD_SIZE {
+            return Err(anyhow::anyhow!("Request head is too large"));
+        }
+        client
+            .read_exact(&mut byte)
+            .context("Failed to read HTTP request")?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+fn send_response(client: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = if body.is_empty() {
+        format!("HTTP/1.1 {}\r\n\r\n", status)
+    } else {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    };
+    client
+        .write_all(response.as_bytes())
+        .context("Failed to send HTTP response")
+}
+
+/// Splits a CONNECT target such as "example.com:443" or "[::1]:443".
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Status answered when a tunnel could not be opened: 504 when the stream
+/// timed out, 502 otherwise.
+pub fn error_status(error: &anyhow::Error) -> &'static str {
+    match error.downcast_ref::<StreamClosedError>() {
+        Some(StreamClosedError {
+            reason: EndReason::Timeout,
+            ..
+        }) => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    }
+}
+
+/// Serves one HTTP proxy client of `user`: its CONNECT request becomes a
+/// stream, isolated by the Proxy-Authorization it sent, if any.
+pub fn serve_http_client(user: User, mut client: TcpStream) -> Result<()> {
+    let head = read_request_head(&mut client)?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, authority) = (parts.next().unwrap_or_default(), parts.next());
+    if method != "CONNECT" {
+        send_response(
+            &mut client,
+            "405 Method Not Allowed",
+            "Only CONNECT is supported",
+        )?;
+        return Err(anyhow::anyhow!("Unsupported method {}", method));
+    }
+    let Some((host, port)) = authority.and_then(parse_authority) else {
+        send_response(&mut client, "400 Bad Request", "Invalid CONNECT target")?;
+        return Err(anyhow::anyhow!("Invalid request line {}", request_line));
+    };
+    let credentials = lines
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("Proxy-Authorization")
+                .then(|| credentials_token(&[value.trim().as_bytes()]))
+        })
+        .transpose()?;
+
+    let isolation = IsolationKey {
+        credentials,
+        ..Default::default()
+    };
+    match user.connect(isolation, &host, port) {
+        Ok((stream_id, _)) => {
+            send_response(&mut client, "200 Connection Established", "")?;
+            proxy_stream(user, stream_id, client)
+        }
+        // the details are only logged, they are none of the client's business
+        Err(e) => {
+            send_response(&mut client, error_status(&e), "Failed to connect")?;
+            Err(e.context(format!("Failed to connect to {}:{}", host, port)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{start_http_server, start_network, HTTP_RESPONSE};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use uuid::Uuid;
+
+    fn start_proxy() -> (User, SocketAddr) {
+        let user = start_network("TestHttpProxyUser");
+        let address = user
+            .start_http_proxy("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        (user, address)
+    }
+
+    /// Sends `request` to the proxy and returns the connection and the
+    /// response head.
+    fn proxy_request(proxy: SocketAddr, request: &str) -> (TcpStream, String) {
+        let mut client = TcpStream::connect(proxy).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let head = read_request_head(&mut client).unwrap();
+        (client, head)
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        assert_eq!(
+            parse_authority("example.com:443"),
+            Some(("example.com".to_string(), 443))
+        );
+        assert_eq!(parse_authority("[::1]:80"), Some(("::1".to_string(), 80)));
+        assert_eq!(parse_authority("example.com"), None);
+        assert_eq!(parse_authority(":80"), None);
+    }
+
+    #[test]
+    fn test_error_status() {
+        let closed = |reason| {
+            anyhow::Error::new(StreamClosedError {
+                stream_id: Uuid::new_v4(),
+                reason,
+            })
+        };
+        assert_eq!(
+            error_status(&closed(EndReason::Timeout)),
+            "504 Gateway Timeout"
+        );
+        assert_eq!(
+            error_status(&closed(EndReason::ConnectRefused)),
+            "502 Bad Gateway"
+        );
+        assert_eq!(
+            error_status(&anyhow::anyhow!("No circuit")),
+            "502 Bad Gateway"
+        );
+    }
+
+    #[test]
+    fn test_concurrent_connect_tunnels() {
+        let (_user, proxy) = start_proxy();
+        let port = start_http_server();
+
+        let clients: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(move || {
+                    let (mut client, head) = proxy_request(
+                        proxy,
+                        &format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port),
+                    );
+                    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+                    client
+                        .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+                        .unwrap();
+                    let mut response = vec![];
+                    client.read_to_end(&mut response).unwrap();
+                    assert_eq!(response, HTTP_RESPONSE);
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_isolation_by_proxy_authorization() {
+        let (user, proxy) = start_proxy();
+        let port = start_http_server();
+        for authorization in ["Basic YWxpY2U6c2VjcmV0", "Basic Ym9iOnNlY3JldA=="] {
+            let (_, head) = proxy_request(
+                proxy,
+                &format!(
+                    "CONNECT 127.0.0.1:{} HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n",
+                    port, authorization
+                ),
+            );
+            assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        }
+
+        let circuit_isolation = user.get_state().circuit_isolation;
+        let circuit_of = |authorization: &str| {
+            let token = credentials_token(&[authorization.as_bytes()]).unwrap();
+            circuit_isolation
+                .iter()
+                .find(|(_, key)| key.credentials.as_ref() == Some(&token))
+                .map(|(circuit_id, _)| *circuit_id)
+                .unwrap()
+        };
+        assert_ne!(
+            circuit_of("Basic YWxpY2U6c2VjcmV0"),
+            circuit_of("Basic Ym9iOnNlY3JldA==")
+        );
+        assert!(circuit_isolation
+            .values()
+            .all(|key| !format!("{:?}", key).contains("Basic")));
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let (_user, proxy) = start_proxy();
+
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (mut client, head) = proxy_request(
+            proxy,
+            &format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", closed_port),
+        );
+        assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+        let mut body = String::new();
+        client.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "Failed to connect");
+
+        let (_, head) = proxy_request(proxy, "GET http://localhost/ HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 405"), "{}", head);
+    }
+}
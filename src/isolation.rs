@@ -0,0 +1,146 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    /// Key of the credential tokens, so that a token cannot be checked
+    /// against guessed passwords outside this process.
+    static ref CREDENTIALS_KEY: [u8; 32] = thread_rng().gen();
+}
+
+/// Attributes of a stream that decide which circuits it may share.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct IsolationKey {
+    /// Where the stream goes, such as a relay id or a "host:port".
+    pub destination: Option<String>,
+    /// Client session the stream belongs to, such as a chat persona.
+    pub session: Option<String>,
+    /// Token of the credentials the client authenticated with, such as a
+    /// SOCKS username and password, from `credentials_token`. The
+    /// credentials themselves are never kept.
+    pub credentials: Option<String>,
+    /// Explicit isolation group. Streams in different groups never share a
+    /// circuit, whatever the policy.
+    pub group: Option<String>,
+}
+
+/// Which stream attributes keep streams on separate circuits. The default
+/// follows Tor: streams are isolated by session and credentials but may share
+/// a circuit across destinations.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct IsolationPolicy {
+    pub destination: bool,
+    pub session: bool,
+    pub credentials: bool,
+}
+
+impl Default for IsolationPolicy {
+    fn default() -> Self {
+        Self {
+            destination: false,
+            session: true,
+            credentials: true,
+        }
+    }
+}
+
+/// Isolation token for credentials made of `parts`: an HMAC-SHA256 over
+/// each part prefixed with its length, so that splitting the same bytes
+/// differently gives another token.
+pub fn credentials_token(parts: &[&[u8]]) -> Result<String> {
+    let key = PKey::hmac(&*CREDENTIALS_KEY)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    for part in parts {
+        signer.update(&(part.len() as u64).to_be_bytes())?;
+        signer.update(part)?;
+    }
+    Ok(hex::encode(signer.sign_to_vec()?))
+}
+
+impl IsolationKey {
+    /// Keeps only the attributes `policy` isolates on. Two streams may share a
+    /// circuit when their isolated keys are equal.
+    pub fn isolated(&self, policy: &IsolationPolicy) -> IsolationKey {
+        IsolationKey {
+            destination: self.destination.clone().filter(|_| policy.destination),
+            session: self.session.clone().filter(|_| policy.session),
+            credentials: self.credentials.clone().filter(|_| policy.credentials),
+            group: self.group.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_key(destination: &str, session: &str) -> IsolationKey {
+        IsolationKey {
+            destination: Some(destination.to_string()),
+            session: Some(session.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_default_policy_shares_across_destinations() {
+        let policy = IsolationPolicy::default();
+        assert_eq!(
+            create_key("a:80", "alice").isolated(&policy),
+            create_key("b:80", "alice").isolated(&policy)
+        );
+        assert_ne!(
+            create_key("a:80", "alice").isolated(&policy),
+            create_key("a:80", "bob").isolated(&policy)
+        );
+    }
+
+    #[test]
+    fn test_destination_isolation() {
+        let policy = IsolationPolicy {
+            destination: true,
+            ..Default::default()
+        };
+        assert_ne!(
+            create_key("a:80", "alice").isolated(&policy),
+            create_key("b:80", "alice").isolated(&policy)
+        );
+    }
+
+    #[test]
+    fn test_credentials_and_groups() {
+        let policy = IsolationPolicy {
+            destination: false,
+            session: false,
+            credentials: false,
+        };
+        let key = |credentials: &str, group: &str| IsolationKey {
+            credentials: Some(credentials.to_string()),
+            group: Some(group.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            key("alice:secret", "chat").isolated(&policy),
+            key("bob:secret", "chat").isolated(&policy)
+        );
+        assert_ne!(
+            key("alice:secret", "chat").isolated(&policy),
+            key("alice:secret", "mail").isolated(&policy)
+        );
+        assert_ne!(
+            key("alice:secret", "chat").isolated(&IsolationPolicy::default()),
+            key("bob:secret", "chat").isolated(&IsolationPolicy::default())
+        );
+    }
+
+    #[test]
+    fn test_credentials_token() {
+        let token = |parts: &[&[u8]]| credentials_token(parts).unwrap();
+        assert_eq!(token(&[b"alice", b"secret"]), token(&[b"alice", b"secret"]));
+        assert_ne!(token(&[b"a:b", b"c"]), token(&[b"a", b"b:c"]));
+        assert_ne!(token(&[b"ab", b""]), token(&[b"a", b"b"]));
+        assert!(!token(&[b"alice", b"secret"]).contains("secret"));
+    }
+}
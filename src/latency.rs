@@ -0,0 +1,198 @@
+use crate::{RelayDescriptor, RelayId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Weight of a new sample in the smoothed round trip time.
+const RTT_SMOOTHING: f64 = 0.25;
+/// Samples needed before a relay can be considered slow.
+pub const MIN_RTT_SAMPLES: u64 = 3;
+/// Relays slower than this many times the median relay are avoided.
+pub const SLOW_RELAY_FACTOR: f64 = 3.0;
+
+/// Round trip times in milliseconds.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RttStats {
+    pub last_ms: f64,
+    pub min_ms: f64,
+    /// Exponentially weighted moving average.
+    pub average_ms: f64,
+    pub samples: u64,
+}
+
+impl RttStats {
+    pub fn record(&mut self, rtt_ms: f64) {
+        if self.samples == 0 {
+            self.min_ms = rtt_ms;
+            self.average_ms = rtt_ms;
+        } else {
+            self.min_ms = self.min_ms.min(rtt_ms);
+            self.average_ms += RTT_SMOOTHING * (rtt_ms - self.average_ms);
+        }
+        self.last_ms = rtt_ms;
+        self.samples += 1;
+    }
+}
+
+/// Round trip times measured to each hop of a circuit.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct CircuitLatency {
+    /// Indexed by the position of the hop in the circuit.
+    pub hops: Vec<Option<RttStats>>,
+}
+
+impl CircuitLatency {
+    /// Records a round trip to `hop` and returns the time it adds on top of
+    /// the previous hop, if that one was measured too.
+    pub fn record(&mut self, hop: usize, rtt: Duration) -> Option<f64> {
+        if self.hops.len() <= hop {
+            self.hops.resize(hop + 1, None);
+        }
+        let rtt_ms = rtt.as_micros() as f64 / 1000.0;
+        self.hops[hop]
+            .get_or_insert_with(RttStats::default)
+            .record(rtt_ms);
+        match hop {
+            0 => Some(rtt_ms),
+            _ => self.hops[hop - 1]
+                .as_ref()
+                .map(|previous| (rtt_ms - previous.average_ms).max(0.0)),
+        }
+    }
+
+    /// Round trip time of the whole circuit, measured to its furthest hop.
+    pub fn circuit_rtt_ms(&self) -> Option<f64> {
+        self.hops.iter().flatten().last().map(|rtt| rtt.average_ms)
+    }
+
+    /// Latency of each edge of the circuit, edge `i` leading to hop `i`.
+    pub fn edge_latencies_ms(&self) -> Vec<Option<f64>> {
+        (0..self.hops.len())
+            .map(|hop| {
+                let rtt = self.hops[hop].as_ref()?.average_ms;
+                match hop {
+                    0 => Some(rtt),
+                    _ => Some((rtt - self.hops[hop - 1].as_ref()?.average_ms).max(0.0)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Relays measured often enough whose latency is more than
+/// `SLOW_RELAY_FACTOR` times that of the median measured relay.
+pub fn slow_relays(relay_latency: &HashMap<RelayId, RttStats>) -> Vec<RelayId> {
+    let measured: Vec<(&RelayId, f64)> = relay_latency
+        .iter()
+        .filter(|(_, rtt)| rtt.samples >= MIN_RTT_SAMPLES)
+        .map(|(relay_id, rtt)| (relay_id, rtt.average_ms))
+        .collect();
+    if measured.len() < 2 {
+        return vec![];
+    }
+    let mut averages: Vec<f64> = measured.iter().map(|(_, average)| *average).collect();
+    averages.sort_by(|a, b| a.total_cmp(b));
+    let median = averages[averages.len() / 2];
+    measured
+        .into_iter()
+        .filter(|(_, average)| *average > median * SLOW_RELAY_FACTOR)
+        .map(|(relay_id, _)| *relay_id)
+        .collect()
+}
+
+/// Drops slow relays from `relays`, except `keep` (such as the chosen guard).
+pub fn exclude_slow_relays(
+    relays: &[RelayDescriptor],
+    relay_latency: &HashMap<RelayId, RttStats>,
+    keep: &[RelayId],
+) -> Vec<RelayDescriptor> {
+    let slow = slow_relays(relay_latency);
+    relays
+        .iter()
+        .filter(|relay| keep.contains(&relay.id) || !slow.contains(&relay.id))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExitPolicy, RelayFlag};
+    use std::net::Ipv4Addr;
+    use uuid::Uuid;
+
+    fn create_rtt(average_ms: f64, samples: u64) -> RttStats {
+        RttStats {
+            last_ms: average_ms,
+            min_ms: average_ms,
+            average_ms,
+            samples,
+        }
+    }
+
+    #[test]
+    fn test_rtt_stats() {
+        let mut rtt = RttStats::default();
+        rtt.record(10.0);
+        rtt.record(30.0);
+        rtt.record(2.0);
+        assert_eq!(rtt.samples, 3);
+        assert_eq!(rtt.last_ms, 2.0);
+        assert_eq!(rtt.min_ms, 2.0);
+        assert_eq!(rtt.average_ms, 11.75);
+    }
+
+    #[test]
+    fn test_circuit_latency_edges() {
+        let mut latency = CircuitLatency::default();
+        assert_eq!(latency.record(2, Duration::from_millis(30)), None);
+        assert_eq!(latency.record(0, Duration::from_millis(10)), Some(10.0));
+        assert_eq!(latency.record(1, Duration::from_millis(25)), Some(15.0));
+        assert_eq!(latency.circuit_rtt_ms(), Some(30.0));
+        assert_eq!(
+            latency.edge_latencies_ms(),
+            vec![Some(10.0), Some(15.0), Some(5.0)]
+        );
+    }
+
+    #[test]
+    fn test_slow_relays() {
+        let fast = Uuid::new_v4();
+        let also_fast = Uuid::new_v4();
+        let slow = Uuid::new_v4();
+        let barely_measured = Uuid::new_v4();
+        let relay_latency = HashMap::from([
+            (fast, create_rtt(10.0, 5)),
+            (also_fast, create_rtt(12.0, 5)),
+            (slow, create_rtt(100.0, 5)),
+            (barely_measured, create_rtt(500.0, 1)),
+        ]);
+        assert_eq!(slow_relays(&relay_latency), vec![slow]);
+
+        let relays: Vec<RelayDescriptor> = [fast, slow, barely_measured]
+            .iter()
+            .map(|id| RelayDescriptor {
+                id: *id,
+                nickname: id.to_string(),
+                rsa_public: vec![],
+                family: vec![],
+                address: Ipv4Addr::new(10, 0, 0, 1),
+                autonomous_system: 0,
+                bandwidth: 1000,
+                flags: vec![RelayFlag::Fast],
+                exit_policy: ExitPolicy::default(),
+            })
+            .collect();
+        let ids = |relays: Vec<RelayDescriptor>| -> Vec<RelayId> {
+            relays.iter().map(|relay| relay.id).collect()
+        };
+        assert_eq!(
+            ids(exclude_slow_relays(&relays, &relay_latency, &[])),
+            vec![fast, barely_measured]
+        );
+        assert_eq!(
+            ids(exclude_slow_relays(&relays, &relay_latency, &[slow])),
+            vec![fast, slow, barely_measured]
+        );
+    }
+}
@@ -1,19 +1,57 @@
 pub mod api;
+pub mod build_timeout;
+pub mod circuit_pool;
 pub mod communication;
 pub mod crypto;
 pub mod data;
+pub mod data_stream;
 pub mod directory;
+pub mod exit;
+pub mod exit_policy;
+pub mod flow_control;
+pub mod fragment;
+pub mod guard;
+pub mod http_proxy;
+pub mod isolation;
+pub mod latency;
 pub mod logger;
+pub mod onion_service;
+pub mod path;
+pub mod proxy;
 pub mod relay;
+pub mod replay_cache;
+pub mod resolver;
+pub mod socks;
+pub mod stream;
+#[cfg(test)]
+mod test_utils;
 pub mod user;
 pub mod utils;
 
 pub use api::*;
+pub use build_timeout::*;
+pub use circuit_pool::*;
 pub use communication::*;
 pub use crypto::*;
 pub use data::*;
+pub use data_stream::*;
 pub use directory::*;
+pub use exit::*;
+pub use exit_policy::*;
+pub use flow_control::*;
+pub use fragment::*;
+pub use guard::*;
+pub use http_proxy::*;
+pub use isolation::*;
+pub use latency::*;
 pub use logger::*;
+pub use onion_service::*;
+pub use path::*;
+pub use proxy::*;
 pub use relay::*;
+pub use replay_cache::*;
+pub use resolver::*;
+pub use socks::*;
+pub use stream::*;
 pub use user::*;
 pub use utils::*;
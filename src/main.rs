@@ -3,7 +3,7 @@ use std::{
     time::Duration,
 };
 use uuid::Uuid;
-use veilcomm2::{Api, Event, PayloadType, Relay, User};
+use veilcomm2::{Api, Directory, Event, IntroductionAuth, PayloadType, Relay, User};
 
 #[tokio::main]
 async fn main() {
@@ -58,14 +58,14 @@ async fn main() {
     thread::spawn(move || {
         user.start();
         let circuit_id = Uuid::new_v4();
-        user.establish_circuit(circuit_id, relay_id, relay_id_2, relay_id_3)
+        user.establish_circuit(circuit_id, vec![relay_id, relay_id_2, relay_id_3])
             .unwrap();
         user.send_establish_introduction(relay_id, introduction_id, circuit_id)
             .unwrap();
         let new_circuit_id = Uuid::new_v4();
-        user.establish_circuit(new_circuit_id, relay_id_3, relay_id_2, relay_id_6)
+        user.establish_circuit(new_circuit_id, vec![relay_id_3, relay_id_2, relay_id_6])
             .unwrap();
-        user.listen_for_event(Event(PayloadType::Introduce2, relay_id))
+        user.listen_for_event(Event(PayloadType::Introduce2, relay_id, circuit_id))
             .unwrap();
         user.send_rendezvous1(relay_id_3, rendezvous_cookie, new_circuit_id)
             .unwrap();
@@ -78,7 +78,7 @@ async fn main() {
         user_2.start();
         let circuit_id = Uuid::new_v4();
         user_2
-            .establish_circuit(circuit_id, relay_id_4, relay_id_5, relay_id_6)
+            .establish_circuit(circuit_id, vec![relay_id_4, relay_id_5, relay_id_6])
             .unwrap();
         user_2
             .send_establish_rendezvous(relay_id_4, rendezvous_cookie, circuit_id)
@@ -89,7 +89,10 @@ async fn main() {
         user_2
             .send_introduce1(
                 relay_id_4,
-                introduction_id,
+                IntroductionAuth {
+                    introduction_id,
+                    introduction_key: Directory::get_introduction_key(introduction_id).unwrap(),
+                },
                 stream_id,
                 rendezvous_cookie,
                 introduction_rsa_public,
@@ -97,7 +100,7 @@ async fn main() {
             )
             .unwrap();
         user_2
-            .listen_for_event(Event(PayloadType::Rendezvous2, relay_id_4))
+            .listen_for_event(Event(PayloadType::Rendezvous2, relay_id_4, circuit_id))
             .unwrap();
         let data: Vec<u8> = "Hello, world!".as_bytes().to_vec();
         user_2
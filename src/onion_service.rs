@@ -0,0 +1,83 @@
+use crate::{CircuitId, DataStream, IntroductionPointId, RelayId, RendezvousCookieId, UserId};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Introduction points a service keeps by default.
+pub const DEFAULT_INTRODUCTION_POINTS: usize = 3;
+/// INTRODUCE2s waiting to be accepted, beyond which the oldest is dropped.
+pub const MAX_PENDING_INTRODUCTIONS: usize = 64;
+/// Rendezvous points a service joins at once. Further introductions wait
+/// until one of them is done.
+pub const MAX_PENDING_RENDEZVOUS: usize = 8;
+/// How often a service checks for introductions and whether it was dropped.
+pub const ONION_SERVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often a service checks that its introduction circuits are still up.
+pub const INTRODUCTION_POINT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long an introduction point is kept before it is moved to another
+/// relay.
+pub const INTRODUCTION_POINT_LIFETIME: Duration = Duration::from_secs(18 * 60 * 60);
+/// How long a client waits for the service at the rendezvous point before
+/// trying another introduction point.
+pub const INTRODUCTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OnionServiceConfig {
+    /// Introduction points kept, each at a different relay.
+    pub introduction_points: usize,
+    /// Age at which an introduction point is replaced by one at another
+    /// relay.
+    pub rotation_period: Duration,
+}
+
+impl Default for OnionServiceConfig {
+    fn default() -> Self {
+        Self {
+            introduction_points: DEFAULT_INTRODUCTION_POINTS,
+            rotation_period: INTRODUCTION_POINT_LIFETIME,
+        }
+    }
+}
+
+/// Request of a client, received in an INTRODUCE2, to meet at a rendezvous
+/// point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Introduction {
+    pub rendezvous_cookie: RendezvousCookieId,
+    pub rendezvous_point: RelayId,
+}
+
+/// Introduction point of a service, at the last hop of its own circuit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntroductionPoint {
+    pub introduction_id: IntroductionPointId,
+    pub relay_id: RelayId,
+    pub circuit_id: CircuitId,
+    pub established_at: Instant,
+}
+
+/// Onion service hosted by a user, reachable through the introduction points
+/// published in the descriptor of the user. Dropping it stops the service.
+pub struct OnionService {
+    address: UserId,
+    connections: mpsc::UnboundedReceiver<DataStream>,
+}
+
+impl OnionService {
+    pub fn new(address: UserId, connections: mpsc::UnboundedReceiver<DataStream>) -> Self {
+        Self {
+            address,
+            connections,
+        }
+    }
+
+    /// Address clients connect to, the id of the hosting user.
+    pub fn address(&self) -> UserId {
+        self.address
+    }
+
+    /// Waits for the next client. Returns None once the service stopped.
+    pub async fn accept(&mut self) -> Option<DataStream> {
+        self.connections.recv().await
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{Directory, RelayDescriptor, RelayFlag, RelayId};
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_CIRCUIT_LENGTH: usize = 3;
+pub const MAX_CIRCUIT_LENGTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum CircuitPurpose {
+    General,
+    Introduction,
+    Rendezvous,
+    HSDir,
+}
+
+impl CircuitPurpose {
+    /// Flags required from the last relay of a circuit built for this purpose.
+    pub fn last_hop_flags(&self) -> Vec<RelayFlag> {
+        match self {
+            CircuitPurpose::General => vec![RelayFlag::Exit],
+            CircuitPurpose::Introduction | CircuitPurpose::Rendezvous => vec![RelayFlag::Stable],
+            CircuitPurpose::HSDir => vec![RelayFlag::HSDir],
+        }
+    }
+}
+
+/// Two relays are in the same family only if each lists the other.
+pub fn same_family(relay_1: &RelayDescriptor, relay_2: &RelayDescriptor) -> bool {
+    relay_1.family.contains(&relay_2.id) && relay_2.family.contains(&relay_1.id)
+}
+
+pub fn same_subnet(relay_1: &RelayDescriptor, relay_2: &RelayDescriptor) -> bool {
+    relay_1.address.octets()[0..2] == relay_2.address.octets()[0..2]
+}
+
+pub fn same_autonomous_system(relay_1: &RelayDescriptor, relay_2: &RelayDescriptor) -> bool {
+    relay_1.autonomous_system == relay_2.autonomous_system
+}
+
+/// Returns true if the two relays must not appear together in a circuit.
+pub fn are_related(relay_1: &RelayDescriptor, relay_2: &RelayDescriptor) -> bool {
+    same_family(relay_1, relay_2)
+        || same_subnet(relay_1, relay_2)
+        || same_autonomous_system(relay_1, relay_2)
+}
+
+/// Checks that no two relays of a path are related to each other.
+pub fn validate_path(path: &[RelayDescriptor]) -> Result<()> {
+    for (i, relay_1) in path.iter().enumerate() {
+        for relay_2 in path.iter().skip(i + 1) {
+            if are_related(relay_1, relay_2) {
+                return Err(anyhow::anyhow!(
+                    "Relays {} and {} are related and cannot share a circuit",
+                    relay_1.nickname,
+                    relay_2.nickname
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Validates a user-provided circuit path: it must have between one and
+/// `MAX_CIRCUIT_LENGTH` hops, no repeated relays, only relays known to the
+/// directory, and no related relays.
+pub fn validate_circuit_path(path: &[RelayId]) -> Result<Vec<RelayDescriptor>> {
+    if path.is_empty() {
+        return Err(anyhow::anyhow!("Circuit path is empty"));
+    }
+    if path.len() > MAX_CIRCUIT_LENGTH {
+        return Err(anyhow::anyhow!(
+            "Circuit path has {} hops, the maximum is {}",
+            path.len(),
+            MAX_CIRCUIT_LENGTH
+        ));
+    }
+    for (i, relay_id) in path.iter().enumerate() {
+        if path[..i].contains(relay_id) {
+            return Err(anyhow::anyhow!("Relay {} appears twice in path", relay_id));
+        }
+    }
+    let descriptors = path
+        .iter()
+        .map(|relay_id| {
+            Directory::get_relay(*relay_id)
+                .ok_or_else(|| anyhow::anyhow!("Relay {} not found in directory", relay_id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    validate_path(&descriptors)?;
+    Ok(descriptors)
+}
+
+/// Flags a relay needs to be used at `position` of a circuit of `length` hops.
+pub fn position_flags(purpose: CircuitPurpose, position: usize, length: usize) -> Vec<RelayFlag> {
+    let mut flags = vec![];
+    if position == 0 {
+        flags.push(RelayFlag::Guard);
+    }
+    if position + 1 == length {
+        flags.extend(purpose.last_hop_flags());
+    }
+    if flags.is_empty() {
+        flags.push(RelayFlag::Fast);
+    }
+    flags
+}
+
+/// Picks a relay for `position` among `relays`, weighted by bandwidth, that is
+/// neither already part of `chosen` nor related to any relay in it.
+pub fn select_relay(
+    relays: &[RelayDescriptor],
+    chosen: &[RelayDescriptor],
+    flags: &[RelayFlag],
+) -> Result<RelayDescriptor> {
+    let candidates: Vec<&RelayDescriptor> = relays
+        .iter()
+        .filter(|relay| flags.iter().all(|flag| relay.has_flag(*flag)))
+        .filter(|relay| {
+            chosen
+                .iter()
+                .all(|other| other.id != relay.id && !are_related(relay, other))
+        })
+        .collect();
+    candidates
+        .choose_weighted(&mut rand::thread_rng(), |relay| relay.bandwidth)
+        .map(|relay| (*relay).clone())
+        .map_err(|e| anyhow::anyhow!("No suitable relay with flags {:?}: {}", flags, e))
+}
+
+/// Selects a path of `length` relays for `purpose`. The last hop is picked
+/// first, then the first hop, then the middle hops, like Tor does. When a
+/// `guard` is given it is used as the first hop.
+pub fn select_path(
+    relays: &[RelayDescriptor],
+    purpose: CircuitPurpose,
+    length: usize,
+    guard: Option<&RelayDescriptor>,
+) -> Result<Vec<RelayDescriptor>> {
+    select_path_with_exit(relays, purpose, length, guard, |_| true)
+}
+
+/// Selects a path like `select_path`, with a last hop accepted by
+/// `is_suitable_exit`, such as an exit whose policy allows a destination.
+pub fn select_path_with_exit(
+    relays: &[RelayDescriptor],
+    purpose: CircuitPurpose,
+    length: usize,
+    guard: Option<&RelayDescriptor>,
+    is_suitable_exit: impl Fn(&RelayDescriptor) -> bool,
+) -> Result<Vec<RelayDescriptor>> {
+    if length == 0 {
+        return Err(anyhow::anyhow!("Path length must be at least 1"));
+    }
+    let mut path: Vec<Option<RelayDescriptor>> = vec![None; length];
+    let mut chosen = vec![];
+    if let Some(guard) = guard {
+        let flags = position_flags(purpose, 0, length);
+        if !flags.iter().all(|flag| guard.has_flag(*flag)) {
+            return Err(anyhow::anyhow!(
+                "Guard {} is missing flags {:?}",
+                guard.nickname,
+                flags
+            ));
+        }
+        if length == 1 && !is_suitable_exit(guard) {
+            return Err(anyhow::anyhow!(
+                "Guard {} is not a suitable exit",
+                guard.nickname
+            ));
+        }
+        chosen.push(guard.clone());
+        path[0] = Some(guard.clone());
+    }
+
+    let mut order: Vec<usize> = vec![length - 1];
+    if length > 1 {
+        order.push(0);
+    }
+    order.extend(1..length.saturating_sub(1));
+
+    for position in order {
+        if path[position].is_some() {
+            continue;
+        }
+        let flags = position_flags(purpose, position, length);
+        let relay = if position + 1 == length {
+            let exits: Vec<RelayDescriptor> = relays
+                .iter()
+                .filter(|relay| is_suitable_exit(relay))
+                .cloned()
+                .collect();
+            select_relay(&exits, &chosen, &flags)?
+        } else {
+            select_relay(relays, &chosen, &flags)?
+        };
+        chosen.push(relay.clone());
+        path[position] = Some(relay);
+    }
+    Ok(path.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_relay_descriptor, create_relays};
+    use crate::ExitPolicy;
+    use std::net::Ipv4Addr;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_unrelated_relays() {
+        let relay_1 = create_relay_descriptor(Ipv4Addr::new(10, 0, 0, 1), 1);
+        let relay_2 = create_relay_descriptor(Ipv4Addr::new(10, 1, 0, 1), 2);
+        assert!(!are_related(&relay_1, &relay_2));
+        assert!(validate_path(&[relay_1, relay_2]).is_ok());
+    }
+
+    #[test]
+    fn test_family_requires_mutual_declaration() {
+        let mut relay_1 = create_relay_descriptor(Ipv4Addr::new(10, 0, 0, 1), 1);
+        let mut relay_2 = create_relay_descriptor(Ipv4Addr::new(10, 1, 0, 1), 2);
+
+        relay_1.family.push(relay_2.id);
+        assert!(!same_family(&relay_1, &relay_2));
+        assert!(!are_related(&relay_1, &relay_2));
+
+        relay_2.family.push(relay_1.id);
+        assert!(same_family(&relay_1, &relay_2));
+        assert!(are_related(&relay_1, &relay_2));
+    }
+
+    #[test]
+    fn test_same_subnet() {
+        let relay_1 = create_relay_descriptor(Ipv4Addr::new(10, 0, 0, 1), 1);
+        let relay_2 = create_relay_descriptor(Ipv4Addr::new(10, 0, 255, 7), 2);
+        assert!(same_subnet(&relay_1, &relay_2));
+        assert!(are_related(&relay_1, &relay_2));
+    }
+
+    #[test]
+    fn test_same_autonomous_system() {
+        let relay_1 = create_relay_descriptor(Ipv4Addr::new(10, 0, 0, 1), 7);
+        let relay_2 = create_relay_descriptor(Ipv4Addr::new(10, 1, 0, 1), 7);
+        assert!(same_autonomous_system(&relay_1, &relay_2));
+        assert!(are_related(&relay_1, &relay_2));
+    }
+
+    #[test]
+    fn test_validate_path_rejects_related_relays() {
+        let relay_1 = create_relay_descriptor(Ipv4Addr::new(10, 0, 0, 1), 1);
+        let relay_2 = create_relay_descriptor(Ipv4Addr::new(10, 1, 0, 1), 2);
+        let relay_3 = create_relay_descriptor(Ipv4Addr::new(10, 0, 3, 1), 3);
+        assert!(validate_path(&[relay_1, relay_2, relay_3]).is_err());
+    }
+
+    #[test]
+    fn test_select_path_length_and_no_repeats() {
+        let relays = create_relays(6);
+        for _ in 0..20 {
+            let path = select_path(&relays, CircuitPurpose::General, 3, None).unwrap();
+            assert_eq!(path.len(), 3);
+            assert_ne!(path[0].id, path[1].id);
+            assert_ne!(path[1].id, path[2].id);
+            assert_ne!(path[0].id, path[2].id);
+        }
+    }
+
+    #[test]
+    fn test_select_path_respects_position_flags() {
+        let mut relays = create_relays(6);
+        relays[0].flags = vec![RelayFlag::Guard];
+        relays[1].flags = vec![RelayFlag::Fast];
+        relays[2].flags = vec![RelayFlag::Exit];
+        relays.truncate(3);
+        for _ in 0..20 {
+            let path = select_path(&relays, CircuitPurpose::General, 3, None).unwrap();
+            assert_eq!(path[0].id, relays[0].id);
+            assert_eq!(path[1].id, relays[1].id);
+            assert_eq!(path[2].id, relays[2].id);
+        }
+        assert!(select_path(&relays, CircuitPurpose::HSDir, 3, None).is_err());
+    }
+
+    #[test]
+    fn test_select_path_weighted_by_bandwidth() {
+        let mut relays = create_relays(6);
+        relays[5].bandwidth = 0;
+        for _ in 0..50 {
+            let path = select_path(&relays, CircuitPurpose::General, 3, None).unwrap();
+            assert!(path.iter().all(|relay| relay.id != relays[5].id));
+        }
+    }
+
+    #[test]
+    fn test_select_path_avoids_related_relays() {
+        let mut relays = create_relays(4);
+        relays[1].autonomous_system = relays[0].autonomous_system;
+        for _ in 0..20 {
+            let path = select_path(&relays, CircuitPurpose::General, 3, None).unwrap();
+            assert!(validate_path(&path).is_ok());
+        }
+        assert!(select_path(&relays, CircuitPurpose::General, 4, None).is_err());
+    }
+
+    #[test]
+    fn test_select_path_uses_guard_as_first_hop() {
+        let relays = create_relays(6);
+        for _ in 0..20 {
+            let path = select_path(&relays, CircuitPurpose::General, 3, Some(&relays[4])).unwrap();
+            assert_eq!(path[0].id, relays[4].id);
+            assert!(validate_path(&path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_select_path_with_exit() {
+        let mut relays = create_relays(6);
+        for relay in relays.iter_mut().skip(1) {
+            relay.exit_policy = ExitPolicy::reject_all();
+        }
+        let allows_http =
+            |relay: &RelayDescriptor| relay.exit_policy.allows_destination("10.9.9.9", 80);
+        for _ in 0..20 {
+            let path =
+                select_path_with_exit(&relays, CircuitPurpose::General, 3, None, allows_http)
+                    .unwrap();
+            assert_eq!(path[2].id, relays[0].id);
+        }
+        assert!(select_path_with_exit(
+            &relays,
+            CircuitPurpose::General,
+            1,
+            Some(&relays[1]),
+            allows_http
+        )
+        .is_err());
+        relays[0].exit_policy = ExitPolicy::reject_all();
+        assert!(
+            select_path_with_exit(&relays, CircuitPurpose::General, 3, None, allows_http).is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_circuit_path() {
+        let mut relays = create_relays(3);
+        for relay in relays.iter_mut() {
+            // keep these unreachable relays out of other tests' path selection
+            relay.flags = vec![];
+            Directory::publish_relay(relay.clone());
+        }
+        let ids: Vec<RelayId> = relays.iter().map(|relay| relay.id).collect();
+
+        assert_eq!(validate_circuit_path(&ids[..1]).unwrap().len(), 1);
+        assert_eq!(validate_circuit_path(&ids).unwrap().len(), 3);
+        assert!(validate_circuit_path(&[]).is_err());
+        assert!(validate_circuit_path(&[ids[0], ids[1], ids[0]]).is_err());
+        assert!(validate_circuit_path(&[ids[0], Uuid::new_v4()]).is_err());
+        assert!(validate_circuit_path(&[ids[0]; MAX_CIRCUIT_LENGTH + 1]).is_err());
+    }
+
+    #[test]
+    fn test_select_path_variable_length() {
+        let relays = create_relays(8);
+        let path = select_path(&relays, CircuitPurpose::HSDir, 1, None).unwrap();
+        assert_eq!(path.len(), 1);
+        let path = select_path(&relays, CircuitPurpose::General, 6, None).unwrap();
+        assert_eq!(path.len(), 6);
+        assert!(validate_path(&path).is_ok());
+        assert!(select_path(&relays, CircuitPurpose::General, 0, None).is_err());
+    }
+}
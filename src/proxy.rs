@@ -0,0 +1,53 @@
+use crate::{EndReason, StreamId, User, EXIT_READ_SIZE};
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// How long a proxy waits for stream data before waiting again.
+const PROXY_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Copies bytes between the client of a proxy and `stream_id` until either
+/// side closes. The stream is ended when the client goes away, and the
+/// client is disconnected when the stream ends.
+pub fn proxy_stream(user: User, stream_id: StreamId, mut client: TcpStream) -> Result<()> {
+    let mut client_reader = client
+        .try_clone()
+        .context("Failed to clone client socket")?;
+    let upstream_user = user.clone();
+    thread::spawn(move || {
+        let mut buffer = [0; EXIT_READ_SIZE];
+        let reason = loop {
+            match client_reader.read(&mut buffer) {
+                Ok(0) => break EndReason::Done,
+                Ok(length) => {
+                    if upstream_user
+                        .send_stream_data(stream_id, buffer[..length].to_vec())
+                        .is_err()
+                    {
+                        // closed by the other end
+                        return;
+                    }
+                }
+                Err(_) => break EndReason::ConnReset,
+            }
+        };
+        let _ = upstream_user.end_stream(stream_id, reason);
+    });
+
+    let result = loop {
+        match user.recv_stream(stream_id, PROXY_READ_TIMEOUT) {
+            Ok(Some(data)) => {
+                if let Err(e) = client.write_all(&data) {
+                    let _ = user.end_stream(stream_id, EndReason::ConnReset);
+                    break Err(e).context("Failed to write to client");
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = client.shutdown(Shutdown::Both);
+    result
+}
@@ -1,23 +1,70 @@
+use crate::{
+    connect_to_destination, deliver_data_cell, package_data_cell, resolve_host, Directory,
+    ExitWrite, FlowControlError, FlowWindow, Logger, RelayId, ResolvedPayload, Resolver,
+    SendmePayload, SystemResolver, EXIT_READ_SIZE, EXIT_WRITE_QUEUE_SIZE,
+};
 use crate::{
     decrypt_buffer_with_aes, encrypt_buffer_with_aes, get_handshake_from_onion_skin,
     payloads::{self, CreatePayload},
-    Communication, ConnectedPayload, Keys, Payload, PayloadType, RelayCell, RelayState,
+    verify_introduction_mac, BeginTarget, CircuitId, Communication, ConnectedPayload, DataPayload,
+    DestroyPayload, DestroyReason, EndPayload, EndReason, ExitConnection, ExitPolicy,
+    Introduce1Payload, IntroductionMacInput, IntroductionPointId, Keys, Payload, PongPayload,
+    RelayCell, RelayState, ReplayCache, StreamId, TruncatedPayload,
 };
-use crate::{Directory, Logger};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    io::{Read, Write},
+    net::{Ipv4Addr, Shutdown, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
 };
 use uuid::Uuid;
 
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum RelayFlag {
+    Guard,
+    Exit,
+    Fast,
+    Stable,
+    HSDir,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RelayDescriptor {
     pub id: Uuid,
     pub nickname: String,
     pub rsa_public: Vec<u8>,
+    /// Relays this relay claims to share an operator with. A family is only
+    /// effective when both relays list each other.
+    pub family: Vec<RelayId>,
+    /// Synthetic address, used to detect relays in the same /16.
+    pub address: Ipv4Addr,
+    /// Synthetic autonomous system tag.
+    pub autonomous_system: u32,
+    /// Advertised bandwidth in KB/s, used to weight path selection.
+    pub bandwidth: u64,
+    pub flags: Vec<RelayFlag>,
+    /// Destinations the relay connects streams to when used as an exit.
+    #[serde(default)]
+    pub exit_policy: ExitPolicy,
 }
 
+impl RelayDescriptor {
+    pub fn has_flag(&self, flag: RelayFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+}
+
+pub const DEFAULT_BANDWIDTH: u64 = 1000;
+
+/// How often an exit stream waiting for a SENDME checks whether it was
+/// closed meanwhile.
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct RelayInternalState {
     pub circuits_ids: HashMap<Uuid, Uuid>,
     pub handshakes: HashMap<Uuid, Vec<u8>>,
@@ -25,11 +72,528 @@ pub struct RelayInternalState {
     pub circuits_map: HashMap<Uuid, (Uuid, bool)>,
     pub rendezvous_points: HashMap<Uuid, Uuid>,
     pub introduction_points: HashMap<Uuid, Uuid>,
+    /// Keys the INTRODUCE1s for each introduction point are authenticated
+    /// with.
+    pub introduction_keys: HashMap<IntroductionPointId, Vec<u8>>,
+    /// MACs of the INTRODUCE1s forwarded to services.
+    pub introduction_replay_cache: ReplayCache,
     pub streams: HashMap<Uuid, Uuid>,
+    /// Circuit each stream ending at this relay is carried by.
+    pub stream_circuits: HashMap<StreamId, CircuitId>,
+    pub exit_connections: HashMap<StreamId, ExitConnection>,
+    /// Flow control windows of the circuits streams end at this relay on.
+    pub circuit_windows: HashMap<CircuitId, FlowWindow>,
+    pub stream_windows: HashMap<StreamId, FlowWindow>,
+}
+
+impl RelayInternalState {
+    /// Whether `payload` goes between the two users joined at this
+    /// rendezvous point rather than to one of the streams of this relay.
+    fn is_session_payload(&self, payload: &Payload) -> bool {
+        match payload {
+            Payload::Data(data_payload) => data_payload.stream_id.is_none(),
+            Payload::End(end_payload) => !self.stream_circuits.contains_key(&end_payload.stream_id),
+            _ => false,
+        }
+    }
+
+    /// Checks the MAC of an INTRODUCE1 for introduction point
+    /// `introduction_id` of this relay and records it in the replay cache.
+    /// Returns why it must be dropped, if it must.
+    fn check_introduce1(
+        &mut self,
+        introduction_id: IntroductionPointId,
+        introduce1_payload: &Introduce1Payload,
+    ) -> Result<(), &'static str> {
+        let introduction_key = self
+            .introduction_keys
+            .get(&introduction_id)
+            .ok_or("no key for the introduction point")?;
+        let input = IntroductionMacInput {
+            introduction_id,
+            rendezvous_cookie: introduce1_payload.rendezvous_cookie,
+            rendezvous_point: introduce1_payload.rendezvous_point,
+            onion_skin: &introduce1_payload.onion_skin,
+        };
+        if !verify_introduction_mac(introduction_key, &input, &introduce1_payload.mac) {
+            return Err("invalid MAC");
+        }
+        if !self
+            .introduction_replay_cache
+            .check(&introduce1_payload.mac)
+        {
+            return Err("replayed");
+        }
+        Ok(())
+    }
+
+    fn remove_stale_introduction_keys(&mut self) {
+        let introduction_points = &self.introduction_points;
+        self.introduction_keys
+            .retain(|introduction_id, _| introduction_points.contains_key(introduction_id));
+    }
+
+    /// Frees all state of `circuit_id` and of the circuit it is joined to, if
+    /// any. Returns the circuits and neighbours that must be told about it.
+    pub fn remove_circuit(&mut self, circuit_id: CircuitId) -> Vec<(CircuitId, RelayId)> {
+        let mut removed = vec![circuit_id];
+        if let Some((other_circuit_id, _)) = self.circuits_map.remove(&circuit_id) {
+            self.circuits_map.remove(&other_circuit_id);
+            removed.push(other_circuit_id);
+        }
+        let mut neighbours = vec![];
+        for circuit_id in removed.iter() {
+            self.handshakes.remove(circuit_id);
+            self.circuit_windows.remove(circuit_id);
+            if let Some(relay_id) = self.circuits_ids.remove(circuit_id) {
+                neighbours.push((*circuit_id, relay_id));
+            }
+        }
+        self.rendezvous_points
+            .retain(|_, circuit_id| !removed.contains(circuit_id));
+        self.introduction_points
+            .retain(|_, circuit_id| !removed.contains(circuit_id));
+        self.remove_stale_introduction_keys();
+        let streams: Vec<StreamId> = self
+            .stream_circuits
+            .iter()
+            .filter(|(_, circuit_id)| removed.contains(circuit_id))
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+        for stream_id in streams {
+            self.remove_stream(stream_id);
+        }
+        neighbours
+    }
+
+    /// Forgets `stream_id` and closes its exit connection, if any. Returns
+    /// the circuit that carried it.
+    pub fn remove_stream(&mut self, stream_id: StreamId) -> Option<CircuitId> {
+        self.streams.remove(&stream_id);
+        self.stream_windows.remove(&stream_id);
+        if let Some(connection) = self.exit_connections.remove(&stream_id) {
+            let _ = connection.socket.shutdown(Shutdown::Both);
+        }
+        self.stream_circuits.remove(&stream_id)
+    }
+
+    /// Takes room for a DATA cell of `stream_id` in the windows of the stream
+    /// and of `circuit_id`.
+    pub fn package_data_cell(&mut self, circuit_id: CircuitId, stream_id: StreamId) -> bool {
+        package_data_cell(
+            self.circuit_windows
+                .entry(circuit_id)
+                .or_insert_with(FlowWindow::circuit),
+            self.stream_windows
+                .entry(stream_id)
+                .or_insert_with(FlowWindow::stream),
+        )
+    }
+
+    /// Forgets everything after `circuit_id` on this relay, making it the last
+    /// hop. Returns the circuit and neighbour the dropped part went to.
+    pub fn remove_next_hop(&mut self, circuit_id: CircuitId) -> Option<(CircuitId, RelayId)> {
+        let Some((next_circuit_id, true)) = self.circuits_map.get(&circuit_id).copied() else {
+            return None;
+        };
+        self.circuits_map.remove(&circuit_id);
+        self.circuits_map.remove(&next_circuit_id);
+        self.handshakes.remove(&next_circuit_id);
+        self.rendezvous_points
+            .retain(|_, circuit_id| *circuit_id != next_circuit_id);
+        self.introduction_points
+            .retain(|_, circuit_id| *circuit_id != next_circuit_id);
+        self.remove_stale_introduction_keys();
+        self.circuits_ids
+            .remove(&next_circuit_id)
+            .map(|relay_id| (next_circuit_id, relay_id))
+    }
+}
+
+fn send_destroy(
+    nickname: &str,
+    my_id: RelayId,
+    relay_id: RelayId,
+    circuit_id: CircuitId,
+    reason: DestroyReason,
+) {
+    let destroy_payload = Payload::Destroy(DestroyPayload { reason });
+    let relay_cell = RelayCell {
+        circuit_id,
+        payload: serde_json::to_vec(&destroy_payload).unwrap(),
+    };
+    match Communication::send(my_id, relay_id, relay_cell) {
+        Ok(_) => Logger::info(
+            nickname,
+            format!("Sent DESTROY for circuit {} to {}", circuit_id, relay_id),
+        ),
+        Err(e) => Logger::warn(
+            nickname,
+            format!(
+                "Failed to send DESTROY for circuit {} to {}: {}",
+                circuit_id, relay_id, e
+            ),
+        ),
+    }
+}
+
+/// Tears down `circuit_id` and the circuit joined to it, sending DESTROY to
+/// their neighbours.
+fn close_circuit(
+    nickname: &str,
+    my_id: RelayId,
+    state: &mut RelayInternalState,
+    circuit_id: CircuitId,
+    reason: DestroyReason,
+) {
+    for (circuit_id, relay_id) in state.remove_circuit(circuit_id) {
+        send_destroy(nickname, my_id, relay_id, circuit_id, reason);
+    }
+}
+
+/// Tears down `circuit_id` after `relay_id`, one of its neighbours, could not
+/// be reached.
+fn close_unreachable_circuit(
+    nickname: &str,
+    my_id: RelayId,
+    state: &mut RelayInternalState,
+    circuit_id: CircuitId,
+    relay_id: RelayId,
+    error: anyhow::Error,
+) {
+    Logger::warn(
+        nickname,
+        format!(
+            "Closing circuit {}, relay {} is unreachable: {}",
+            circuit_id, relay_id, error
+        ),
+    );
+    close_circuit(
+        nickname,
+        my_id,
+        state,
+        circuit_id,
+        DestroyReason::ChannelClosed,
+    );
+}
+
+/// Tears down `circuit_id`, whose user broke the flow control rules.
+fn close_misbehaving_circuit(
+    nickname: &str,
+    my_id: RelayId,
+    state: &mut RelayInternalState,
+    circuit_id: CircuitId,
+    error: FlowControlError,
+) {
+    Logger::warn(
+        nickname,
+        format!("Closing circuit {}: {}", circuit_id, error),
+    );
+    close_circuit(nickname, my_id, state, circuit_id, DestroyReason::Protocol);
+}
+
+/// Sends `payload` back towards the user on `circuit_id`, encrypted with the
+/// key this relay shares with the user.
+fn send_backward(
+    nickname: &str,
+    my_id: RelayId,
+    state: &RelayInternalState,
+    circuit_id: CircuitId,
+    payload: &Payload,
+) {
+    let (Some(handshake), Some(relay_id)) = (
+        state.handshakes.get(&circuit_id),
+        state.circuits_ids.get(&circuit_id),
+    ) else {
+        Logger::error(nickname, format!("No circuit {} to answer on", circuit_id));
+        return;
+    };
+    let relay_cell = RelayCell {
+        circuit_id,
+        payload: encrypt_buffer_with_aes(handshake, &serde_json::to_vec(payload).unwrap()).unwrap(),
+    };
+    if let Err(e) = Communication::send(my_id, *relay_id, relay_cell) {
+        Logger::warn(
+            nickname,
+            format!(
+                "Failed to send {:?} on circuit {}: {}",
+                payload.get_type(),
+                circuit_id,
+                e
+            ),
+        );
+    }
+}
+
+/// Passes `payload` on to the user whose circuit was joined to `circuit_id`
+/// at this rendezvous point. Returns false if `circuit_id` is not joined.
+fn send_to_joined_circuit(
+    nickname: &str,
+    my_id: RelayId,
+    state: &RelayInternalState,
+    circuit_id: CircuitId,
+    payload: &Payload,
+) -> bool {
+    let Some((joined_circuit_id, _)) = state
+        .circuits_map
+        .get(&circuit_id)
+        .copied()
+        .filter(|(joined_circuit_id, _)| state.handshakes.contains_key(joined_circuit_id))
+    else {
+        return false;
+    };
+    send_backward(nickname, my_id, state, joined_circuit_id, payload);
+    Logger::info(
+        nickname,
+        format!(
+            "Forwarded {:?} payload to the joined circuit",
+            payload.get_type()
+        ),
+    );
+    true
+}
+
+/// Connects exit stream `stream_id` to `host:port` and answers its BEGIN,
+/// then turns what the destination sends into DATA cells until it closes.
+#[allow(clippy::too_many_arguments)]
+fn open_exit_stream(
+    nickname: String,
+    my_id: RelayId,
+    internal_state: Arc<Mutex<RelayInternalState>>,
+    window_updates: Arc<Condvar>,
+    circuit_id: CircuitId,
+    stream_id: StreamId,
+    (host, port): (String, u16),
+    exit_policy: &ExitPolicy,
+    resolver: &dyn Resolver,
+) {
+    let destination = format!("{}:{}", host, port);
+    let connected = connect_to_destination(&host, port, exit_policy, resolver).and_then(|socket| {
+        let reader = socket.try_clone().map_err(|_| EndReason::Internal)?;
+        let writer = socket.try_clone().map_err(|_| EndReason::Internal)?;
+        Ok((socket, reader, writer))
+    });
+    let mut internal_state_lock = internal_state.lock().unwrap();
+    if internal_state_lock.stream_circuits.get(&stream_id) != Some(&circuit_id) {
+        // the stream or its circuit was closed while connecting
+        return;
+    }
+    let mut reader = match connected {
+        Ok((socket, reader, writer)) => {
+            let (writes_sender, writes) = mpsc::sync_channel(EXIT_WRITE_QUEUE_SIZE);
+            internal_state_lock.exit_connections.insert(
+                stream_id,
+                ExitConnection {
+                    destination: destination.clone(),
+                    socket,
+                    writer: writes_sender,
+                },
+            );
+            let writer_nickname = nickname.clone();
+            let writer_state = internal_state.clone();
+            std::thread::spawn(move || {
+                write_exit_stream(
+                    writer_nickname,
+                    my_id,
+                    writer_state,
+                    (circuit_id, stream_id),
+                    writer,
+                    writes,
+                );
+            });
+            Logger::info(
+                &nickname,
+                format!("Connected stream {} to {}", stream_id, destination),
+            );
+            send_backward(
+                &nickname,
+                my_id,
+                &internal_state_lock,
+                circuit_id,
+                &Payload::Connected(ConnectedPayload { stream_id }),
+            );
+            reader
+        }
+        Err(reason) => {
+            internal_state_lock.remove_stream(stream_id);
+            Logger::warn(
+                &nickname,
+                format!(
+                    "Failed to connect stream {} to {}: {:?}",
+                    stream_id, destination, reason
+                ),
+            );
+            send_backward(
+                &nickname,
+                my_id,
+                &internal_state_lock,
+                circuit_id,
+                &Payload::End(EndPayload { stream_id, reason }),
+            );
+            return;
+        }
+    };
+    drop(internal_state_lock);
+
+    let mut buffer = [0; EXIT_READ_SIZE];
+    let reason = loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break EndReason::Done,
+            Ok(length) => {
+                // stops reading from the destination until a SENDME
+                let mut internal_state_lock = internal_state.lock().unwrap();
+                loop {
+                    if !internal_state_lock
+                        .exit_connections
+                        .contains_key(&stream_id)
+                    {
+                        return;
+                    }
+                    if internal_state_lock.package_data_cell(circuit_id, stream_id) {
+                        break;
+                    }
+                    internal_state_lock = window_updates
+                        .wait_timeout(internal_state_lock, WINDOW_POLL_INTERVAL)
+                        .unwrap()
+                        .0;
+                }
+                let data_payload = Payload::Data(DataPayload {
+                    data: buffer[..length].to_vec(),
+                    stream_id: Some(stream_id),
+                    rendezvous_cookie: None,
+                    fragment: None,
+                });
+                send_backward(
+                    &nickname,
+                    my_id,
+                    &internal_state_lock,
+                    circuit_id,
+                    &data_payload,
+                );
+            }
+            Err(_) => break EndReason::ConnReset,
+        }
+    };
+    // unless the user or the circuit already closed the stream
+    let mut internal_state_lock = internal_state.lock().unwrap();
+    if internal_state_lock.remove_stream(stream_id).is_some() {
+        Logger::info(
+            &nickname,
+            format!(
+                "Connection of stream {} to {} closed with reason {:?}",
+                stream_id, destination, reason
+            ),
+        );
+        send_backward(
+            &nickname,
+            my_id,
+            &internal_state_lock,
+            circuit_id,
+            &Payload::End(EndPayload { stream_id, reason }),
+        );
+    }
+}
+
+/// Writes the DATA cells of exit stream `stream_id` to its destination and
+/// sends the SENDMEs for them once written, until the stream is closed.
+fn write_exit_stream(
+    nickname: String,
+    my_id: RelayId,
+    internal_state: Arc<Mutex<RelayInternalState>>,
+    (circuit_id, stream_id): (CircuitId, StreamId),
+    mut writer: TcpStream,
+    writes: Receiver<ExitWrite>,
+) {
+    for ExitWrite { data, sendmes } in writes {
+        let written = writer.write_all(&data);
+        let mut internal_state_lock = internal_state.lock().unwrap();
+        let open = written.is_ok()
+            && internal_state_lock
+                .exit_connections
+                .contains_key(&stream_id);
+        // the circuit window is shared with other streams, so its SENDMEs
+        // are owed even if this stream is gone
+        for sendme in sendmes {
+            if open || sendme.stream_id.is_none() {
+                send_backward(
+                    &nickname,
+                    my_id,
+                    &internal_state_lock,
+                    circuit_id,
+                    &Payload::Sendme(sendme),
+                );
+            }
+        }
+        if let Err(e) = written {
+            if internal_state_lock.remove_stream(stream_id).is_some() {
+                Logger::warn(
+                    &nickname,
+                    format!("Connection of stream {} failed: {}", stream_id, e),
+                );
+                send_backward(
+                    &nickname,
+                    my_id,
+                    &internal_state_lock,
+                    circuit_id,
+                    &Payload::End(EndPayload {
+                        stream_id,
+                        reason: EndReason::ConnReset,
+                    }),
+                );
+            }
+        }
+    }
+}
+
+/// Cuts `circuit_id` back to this relay: the dropped part is destroyed and
+/// TRUNCATED is sent back towards the user.
+fn truncate_circuit(
+    nickname: &str,
+    my_id: RelayId,
+    state: &mut RelayInternalState,
+    circuit_id: CircuitId,
+    reason: DestroyReason,
+) {
+    if let Some((next_circuit_id, relay_id)) = state.remove_next_hop(circuit_id) {
+        send_destroy(nickname, my_id, relay_id, next_circuit_id, reason);
+    }
+    let (Some(handshake), Some(relay_id)) = (
+        state.handshakes.get(&circuit_id),
+        state.circuits_ids.get(&circuit_id),
+    ) else {
+        Logger::error(nickname, format!("No circuit {} to truncate", circuit_id));
+        return;
+    };
+    let truncated_payload = Payload::Truncated(TruncatedPayload { reason });
+    let relay_cell = RelayCell {
+        circuit_id,
+        payload: encrypt_buffer_with_aes(
+            handshake,
+            &serde_json::to_vec(&truncated_payload).unwrap(),
+        )
+        .unwrap(),
+    };
+    match Communication::send(my_id, *relay_id, relay_cell) {
+        Ok(_) => Logger::info(
+            nickname,
+            format!(
+                "Sent TRUNCATED for circuit {} with reason {:?}",
+                circuit_id, reason
+            ),
+        ),
+        Err(e) => Logger::warn(
+            nickname,
+            format!("Failed to send TRUNCATED for circuit {}: {}", circuit_id, e),
+        ),
+    }
 }
 
 pub struct Relay {
     internal_state: Arc<Mutex<RelayInternalState>>,
+    /// Notified with `internal_state` when a SENDME opened a window.
+    window_updates: Arc<Condvar>,
+    /// Looks up the hostnames of streams exiting at this relay.
+    resolver: Arc<dyn Resolver>,
     relay_descriptor: RelayDescriptor,
 }
 
@@ -46,6 +610,17 @@ impl Relay {
                 id: Uuid::new_v4(),
                 nickname: nickname.clone(),
                 rsa_public: rsa.public_key_to_pem().unwrap(),
+                family: vec![],
+                address: Ipv4Addr::from(rand::random::<u32>()),
+                autonomous_system: rand::random(),
+                bandwidth: DEFAULT_BANDWIDTH,
+                flags: vec![
+                    RelayFlag::Guard,
+                    RelayFlag::Fast,
+                    RelayFlag::Stable,
+                    RelayFlag::HSDir,
+                ],
+                exit_policy: ExitPolicy::default(),
             },
             internal_state: Arc::new(Mutex::new(RelayInternalState {
                 keys: Keys {
@@ -60,11 +635,63 @@ impl Relay {
                 circuits_map: HashMap::new(),
                 rendezvous_points: HashMap::new(),
                 introduction_points: HashMap::new(),
+                introduction_keys: HashMap::new(),
+                introduction_replay_cache: ReplayCache::default(),
                 streams: HashMap::new(),
+                stream_circuits: HashMap::new(),
+                exit_connections: HashMap::new(),
+                circuit_windows: HashMap::new(),
+                stream_windows: HashMap::new(),
             })),
+            window_updates: Arc::new(Condvar::new()),
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+
+    /// Creates a relay that also exits to the destinations `exit_policy`
+    /// accepts.
+    pub fn new_exit(nickname: String, exit_policy: ExitPolicy) -> Self {
+        let mut relay = Self::new(nickname);
+        relay.relay_descriptor.flags.push(RelayFlag::Exit);
+        relay.relay_descriptor.exit_policy = exit_policy;
+        relay
+    }
+
+    /// Declares `relay_id` as part of this relay's family. Must be called
+    /// before `start`, since the descriptor is published at startup.
+    pub fn add_family_member(&mut self, relay_id: RelayId) {
+        if relay_id != self.relay_descriptor.id && !self.relay_descriptor.family.contains(&relay_id)
+        {
+            self.relay_descriptor.family.push(relay_id);
         }
     }
 
+    pub fn set_address(&mut self, address: Ipv4Addr) {
+        self.relay_descriptor.address = address;
+    }
+
+    pub fn set_autonomous_system(&mut self, autonomous_system: u32) {
+        self.relay_descriptor.autonomous_system = autonomous_system;
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: u64) {
+        self.relay_descriptor.bandwidth = bandwidth;
+    }
+
+    pub fn set_flags(&mut self, flags: Vec<RelayFlag>) {
+        self.relay_descriptor.flags = flags;
+    }
+
+    pub fn set_exit_policy(&mut self, exit_policy: ExitPolicy) {
+        self.relay_descriptor.exit_policy = exit_policy;
+    }
+
+    /// Replaces the system resolver used for hostnames of exit streams and
+    /// RESOLVE requests. Must be called before `start`.
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
     pub fn get_state(&self) -> RelayState {
         let internal_state_lock = self.internal_state.lock().unwrap();
         RelayState {
@@ -72,12 +699,70 @@ impl Relay {
             nickname: self.relay_descriptor.nickname.clone(),
             circuits: internal_state_lock.circuits_ids.clone(),
             streams: internal_state_lock.streams.clone(),
+            stream_circuits: internal_state_lock.stream_circuits.clone(),
+            exit_streams: internal_state_lock
+                .exit_connections
+                .iter()
+                .map(|(stream_id, connection)| (*stream_id, connection.destination.clone()))
+                .collect(),
             logs: Logger::get_logs(self.relay_descriptor.nickname.clone()),
             is_rendezvous_point: !internal_state_lock.rendezvous_points.is_empty(),
             is_introduction_point: !internal_state_lock.introduction_points.is_empty(),
+            replayed_introductions: internal_state_lock.introduction_replay_cache.replays(),
+        }
+    }
+
+    /// Tears down `circuit_id` and tells both neighbours to do the same.
+    pub fn destroy_circuit(&self, circuit_id: CircuitId, reason: DestroyReason) {
+        let nickname = &self.relay_descriptor.nickname;
+        Logger::info(
+            nickname,
+            format!("Destroying circuit {} with reason {:?}", circuit_id, reason),
+        );
+        let neighbours = self
+            .internal_state
+            .lock()
+            .unwrap()
+            .remove_circuit(circuit_id);
+        for (circuit_id, relay_id) in neighbours {
+            send_destroy(
+                nickname,
+                self.relay_descriptor.id,
+                relay_id,
+                circuit_id,
+                reason,
+            );
         }
     }
 
+    /// Closes `stream_id` and sends END with `reason` to the user.
+    pub fn end_stream(&self, stream_id: StreamId, reason: EndReason) {
+        let nickname = &self.relay_descriptor.nickname;
+        let mut internal_state_lock = self.internal_state.lock().unwrap();
+        let Some(circuit_id) = internal_state_lock.remove_stream(stream_id) else {
+            Logger::error(nickname, format!("No stream {} to end", stream_id));
+            return;
+        };
+        Logger::info(
+            nickname,
+            format!("Ending stream {} with reason {:?}", stream_id, reason),
+        );
+        send_backward(
+            nickname,
+            self.relay_descriptor.id,
+            &internal_state_lock,
+            circuit_id,
+            &Payload::End(EndPayload { stream_id, reason }),
+        );
+    }
+
+    /// Disconnects the relay from the network. Neighbours find out the next
+    /// time they try to send on one of its circuits.
+    pub fn stop(&self) {
+        Logger::info(&self.relay_descriptor.nickname, "Stopping the relay server");
+        Communication::unregister(self.relay_descriptor.id);
+    }
+
     pub fn start(&self) {
         Logger::info(&self.relay_descriptor.nickname, "Starting the relay server");
 
@@ -100,8 +785,12 @@ impl Relay {
 
         let nickname = self.relay_descriptor.nickname.clone();
         let my_id = self.relay_descriptor.id;
+        let is_exit = self.relay_descriptor.has_flag(RelayFlag::Exit);
+        let exit_policy = self.relay_descriptor.exit_policy.clone();
 
         let internal_state = self.internal_state.clone();
+        let window_updates = self.window_updates.clone();
+        let resolver = self.resolver.clone();
 
         std::thread::spawn(move || {
             loop {
@@ -110,71 +799,151 @@ impl Relay {
                         let mut internal_state_lock = internal_state.lock().unwrap();
                         Logger::info(&nickname, "Received relay cell");
 
-                        if let Some((next_circuit_id, direction)) =
-                            internal_state_lock.circuits_map.get(&relay_cell.circuit_id)
+                        // DESTROY cells are sent in the clear, like CREATE and CREATED
+                        if let Ok(Payload::Destroy(destroy_payload)) =
+                            serde_json::from_slice::<Payload>(&relay_cell.payload)
                         {
-                            if *direction {
-                                // decrypt with handshake then forward to next relay
-                                let handshake = internal_state_lock
-                                    .handshakes
-                                    .get(&relay_cell.circuit_id)
+                            // only the neighbours on the circuit may tear it down
+                            if internal_state_lock.circuits_ids.get(&relay_cell.circuit_id)
+                                != Some(&sender_id)
+                            {
+                                Logger::warn(
+                                    &nickname,
+                                    format!(
+                                        "Ignored DESTROY for circuit {} from {}",
+                                        relay_cell.circuit_id, sender_id
+                                    ),
+                                );
+                                continue;
+                            }
+                            Logger::info(
+                                &nickname,
+                                format!(
+                                    "Received DESTROY for circuit {} with reason {:?}",
+                                    relay_cell.circuit_id, destroy_payload.reason
+                                ),
+                            );
+                            for (circuit_id, relay_id) in
+                                internal_state_lock.remove_circuit(relay_cell.circuit_id)
+                            {
+                                if circuit_id != relay_cell.circuit_id {
+                                    send_destroy(
+                                        &nickname,
+                                        my_id,
+                                        relay_id,
+                                        circuit_id,
+                                        destroy_payload.reason,
+                                    );
+                                }
+                            }
+                            continue;
+                        }
+
+                        // payload of a cell addressed to this relay on a circuit that goes
+                        // on past it
+                        let mut recognized = None;
+                        if let Some((next_circuit_id, true)) = internal_state_lock
+                            .circuits_map
+                            .get(&relay_cell.circuit_id)
+                            .copied()
+                        {
+                            // decrypt with handshake then forward to next relay
+                            let handshake = internal_state_lock
+                                .handshakes
+                                .get(&relay_cell.circuit_id)
+                                .unwrap();
+                            let decrypted_payload =
+                                decrypt_buffer_with_aes(&handshake[0..32], &relay_cell.payload)
                                     .unwrap();
-                                let decrypted_payload =
-                                    decrypt_buffer_with_aes(&handshake[0..32], &relay_cell.payload)
-                                        .unwrap();
-                                if let Ok(payload) =
-                                    serde_json::from_slice::<Payload>(&decrypted_payload)
+                            // a joined circuit ends here too, so it has a handshake
+                            let joined = internal_state_lock
+                                .handshakes
+                                .contains_key(&next_circuit_id);
+                            match serde_json::from_slice::<Payload>(&decrypted_payload) {
+                                Ok(payload)
+                                    if joined
+                                        && internal_state_lock.is_session_payload(&payload) =>
                                 {
-                                    if payload.get_type() == PayloadType::Data {
-                                        let id = internal_state_lock
-                                            .circuits_ids
-                                            .get(next_circuit_id)
+                                    let id = *internal_state_lock
+                                        .circuits_ids
+                                        .get(&next_circuit_id)
+                                        .unwrap();
+                                    let handshake = internal_state_lock
+                                        .handshakes
+                                        .get(&next_circuit_id)
+                                        .expect("Handshake not found");
+                                    let encrypted_payload =
+                                        encrypt_buffer_with_aes(handshake, &decrypted_payload)
                                             .unwrap();
-                                        let handshake = internal_state_lock
-                                            .handshakes
-                                            .get(next_circuit_id)
-                                            .expect("Handshake not found");
-                                        let encrypted_payload =
-                                            encrypt_buffer_with_aes(handshake, &decrypted_payload)
-                                                .unwrap();
-                                        let relay_cell = RelayCell {
-                                            circuit_id: *next_circuit_id,
-                                            payload: encrypted_payload,
-                                        };
-                                        Logger::info(&nickname, "forwarding data payload");
-                                        Communication::send(my_id, *id, relay_cell).unwrap();
-                                        Logger::info(&nickname, "forwarded data payload");
-                                    } else {
-                                        Logger::error(
+                                    let relay_cell = RelayCell {
+                                        circuit_id: next_circuit_id,
+                                        payload: encrypted_payload,
+                                    };
+                                    if let Err(e) = Communication::send(my_id, id, relay_cell) {
+                                        close_unreachable_circuit(
                                             &nickname,
-                                            &format!(
-                                                "expected data payload, got {:?} payload",
-                                                payload.get_type()
-                                            ),
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            next_circuit_id,
+                                            id,
+                                            e,
                                         );
+                                        continue;
                                     }
-                                } else {
-                                    let relay_cell = RelayCell {
-                                        circuit_id: *next_circuit_id,
+                                    Logger::info(
+                                        &nickname,
+                                        format!(
+                                            "Forwarded {:?} payload to the joined circuit",
+                                            payload.get_type()
+                                        ),
+                                    );
+                                    continue;
+                                }
+                                Ok(payload) => {
+                                    Logger::info(
+                                        &nickname,
+                                        format!(
+                                            "Recognized {:?} payload addressed to this hop",
+                                            payload.get_type()
+                                        ),
+                                    );
+                                    recognized = Some(payload);
+                                }
+                                Err(_) => {
+                                    let next_relay_cell = RelayCell {
+                                        circuit_id: next_circuit_id,
                                         payload: decrypted_payload,
                                     };
-                                    let id = internal_state_lock
+                                    let id = *internal_state_lock
                                         .circuits_ids
-                                        .get(next_circuit_id)
+                                        .get(&next_circuit_id)
                                         .unwrap();
                                     Logger::info(&nickname, "forwarding relay cell to next relay");
-                                    Communication::send(my_id, *id, relay_cell).unwrap();
-                                    Logger::info(
-                                        &nickname,
-                                        &"Forwarded relay cell to next relay".to_string(),
-                                    );
+                                    if let Err(e) = Communication::send(my_id, id, next_relay_cell)
+                                    {
+                                        Logger::warn(
+                                            &nickname,
+                                            format!("Next relay {} is unreachable: {}", id, e),
+                                        );
+                                        truncate_circuit(
+                                            &nickname,
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            relay_cell.circuit_id,
+                                            DestroyReason::ChannelClosed,
+                                        );
+                                        continue;
+                                    }
+                                    Logger::info(&nickname, "Forwarded relay cell to next relay");
+                                    continue;
                                 }
-                                continue;
                             }
-                        };
+                        }
 
                         // get the payload
-                        let payload = if let Some(handshake) =
+                        let payload = if let Some(payload) = recognized {
+                            payload
+                        } else if let Some(handshake) =
                             internal_state_lock.handshakes.get(&relay_cell.circuit_id)
                         {
                             let decrypted_payload =
@@ -182,7 +951,7 @@ impl Relay {
                                     .unwrap();
                             Logger::info(
                                 &nickname,
-                                &format!(
+                                format!(
                                     "Decrypted payload with handshake for circuit {}",
                                     relay_cell.circuit_id
                                 ),
@@ -191,10 +960,7 @@ impl Relay {
                         } else {
                             Logger::info(
                                 &nickname,
-                                &format!(
-                                    "No handshake found for circuit {}",
-                                    relay_cell.circuit_id
-                                ),
+                                format!("No handshake found for circuit {}", relay_cell.circuit_id),
                             );
                             if let Ok(payload) =
                                 serde_json::from_slice::<Payload>(&relay_cell.payload)
@@ -204,35 +970,43 @@ impl Relay {
                                 internal_state_lock.circuits_map.get(&relay_cell.circuit_id)
                             {
                                 if !*direction {
-                                    let id = internal_state_lock
+                                    let next_circuit_id = *next_circuit_id;
+                                    let id = *internal_state_lock
                                         .circuits_ids
-                                        .get(next_circuit_id)
+                                        .get(&next_circuit_id)
                                         .unwrap();
                                     Logger::info(
                                         &nickname,
-                                        &format!(
+                                        format!(
                                             "Forwarding payload back to circuit {}",
                                             next_circuit_id
                                         ),
                                     );
                                     let handshake = internal_state_lock
                                         .handshakes
-                                        .get(next_circuit_id)
+                                        .get(&next_circuit_id)
                                         .unwrap();
                                     let encrypted_payload =
                                         encrypt_buffer_with_aes(handshake, &relay_cell.payload)
                                             .unwrap();
                                     let relay_cell = RelayCell {
-                                        circuit_id: *next_circuit_id,
+                                        circuit_id: next_circuit_id,
                                         payload: encrypted_payload,
                                     };
-                                    Communication::send(my_id, *id, relay_cell).unwrap();
-                                    Logger::info(
-                                        &nickname,
-                                        &"Forwarded payload to previous relay".to_string(),
-                                    );
+                                    if let Err(e) = Communication::send(my_id, id, relay_cell) {
+                                        close_unreachable_circuit(
+                                            &nickname,
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            next_circuit_id,
+                                            id,
+                                            e,
+                                        );
+                                        continue;
+                                    }
+                                    Logger::info(&nickname, "Forwarded payload to previous relay");
                                 } else {
-                                    Logger::error(&nickname, &format!("direction is wrong, expected false, got true for circuit {} coming from {}",
+                                    Logger::error(&nickname, format!("direction is wrong, expected false, got true for circuit {} coming from {}",
                                         relay_cell.circuit_id,
                                         sender_id
                                     ));
@@ -241,7 +1015,7 @@ impl Relay {
                             } else {
                                 Logger::error(
                                     &nickname,
-                                    &format!(
+                                    format!(
                                         "no circuit found for circuit {} coming from {}",
                                         relay_cell.circuit_id, sender_id
                                     ),
@@ -252,7 +1026,7 @@ impl Relay {
 
                         Logger::info(
                             &nickname,
-                            &format!("Received payload: {:?}", payload.get_type()),
+                            format!("Received payload: {:?}", payload.get_type()),
                         );
 
                         match payload {
@@ -296,17 +1070,29 @@ impl Relay {
                                     payload: serde_json::to_vec(&created_payload).unwrap(),
                                 };
 
-                                Communication::send(my_id, sender_id, relay_cell).unwrap();
-                                Logger::info(&nickname, &"Sent created payload".to_string());
+                                let circuit_id = relay_cell.circuit_id;
+                                if let Err(e) = Communication::send(my_id, sender_id, relay_cell) {
+                                    close_unreachable_circuit(
+                                        &nickname,
+                                        my_id,
+                                        &mut internal_state_lock,
+                                        circuit_id,
+                                        sender_id,
+                                        e,
+                                    );
+                                    continue;
+                                }
+                                Logger::info(&nickname, "Sent created payload");
                             }
                             Payload::Created(created_payload) => {
                                 if let Some((next_circuit_id, direction)) =
                                     internal_state_lock.circuits_map.get(&relay_cell.circuit_id)
                                 {
                                     if !*direction {
-                                        let id = internal_state_lock
+                                        let next_circuit_id = *next_circuit_id;
+                                        let id = *internal_state_lock
                                             .circuits_ids
-                                            .get(next_circuit_id)
+                                            .get(&next_circuit_id)
                                             .unwrap();
                                         Logger::info(
                                             &nickname,
@@ -322,7 +1108,7 @@ impl Relay {
                                             });
                                         let handshake = internal_state_lock
                                             .handshakes
-                                            .get(next_circuit_id)
+                                            .get(&next_circuit_id)
                                             .unwrap();
                                         let encrypted_payload = encrypt_buffer_with_aes(
                                             handshake,
@@ -330,13 +1116,23 @@ impl Relay {
                                         )
                                         .unwrap();
                                         let relay_cell = RelayCell {
-                                            circuit_id: *next_circuit_id,
+                                            circuit_id: next_circuit_id,
                                             payload: encrypted_payload,
                                         };
-                                        Communication::send(my_id, *id, relay_cell).unwrap();
+                                        if let Err(e) = Communication::send(my_id, id, relay_cell) {
+                                            close_unreachable_circuit(
+                                                &nickname,
+                                                my_id,
+                                                &mut internal_state_lock,
+                                                next_circuit_id,
+                                                id,
+                                                e,
+                                            );
+                                            continue;
+                                        }
                                         Logger::info(
                                             &nickname,
-                                            "Forwarded payload to previous relay".to_string(),
+                                            "Forwarded payload to previous relay",
                                         );
                                     } else {
                                         Logger::error(&nickname, format!("direction is wrong, expected false, got true for circuit {} coming from {}",
@@ -351,8 +1147,7 @@ impl Relay {
                                 // Check if the circuit is already extended
                                 if internal_state_lock
                                     .circuits_map
-                                    .get(&relay_cell.circuit_id)
-                                    .is_some()
+                                    .contains_key(&relay_cell.circuit_id)
                                 {
                                     Logger::error(
                                         &nickname,
@@ -364,6 +1159,7 @@ impl Relay {
                                     &nickname,
                                     format!("Extending circuit with ID: {}", relay_cell.circuit_id),
                                 );
+                                let circuit_id = relay_cell.circuit_id;
                                 let new_circuit_id = Uuid::new_v4();
                                 internal_state_lock
                                     .circuits_map
@@ -382,7 +1178,32 @@ impl Relay {
                                     payload: serde_json::to_vec(&create_payload)
                                         .expect("Failed to serialize JSON"),
                                 };
-                                Communication::send(my_id, id, relay_cell).unwrap();
+                                if let Err(e) = Communication::send(my_id, id, relay_cell) {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!("Failed to extend to relay {}: {}", id, e),
+                                    );
+                                    truncate_circuit(
+                                        &nickname,
+                                        my_id,
+                                        &mut internal_state_lock,
+                                        circuit_id,
+                                        DestroyReason::ConnectFailed,
+                                    );
+                                }
+                            }
+                            Payload::Truncate(_) => {
+                                Logger::info(
+                                    &nickname,
+                                    format!("Truncating circuit {}", relay_cell.circuit_id),
+                                );
+                                truncate_circuit(
+                                    &nickname,
+                                    my_id,
+                                    &mut internal_state_lock,
+                                    relay_cell.circuit_id,
+                                    DestroyReason::Requested,
+                                );
                             }
                             Payload::EstablishRendezvous(establish_rendezvous) => {
                                 let rendezvous_cookie = establish_rendezvous.rendezvous_cookie;
@@ -406,10 +1227,21 @@ impl Relay {
                                     circuit_id: relay_cell.circuit_id,
                                     payload: encrypted_payload,
                                 };
-                                Communication::send(my_id, sender_id, relay_cell).unwrap();
+                                let circuit_id = relay_cell.circuit_id;
+                                if let Err(e) = Communication::send(my_id, sender_id, relay_cell) {
+                                    close_unreachable_circuit(
+                                        &nickname,
+                                        my_id,
+                                        &mut internal_state_lock,
+                                        circuit_id,
+                                        sender_id,
+                                        e,
+                                    );
+                                    continue;
+                                }
                                 Logger::info(
                                     &nickname,
-                                    &format!(
+                                    format!(
                                         "Established rendezvous, cookie: {}",
                                         rendezvous_cookie
                                     ),
@@ -420,6 +1252,10 @@ impl Relay {
                                 internal_state_lock
                                     .introduction_points
                                     .insert(introduction_id, relay_cell.circuit_id);
+                                internal_state_lock.introduction_keys.insert(
+                                    introduction_id,
+                                    establish_introduction.introduction_key,
+                                );
                                 let established_introduction_payload =
                                     Payload::EstablishedIntroduction(
                                         payloads::EstablishedIntroductionPayload {},
@@ -438,53 +1274,181 @@ impl Relay {
                                     circuit_id: relay_cell.circuit_id,
                                     payload: encrypted_payload,
                                 };
-                                Communication::send(my_id, sender_id, relay_cell).unwrap();
+                                let circuit_id = relay_cell.circuit_id;
+                                if let Err(e) = Communication::send(my_id, sender_id, relay_cell) {
+                                    close_unreachable_circuit(
+                                        &nickname,
+                                        my_id,
+                                        &mut internal_state_lock,
+                                        circuit_id,
+                                        sender_id,
+                                        e,
+                                    );
+                                    continue;
+                                }
                                 Logger::info(
                                     &nickname,
-                                    &format!("Established introduction, id: {}", introduction_id),
+                                    format!("Established introduction, id: {}", introduction_id),
                                 );
                             }
                             Payload::Begin(begin_payload) => {
-                                let connected_payload = Payload::Connected(ConnectedPayload {});
-                                let handshake = internal_state_lock
-                                    .handshakes
-                                    .get(&relay_cell.circuit_id)
-                                    .unwrap();
-                                let encrypted_payload = encrypt_buffer_with_aes(
-                                    handshake,
-                                    &serde_json::to_vec(&connected_payload).unwrap(),
-                                )
-                                .unwrap();
-                                let begin_relay_cell = RelayCell {
-                                    circuit_id: relay_cell.circuit_id,
-                                    payload: encrypted_payload,
+                                let stream_id = begin_payload.stream_id;
+                                let reply = if internal_state_lock
+                                    .stream_circuits
+                                    .contains_key(&stream_id)
+                                {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!("Stream {} is already open", stream_id),
+                                    );
+                                    Some(Payload::End(EndPayload {
+                                        stream_id,
+                                        reason: EndReason::Protocol,
+                                    }))
+                                } else {
+                                    match begin_payload.target {
+                                        BeginTarget::Relay(relay_descriptor) => {
+                                            internal_state_lock
+                                                .streams
+                                                .insert(stream_id, relay_descriptor.id);
+                                            internal_state_lock
+                                                .stream_circuits
+                                                .insert(stream_id, relay_cell.circuit_id);
+                                            Logger::info(
+                                                &nickname,
+                                                format!(
+                                                    "Opened stream {} to relay {}",
+                                                    stream_id, relay_descriptor.id
+                                                ),
+                                            );
+                                            Some(Payload::Connected(ConnectedPayload { stream_id }))
+                                        }
+                                        BeginTarget::Host { host, port }
+                                            if !is_exit
+                                                || !exit_policy.allows_destination(&host, port) =>
+                                        {
+                                            Logger::warn(
+                                                &nickname,
+                                                format!(
+                                                    "Refused stream {} to {}:{} by exit policy",
+                                                    stream_id, host, port
+                                                ),
+                                            );
+                                            Some(Payload::End(EndPayload {
+                                                stream_id,
+                                                reason: EndReason::ExitPolicy,
+                                            }))
+                                        }
+                                        BeginTarget::Host { host, port } => {
+                                            internal_state_lock
+                                                .stream_circuits
+                                                .insert(stream_id, relay_cell.circuit_id);
+                                            let nickname = nickname.clone();
+                                            let internal_state = internal_state.clone();
+                                            let window_updates = window_updates.clone();
+                                            let circuit_id = relay_cell.circuit_id;
+                                            let exit_policy = exit_policy.clone();
+                                            let resolver = resolver.clone();
+                                            std::thread::spawn(move || {
+                                                open_exit_stream(
+                                                    nickname,
+                                                    my_id,
+                                                    internal_state,
+                                                    window_updates,
+                                                    circuit_id,
+                                                    stream_id,
+                                                    (host, port),
+                                                    &exit_policy,
+                                                    resolver.as_ref(),
+                                                )
+                                            });
+                                            // answered once connected
+                                            None
+                                        }
+                                    }
                                 };
-                                internal_state_lock.streams.insert(
-                                    begin_payload.stream_id,
-                                    begin_payload.relay_descriptor.id,
-                                );
-                                Communication::send(my_id, sender_id, begin_relay_cell).unwrap();
+                                if let Some(reply) = reply {
+                                    send_backward(
+                                        &nickname,
+                                        my_id,
+                                        &internal_state_lock,
+                                        relay_cell.circuit_id,
+                                        &reply,
+                                    );
+                                }
+                            }
+                            Payload::End(end_payload) => {
+                                let stream_id = end_payload.stream_id;
+                                if internal_state_lock.stream_circuits.get(&stream_id)
+                                    == Some(&relay_cell.circuit_id)
+                                {
+                                    internal_state_lock.remove_stream(stream_id);
+                                    Logger::info(
+                                        &nickname,
+                                        format!(
+                                            "Stream {} was closed with reason {:?}",
+                                            stream_id, end_payload.reason
+                                        ),
+                                    );
+                                } else if !send_to_joined_circuit(
+                                    &nickname,
+                                    my_id,
+                                    &internal_state_lock,
+                                    relay_cell.circuit_id,
+                                    &Payload::End(end_payload),
+                                ) {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!("END for unknown stream {}", stream_id),
+                                    );
+                                }
                             }
                             Payload::Introduce1(introduce1_payload) => {
                                 // verify that introduction id matches and that the stream exists
                                 let stream_id = introduce1_payload.stream_id;
                                 let introduction_id = introduce1_payload.introduction_id;
 
-                                if let Some(id) = internal_state_lock.streams.get(&stream_id) {
+                                if let Some(id) =
+                                    internal_state_lock.streams.get(&stream_id).copied()
+                                {
                                     Logger::info(&nickname, "Stream found");
                                     let introduce1_payload =
                                         Payload::Introduce1(payloads::Introduce1Payload {
                                             stream_id,
                                             introduction_id,
                                             rendezvous_cookie: introduce1_payload.rendezvous_cookie,
+                                            rendezvous_point: introduce1_payload.rendezvous_point,
                                             onion_skin: introduce1_payload.onion_skin,
+                                            mac: introduce1_payload.mac,
                                         });
 
                                     let relay_cell = RelayCell {
                                         circuit_id: relay_cell.circuit_id,
                                         payload: serde_json::to_vec(&introduce1_payload).unwrap(),
                                     };
-                                    Communication::send(my_id, *id, relay_cell.clone()).unwrap();
+                                    if let Err(e) =
+                                        Communication::send(my_id, id, relay_cell.clone())
+                                    {
+                                        Logger::warn(
+                                            &nickname,
+                                            format!(
+                                                "Closing stream {}, relay {} is unreachable: {}",
+                                                stream_id, id, e
+                                            ),
+                                        );
+                                        internal_state_lock.remove_stream(stream_id);
+                                        send_backward(
+                                            &nickname,
+                                            my_id,
+                                            &internal_state_lock,
+                                            relay_cell.circuit_id,
+                                            &Payload::End(EndPayload {
+                                                stream_id,
+                                                reason: EndReason::ConnReset,
+                                            }),
+                                        );
+                                        continue;
+                                    }
                                     Logger::info(
                                         &nickname,
                                         format!("Sent introduce1 payload to stream {}", stream_id),
@@ -507,18 +1471,44 @@ impl Relay {
                                         circuit_id: relay_cell.circuit_id,
                                         payload: encrypted_payload,
                                     };
-                                    Communication::send(my_id, sender_id, relay_cell).unwrap();
+                                    let circuit_id = relay_cell.circuit_id;
+                                    if let Err(e) =
+                                        Communication::send(my_id, sender_id, relay_cell)
+                                    {
+                                        close_unreachable_circuit(
+                                            &nickname,
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            circuit_id,
+                                            sender_id,
+                                            e,
+                                        );
+                                        continue;
+                                    }
                                     Logger::info(&nickname, "Sent introduce ack payload");
                                 } else {
                                     Logger::warn(&nickname, "Stream not found");
                                     if let Some(introduction_circuit_id) = internal_state_lock
                                         .introduction_points
                                         .get(&introduction_id)
+                                        .copied()
                                     {
                                         Logger::info(&nickname, "Introduction point found");
-                                        let introduction_relay_id = internal_state_lock
+                                        if let Err(reason) = internal_state_lock
+                                            .check_introduce1(introduction_id, &introduce1_payload)
+                                        {
+                                            Logger::warn(
+                                                &nickname,
+                                                format!(
+                                                    "Dropped INTRODUCE1 for introduction point {}: {}",
+                                                    introduction_id, reason
+                                                ),
+                                            );
+                                            continue;
+                                        }
+                                        let introduction_relay_id = *internal_state_lock
                                             .circuits_ids
-                                            .get(introduction_circuit_id)
+                                            .get(&introduction_circuit_id)
                                             .expect("Introduction point not found");
                                         Logger::info(
                                             &nickname,
@@ -529,13 +1519,17 @@ impl Relay {
                                         );
                                         let introduce2_payload =
                                             Payload::Introduce2(payloads::Introduce2Payload {
+                                                introduction_id,
                                                 rendezvous_cookie: introduce1_payload
                                                     .rendezvous_cookie,
+                                                rendezvous_point: introduce1_payload
+                                                    .rendezvous_point,
                                                 onion_skin: introduce1_payload.onion_skin,
+                                                mac: introduce1_payload.mac,
                                             });
                                         let handshake = internal_state_lock
                                             .handshakes
-                                            .get(introduction_circuit_id)
+                                            .get(&introduction_circuit_id)
                                             .expect("Handshake not found");
                                         let introduce2_payload = encrypt_buffer_with_aes(
                                             handshake,
@@ -543,15 +1537,23 @@ impl Relay {
                                         )
                                         .unwrap();
                                         let relay_cell = RelayCell {
-                                            circuit_id: *introduction_circuit_id,
+                                            circuit_id: introduction_circuit_id,
                                             payload: introduce2_payload,
                                         };
-                                        Communication::send(
+                                        if let Err(e) = Communication::send(
                                             my_id,
-                                            *introduction_relay_id,
+                                            introduction_relay_id,
                                             relay_cell,
-                                        )
-                                        .unwrap();
+                                        ) {
+                                            close_unreachable_circuit(
+                                                &nickname,
+                                                my_id,
+                                                &mut internal_state_lock,
+                                                introduction_circuit_id,
+                                                introduction_relay_id,
+                                                e,
+                                            );
+                                        }
                                     } else {
                                         Logger::error(&nickname, "Introduction point not found");
                                         continue;
@@ -591,47 +1593,236 @@ impl Relay {
                                         circuit_id: original_circuit_id,
                                         payload: encrypted_payload,
                                     };
-                                    let id = internal_state_lock
+                                    let id = *internal_state_lock
                                         .circuits_ids
                                         .get(&original_circuit_id)
                                         .expect("Original circuit not found");
-                                    Communication::send(my_id, *id, relay_cell).unwrap();
+                                    if let Err(e) = Communication::send(my_id, id, relay_cell) {
+                                        close_unreachable_circuit(
+                                            &nickname,
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            original_circuit_id,
+                                            id,
+                                            e,
+                                        );
+                                    }
                                 } else {
                                     Logger::error(&nickname, "Rendezvous point not found");
                                     continue;
                                 }
                             }
+                            Payload::Data(DataPayload {
+                                stream_id: Some(stream_id),
+                                data,
+                                ..
+                            }) => {
+                                if internal_state_lock.stream_circuits.get(&stream_id)
+                                    != Some(&relay_cell.circuit_id)
+                                {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!("Dropped data for unknown stream {}", stream_id),
+                                    );
+                                    continue;
+                                }
+                                let state = &mut *internal_state_lock;
+                                let delivered = deliver_data_cell(
+                                    state
+                                        .circuit_windows
+                                        .entry(relay_cell.circuit_id)
+                                        .or_insert_with(FlowWindow::circuit),
+                                    state
+                                        .stream_windows
+                                        .entry(stream_id)
+                                        .or_insert_with(FlowWindow::stream),
+                                    stream_id,
+                                );
+                                let sendmes = match delivered {
+                                    Ok(sendmes) => sendmes,
+                                    Err(e) => {
+                                        close_misbehaving_circuit(
+                                            &nickname,
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            relay_cell.circuit_id,
+                                            e,
+                                        );
+                                        continue;
+                                    }
+                                };
+                                Logger::info(
+                                    &nickname,
+                                    format!(
+                                        "Received {} bytes on stream {}",
+                                        data.len(),
+                                        stream_id
+                                    ),
+                                );
+                                // the writer of the stream acknowledges the data
+                                // once it is written out
+                                let write = ExitWrite { data, sendmes };
+                                let queued =
+                                    match internal_state_lock.exit_connections.get(&stream_id) {
+                                        Some(connection) => connection.writer.try_send(write),
+                                        None => Err(TrySendError::Disconnected(write)),
+                                    };
+                                match queued {
+                                    Ok(()) => {}
+                                    Err(TrySendError::Full(_)) => {
+                                        close_misbehaving_circuit(
+                                            &nickname,
+                                            my_id,
+                                            &mut internal_state_lock,
+                                            relay_cell.circuit_id,
+                                            FlowControlError::WindowExceeded,
+                                        );
+                                    }
+                                    // not connected yet, nothing to write to
+                                    Err(TrySendError::Disconnected(write)) => {
+                                        for sendme in write.sendmes {
+                                            send_backward(
+                                                &nickname,
+                                                my_id,
+                                                &internal_state_lock,
+                                                relay_cell.circuit_id,
+                                                &Payload::Sendme(sendme),
+                                            );
+                                        }
+                                    }
+                                }
+                            }
                             Payload::Data(_) => {
-                                let circuit_id = internal_state_lock
-                                    .circuits_map
-                                    .get(&relay_cell.circuit_id)
-                                    .unwrap()
-                                    .0;
+                                if !send_to_joined_circuit(
+                                    &nickname,
+                                    my_id,
+                                    &internal_state_lock,
+                                    relay_cell.circuit_id,
+                                    &payload,
+                                ) {
+                                    Logger::error(&nickname, "No joined circuit for data payload");
+                                }
+                            }
+                            Payload::Resolve(resolve_payload) if !is_exit => {
+                                Logger::warn(
+                                    &nickname,
+                                    format!(
+                                        "Refused to resolve {}, not an exit relay",
+                                        resolve_payload.hostname
+                                    ),
+                                );
+                                send_backward(
+                                    &nickname,
+                                    my_id,
+                                    &internal_state_lock,
+                                    relay_cell.circuit_id,
+                                    &Payload::Resolved(ResolvedPayload {
+                                        stream_id: resolve_payload.stream_id,
+                                        addresses: vec![],
+                                    }),
+                                );
+                            }
+                            Payload::Resolve(resolve_payload) => {
+                                let nickname = nickname.clone();
+                                let internal_state = internal_state.clone();
+                                let resolver = resolver.clone();
+                                let circuit_id = relay_cell.circuit_id;
+                                // lookups can be slow, so they do not hold up other cells
+                                std::thread::spawn(move || {
+                                    let hostname = resolve_payload.hostname;
+                                    let addresses = resolve_host(&hostname, resolver.as_ref())
+                                        .unwrap_or_default();
+                                    Logger::info(
+                                        &nickname,
+                                        format!("Resolved {} to {:?}", hostname, addresses),
+                                    );
+                                    send_backward(
+                                        &nickname,
+                                        my_id,
+                                        &internal_state.lock().unwrap(),
+                                        circuit_id,
+                                        &Payload::Resolved(ResolvedPayload {
+                                            stream_id: resolve_payload.stream_id,
+                                            addresses,
+                                        }),
+                                    );
+                                });
+                            }
+                            Payload::Sendme(SendmePayload { stream_id }) => {
+                                let circuit_id = relay_cell.circuit_id;
+                                let state = &mut *internal_state_lock;
+                                let window = match stream_id {
+                                    Some(stream_id)
+                                        if state.stream_circuits.get(&stream_id)
+                                            == Some(&circuit_id) =>
+                                    {
+                                        state
+                                            .stream_windows
+                                            .entry(stream_id)
+                                            .or_insert_with(FlowWindow::stream)
+                                    }
+                                    Some(stream_id) => {
+                                        Logger::warn(
+                                            &nickname,
+                                            format!("SENDME for unknown stream {}", stream_id),
+                                        );
+                                        continue;
+                                    }
+                                    None => state
+                                        .circuit_windows
+                                        .entry(circuit_id)
+                                        .or_insert_with(FlowWindow::circuit),
+                                };
+                                if let Err(e) = window.record_sendme() {
+                                    close_misbehaving_circuit(
+                                        &nickname,
+                                        my_id,
+                                        &mut internal_state_lock,
+                                        circuit_id,
+                                        e,
+                                    );
+                                    continue;
+                                }
+                                window_updates.notify_all();
+                            }
+                            Payload::Ping(ping_payload) => {
+                                let pong_payload = Payload::Pong(PongPayload {
+                                    nonce: ping_payload.nonce,
+                                });
                                 let handshake = internal_state_lock
                                     .handshakes
-                                    .get(&circuit_id)
+                                    .get(&relay_cell.circuit_id)
                                     .expect("Handshake not found");
                                 let encrypted_payload = encrypt_buffer_with_aes(
                                     handshake,
-                                    &serde_json::to_vec(&payload).unwrap(),
+                                    &serde_json::to_vec(&pong_payload).unwrap(),
                                 )
                                 .unwrap();
                                 let relay_cell = RelayCell {
-                                    circuit_id,
+                                    circuit_id: relay_cell.circuit_id,
                                     payload: encrypted_payload,
                                 };
-                                let id = internal_state_lock.circuits_ids.get(&circuit_id).unwrap();
-                                Communication::send(my_id, *id, relay_cell).unwrap();
-                                Logger::info(&nickname, "Forwarded data payload");
+                                if let Err(e) = Communication::send(my_id, sender_id, relay_cell) {
+                                    Logger::warn(&nickname, format!("Failed to send PONG: {}", e));
+                                }
+                            }
+                            Payload::Drop(drop_payload) => {
+                                Logger::info(
+                                    &nickname,
+                                    format!(
+                                        "Dropped a padding cell of {} bytes",
+                                        drop_payload.padding.len()
+                                    ),
+                                );
                             }
                             _ => {
-                                Logger::error(&nickname, &"Unhandled payload type".to_string());
+                                Logger::error(&nickname, "Unhandled payload type");
                             }
                         }
                     }
                     Err(e) => {
                         Logger::error(&nickname, format!("Failed to read from socket: {}", e));
-                        continue;
+                        break;
                     }
                 }
             }
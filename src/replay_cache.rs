@@ -0,0 +1,78 @@
+use std::collections::{HashSet, VecDeque};
+
+/// Tags remembered by a replay cache, as many introductions as Tor allows an
+/// introduction point before rotating it.
+pub const MAX_REPLAY_CACHE_ENTRIES: usize = 16 * 1024;
+
+/// Tags of the introductions seen before, such as their MAC, so that replayed
+/// ones are dropped. Beyond its capacity the oldest tags are forgotten.
+pub struct ReplayCache {
+    seen: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+    capacity: usize,
+    replays: u64,
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(MAX_REPLAY_CACHE_ENTRIES)
+    }
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+            replays: 0,
+        }
+    }
+
+    /// Records `tag`. Returns false, counting a replay, if it was seen before.
+    pub fn check(&mut self, tag: &[u8]) -> bool {
+        if self.seen.contains(tag) {
+            self.replays += 1;
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(tag.to_vec());
+        self.order.push_back(tag.to_vec());
+        true
+    }
+
+    /// Replays dropped so far.
+    pub fn replays(&self) -> u64 {
+        self.replays
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replays_are_counted() {
+        let mut cache = ReplayCache::default();
+        assert!(cache.check(b"first"));
+        assert!(cache.check(b"second"));
+        assert!(!cache.check(b"first"));
+        assert!(!cache.check(b"first"));
+        assert_eq!(cache.replays(), 2);
+    }
+
+    #[test]
+    fn test_oldest_tags_are_forgotten() {
+        let mut cache = ReplayCache::new(2);
+        assert!(cache.check(b"first"));
+        assert!(cache.check(b"second"));
+        assert!(cache.check(b"third"));
+        assert!(!cache.check(b"third"));
+        assert!(cache.check(b"first"));
+        assert_eq!(cache.replays(), 1);
+    }
+}
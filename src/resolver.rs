@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+
+/// How an exit relay looks up the addresses of hostnames.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>>;
+}
+
+/// Resolves names with the operating system of the relay.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>> {
+        Ok((hostname, 0)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve {}", hostname))?
+            .map(|address| address.ip())
+            .collect())
+    }
+}
+
+/// Resolves names from a fixed table, for tests and closed networks.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    entries: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn insert(&mut self, hostname: &str, addresses: Vec<IpAddr>) {
+        self.entries.insert(hostname.to_lowercase(), addresses);
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>> {
+        self.entries
+            .get(&hostname.to_lowercase())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown host {}", hostname))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_static_resolver() {
+        let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut resolver = StaticResolver::default();
+        resolver.insert("Service.test", vec![address]);
+        assert_eq!(resolver.resolve("service.TEST").unwrap(), vec![address]);
+        assert!(resolver.resolve("other.test").is_err());
+    }
+}
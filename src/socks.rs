@@ -0,0 +1,322 @@
+use crate::{credentials_token, proxy_stream, EndReason, IsolationKey, StreamClosedError, User};
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+
+pub const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+/// Version of the username/password subnegotiation of RFC 1929.
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const CONNECT: u8 = 0x01;
+/// Tor's extension resolving a name through the network, answered with the
+/// address in the reply.
+const RESOLVE: u8 = 0xf0;
+const IPV4_ADDRESS: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6_ADDRESS: u8 = 0x04;
+
+/// Reply codes of RFC 1928.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocksReply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl From<EndReason> for SocksReply {
+    fn from(reason: EndReason) -> Self {
+        match reason {
+            EndReason::ExitPolicy => SocksReply::NotAllowed,
+            EndReason::NoRoute => SocksReply::NetworkUnreachable,
+            EndReason::ResolveFailed => SocksReply::HostUnreachable,
+            EndReason::ConnectRefused => SocksReply::ConnectionRefused,
+            EndReason::Timeout => SocksReply::TtlExpired,
+            _ => SocksReply::GeneralFailure,
+        }
+    }
+}
+
+/// Sends `reply` with `address` as the bound address, which is only
+/// meaningful for RESOLVE.
+fn send_reply(client: &mut TcpStream, reply: SocksReply, address: Option<IpAddr>) -> Result<()> {
+    let mut response = vec![SOCKS_VERSION, reply as u8, 0];
+    match address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)) {
+        IpAddr::V4(address) => {
+            response.push(IPV4_ADDRESS);
+            response.extend_from_slice(&address.octets());
+        }
+        IpAddr::V6(address) => {
+            response.push(IPV6_ADDRESS);
+            response.extend_from_slice(&address.octets());
+        }
+    }
+    response.extend_from_slice(&[0, 0]);
+    client
+        .write_all(&response)
+        .context("Failed to send SOCKS reply")
+}
+
+/// Reply to a failed request, following the END reason that closed its
+/// stream if there was one.
+fn error_reply(error: &anyhow::Error) -> SocksReply {
+    error
+        .downcast_ref::<StreamClosedError>()
+        .map_or(SocksReply::GeneralFailure, |error| error.reason.into())
+}
+
+fn read_bytes(client: &mut TcpStream, length: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; length];
+    client
+        .read_exact(&mut bytes)
+        .context("Failed to read SOCKS request")?;
+    Ok(bytes)
+}
+
+/// Reads the username and password of RFC 1929, accepting any, and returns
+/// their isolation token.
+fn read_credentials(client: &mut TcpStream) -> Result<String> {
+    let version = read_bytes(client, 1)?[0];
+    if version != USERNAME_PASSWORD_VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported authentication version {}",
+            version
+        ));
+    }
+    let length = read_bytes(client, 1)?[0] as usize;
+    let username = read_bytes(client, length)?;
+    let length = read_bytes(client, 1)?[0] as usize;
+    let password = read_bytes(client, length)?;
+    client
+        .write_all(&[USERNAME_PASSWORD_VERSION, 0])
+        .context("Failed to send SOCKS authentication reply")?;
+    credentials_token(&[&username, &password])
+}
+
+/// Serves one SOCKS5 client of `user`: its CONNECT request becomes a
+/// stream, isolated by the username and password it authenticated with, and
+/// its RESOLVE request is answered by an exit.
+pub fn serve_socks_client(user: User, mut client: TcpStream) -> Result<()> {
+    let greeting = read_bytes(&mut client, 2)?;
+    if greeting[0] != SOCKS_VERSION {
+        return Err(anyhow::anyhow!("Unsupported SOCKS version {}", greeting[0]));
+    }
+    let methods = read_bytes(&mut client, greeting[1] as usize)?;
+    let credentials = if methods.contains(&USERNAME_PASSWORD) {
+        client.write_all(&[SOCKS_VERSION, USERNAME_PASSWORD])?;
+        Some(read_credentials(&mut client)?)
+    } else if methods.contains(&NO_AUTHENTICATION) {
+        client.write_all(&[SOCKS_VERSION, NO_AUTHENTICATION])?;
+        None
+    } else {
+        client.write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])?;
+        return Err(anyhow::anyhow!("No acceptable authentication method"));
+    };
+
+    let request = read_bytes(&mut client, 4)?;
+    let host = match request[3] {
+        IPV4_ADDRESS => {
+            let octets: [u8; 4] = read_bytes(&mut client, 4)?.try_into().unwrap();
+            Ipv4Addr::from(octets).to_string()
+        }
+        DOMAIN_NAME => {
+            let length = read_bytes(&mut client, 1)?[0] as usize;
+            String::from_utf8_lossy(&read_bytes(&mut client, length)?).to_string()
+        }
+        IPV6_ADDRESS => {
+            let octets: [u8; 16] = read_bytes(&mut client, 16)?.try_into().unwrap();
+            Ipv6Addr::from(octets).to_string()
+        }
+        address_type => {
+            send_reply(&mut client, SocksReply::AddressTypeNotSupported, None)?;
+            return Err(anyhow::anyhow!("Unsupported address type {}", address_type));
+        }
+    };
+    let port = read_bytes(&mut client, 2)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let isolation = IsolationKey {
+        credentials,
+        ..Default::default()
+    };
+    if request[1] == RESOLVE {
+        return match user.resolve(isolation, &host) {
+            Ok(addresses) => {
+                // clients of the extension expect IPv4 when there is one
+                let address = addresses
+                    .iter()
+                    .find(|address| address.is_ipv4())
+                    .or(addresses.first())
+                    .copied();
+                send_reply(&mut client, SocksReply::Succeeded, address)
+            }
+            Err(e) => {
+                send_reply(&mut client, error_reply(&e), None)?;
+                Err(e)
+            }
+        };
+    }
+    if request[1] != CONNECT {
+        send_reply(&mut client, SocksReply::CommandNotSupported, None)?;
+        return Err(anyhow::anyhow!("Unsupported SOCKS command {}", request[1]));
+    }
+
+    match user.connect(isolation, &host, port) {
+        Ok((stream_id, _)) => {
+            send_reply(&mut client, SocksReply::Succeeded, None)?;
+            proxy_stream(user, stream_id, client)
+        }
+        Err(e) => {
+            send_reply(&mut client, error_reply(&e), None)?;
+            Err(e.context(format!("Failed to connect to {}:{}", host, port)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{start_http_server, start_network, HTTP_RESPONSE};
+    use std::net::{SocketAddr, TcpListener};
+
+    fn start_proxy() -> (User, SocketAddr) {
+        let user = start_network("TestSocksUser");
+        let address = user
+            .start_socks_proxy("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        (user, address)
+    }
+
+    /// Sends a SOCKS5 request for `address` and returns the connection and
+    /// the reply, with an IPv4 bound address.
+    fn socks_request(
+        proxy: SocketAddr,
+        credentials: Option<(&str, &str)>,
+        command: u8,
+        address: &[u8],
+        port: u16,
+    ) -> (TcpStream, [u8; 10]) {
+        let mut client = TcpStream::connect(proxy).unwrap();
+        let mut reply = [0; 2];
+        match credentials {
+            Some((username, password)) => {
+                client
+                    .write_all(&[SOCKS_VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD])
+                    .unwrap();
+                client.read_exact(&mut reply).unwrap();
+                assert_eq!(reply, [SOCKS_VERSION, USERNAME_PASSWORD]);
+                let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                client.write_all(&request).unwrap();
+                client.read_exact(&mut reply).unwrap();
+                assert_eq!(reply, [USERNAME_PASSWORD_VERSION, 0]);
+            }
+            None => {
+                client
+                    .write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])
+                    .unwrap();
+                client.read_exact(&mut reply).unwrap();
+                assert_eq!(reply, [SOCKS_VERSION, NO_AUTHENTICATION]);
+            }
+        }
+        let mut request = vec![SOCKS_VERSION, command, 0];
+        request.extend_from_slice(address);
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).unwrap();
+        (client, reply)
+    }
+
+    fn http_get(mut client: TcpStream) -> Vec<u8> {
+        client
+            .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_socks_connect_to_http_server() {
+        let (user, proxy) = start_proxy();
+        let port = start_http_server();
+
+        let (client, reply) =
+            socks_request(proxy, None, CONNECT, &[IPV4_ADDRESS, 127, 0, 0, 1], port);
+        assert_eq!(reply[1], SocksReply::Succeeded as u8);
+        assert_eq!(http_get(client), HTTP_RESPONSE);
+
+        let mut domain = vec![DOMAIN_NAME, 9];
+        domain.extend_from_slice(b"localhost");
+        let alice = socks_request(proxy, Some(("alice", "secret")), CONNECT, &domain, port);
+        let bob = socks_request(proxy, Some(("bob", "secret")), CONNECT, &domain, port);
+        assert_eq!(alice.1[1], SocksReply::Succeeded as u8);
+        assert_eq!(bob.1[1], SocksReply::Succeeded as u8);
+        assert_eq!(http_get(alice.0), HTTP_RESPONSE);
+        assert_eq!(http_get(bob.0), HTTP_RESPONSE);
+
+        let circuit_isolation = user.get_state().circuit_isolation;
+        let circuit_of = |username: &str| {
+            let token = credentials_token(&[username.as_bytes(), b"secret"]).unwrap();
+            circuit_isolation
+                .iter()
+                .find(|(_, key)| key.credentials.as_ref() == Some(&token))
+                .map(|(circuit_id, _)| *circuit_id)
+                .unwrap()
+        };
+        assert_ne!(circuit_of("alice"), circuit_of("bob"));
+        // the password itself is not kept
+        assert!(circuit_isolation
+            .values()
+            .all(|key| !format!("{:?}", key).contains("secret")));
+    }
+
+    #[test]
+    fn test_socks_errors() {
+        let (_user, proxy) = start_proxy();
+        let port = start_http_server();
+
+        let bind = 0x02;
+        let (_, reply) = socks_request(proxy, None, bind, &[IPV4_ADDRESS, 127, 0, 0, 1], port);
+        assert_eq!(reply[1], SocksReply::CommandNotSupported as u8);
+
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (_, reply) = socks_request(
+            proxy,
+            None,
+            CONNECT,
+            &[IPV4_ADDRESS, 127, 0, 0, 1],
+            closed_port,
+        );
+        assert_eq!(reply[1], SocksReply::ConnectionRefused as u8);
+    }
+
+    #[test]
+    fn test_socks_resolve() {
+        let (_user, proxy) = start_proxy();
+
+        let mut domain = vec![DOMAIN_NAME, 12];
+        domain.extend_from_slice(b"service.test");
+        let (_, reply) = socks_request(proxy, None, RESOLVE, &domain, 0);
+        assert_eq!(reply[1], SocksReply::Succeeded as u8);
+        assert_eq!(reply[3..8], [IPV4_ADDRESS, 127, 0, 0, 1]);
+
+        let mut domain = vec![DOMAIN_NAME, 12];
+        domain.extend_from_slice(b"unknown.test");
+        let (_, reply) = socks_request(proxy, None, RESOLVE, &domain, 0);
+        assert_eq!(reply[1], SocksReply::HostUnreachable as u8);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{CircuitId, EndReason, FlowWindow, StreamId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum StreamStatus {
+    /// BEGIN was sent and neither CONNECTED nor END came back yet.
+    Connecting,
+    Open,
+    Closed(EndReason),
+}
+
+/// A stream multiplexed with others on one circuit.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub circuit_id: CircuitId,
+    /// Hop of the circuit the stream was opened at.
+    pub hop: usize,
+    pub status: StreamStatus,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub window: FlowWindow,
+    /// Data received and not read yet.
+    #[serde(skip)]
+    pub buffer: Vec<u8>,
+}
+
+impl StreamInfo {
+    pub fn new(circuit_id: CircuitId, hop: usize) -> Self {
+        Self {
+            circuit_id,
+            hop,
+            status: StreamStatus::Connecting,
+            bytes_sent: 0,
+            bytes_received: 0,
+            window: FlowWindow::stream(),
+            buffer: vec![],
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.status, StreamStatus::Closed(_))
+    }
+
+    /// Marks the stream closed unless it already was, keeping the first
+    /// reason. Returns whether it was still open.
+    pub fn close(&mut self, reason: EndReason) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        self.status = StreamStatus::Closed(reason);
+        true
+    }
+}
+
+/// Error of a stream closed by END, which callers can downcast to from an
+/// `anyhow::Error` to find out why.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamClosedError {
+    pub stream_id: StreamId,
+    pub reason: EndReason,
+}
+
+impl fmt::Display for StreamClosedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Stream {} was closed with reason {:?}",
+            self.stream_id, self.reason
+        )
+    }
+}
+
+impl std::error::Error for StreamClosedError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_keeps_first_reason() {
+        let mut stream = StreamInfo::new(CircuitId::new_v4(), 2);
+        assert_eq!(stream.status, StreamStatus::Connecting);
+        assert!(stream.close(EndReason::ConnReset));
+        assert!(!stream.close(EndReason::Destroy));
+        assert_eq!(stream.status, StreamStatus::Closed(EndReason::ConnReset));
+        assert!(stream.is_closed());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{ExitPolicy, Relay, RelayDescriptor, RelayFlag, RelayId, StaticResolver, User};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+
+pub const HTTP_RESPONSE: &[u8] = b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+
+/// Serves TCP connections on 127.0.0.1 with `handle`, returning the port.
+pub fn start_tcp_server(handle: fn(TcpStream)) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for socket in listener.incoming().flatten() {
+            thread::spawn(move || handle(socket));
+        }
+    });
+    port
+}
+
+pub fn echo(mut socket: TcpStream) {
+    let mut reader = socket.try_clone().unwrap();
+    let _ = std::io::copy(&mut reader, &mut socket);
+}
+
+/// Answers every request with `HTTP_RESPONSE`, returning the port.
+pub fn start_http_server() -> u16 {
+    start_tcp_server(|mut socket| {
+        let mut request = vec![];
+        let mut buffer = [0; 512];
+        while !request.ends_with(b"\r\n\r\n") {
+            match socket.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(length) => request.extend_from_slice(&buffer[..length]),
+            }
+        }
+        let _ = socket.write_all(HTTP_RESPONSE);
+    })
+}
+
+/// Resolver of the exits of tests, so that no name is looked up by the
+/// system. Knows "localhost" and "service.test".
+pub fn test_resolver() -> Arc<StaticResolver> {
+    let mut resolver = StaticResolver::default();
+    resolver.insert("localhost", vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    resolver.insert("service.test", vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    Arc::new(resolver)
+}
+
+/// Starts `count` exits that accept every destination.
+pub fn start_relays(count: usize) -> Vec<Relay> {
+    (0..count)
+        .map(|i| {
+            let mut relay = Relay::new_exit(format!("TestRelay{}", i), ExitPolicy::accept_all());
+            relay.set_resolver(test_resolver());
+            relay.start();
+            relay
+        })
+        .collect()
+}
+
+pub fn relay_ids(relays: &[Relay]) -> Vec<RelayId> {
+    relays
+        .iter()
+        .map(|relay| relay.get_relay_descriptor().id)
+        .collect()
+}
+
+pub fn start_user(nickname: &str) -> User {
+    let user = User::new(nickname.to_string());
+    user.start();
+    user
+}
+
+/// Starts a user and enough exits for it to build circuits.
+pub fn start_network(nickname: &str) -> User {
+    start_relays(3);
+    start_user(nickname)
+}
+
+/// Descriptor of a relay with every flag, for path selection tests.
+pub fn create_relay_descriptor(address: Ipv4Addr, autonomous_system: u32) -> RelayDescriptor {
+    RelayDescriptor {
+        id: Uuid::new_v4(),
+        nickname: "Relay".to_string(),
+        rsa_public: vec![],
+        family: vec![],
+        address,
+        autonomous_system,
+        bandwidth: 1000,
+        flags: vec![
+            RelayFlag::Guard,
+            RelayFlag::Exit,
+            RelayFlag::Fast,
+            RelayFlag::Stable,
+            RelayFlag::HSDir,
+        ],
+        exit_policy: ExitPolicy::accept_all(),
+    }
+}
+
+/// Descriptors of `count` unrelated relays.
+pub fn create_relays(count: u8) -> Vec<RelayDescriptor> {
+    (0..count)
+        .map(|i| create_relay_descriptor(Ipv4Addr::new(10, i, 0, 1), i as u32))
+        .collect()
+}
@@ -1,21 +1,63 @@
-use crate::payloads::{CreatePayload, ExtendPayload};
+use crate::payloads::{
+    CreatePayload, DataPayload, DropPayload, EndPayload, ExtendPayload, PingPayload,
+    ResolvePayload, SendmePayload, TruncatePayload,
+};
 use crate::relay_cell::RelayCell;
 use crate::{
-    decrypt_buffer_with_aes, encrypt_buffer_with_aes, generate_random_aes_key,
-    get_handshake_from_onion_skin, CircuitId, Communication, Directory,
-    EstablishIntroductionPayload, EstablishRendezvousPayload, Event, Handshake, Introduce1Payload,
-    IntroductionPointId, Keys, Logger, OnionSkin, Payload, PayloadType, RelayId,
-    RendezvousCookieId, StreamId, UserId, UserState,
+    decrypt_buffer_with_aes, decrypt_introduction_points, encrypt_buffer_with_aes,
+    encrypt_introduction_points, exclude_slow_relays, fragment_message, generate_introduction_key,
+    generate_random_aes_key, get_handshake_from_onion_skin, introduction_mac, package_data_cell,
+    select_path_with_exit, serve_http_client, serve_socks_client, validate_circuit_path,
+    validate_path, verify_introduction_mac, BeginTarget, CircuitBuildTimeout, CircuitId,
+    CircuitLatency, CircuitPool, CircuitPoolConfig, CircuitPurpose, Communication, DataStream,
+    DataStreamEndpoint, DestroyPayload, DestroyReason, Directory, EncryptedIntroductionPoints,
+    EndReason, EstablishIntroductionPayload, EstablishRendezvousPayload, Event, EventQueue,
+    FlowWindow, GuardSet, Handshake, Introduce1Payload, Introduction, IntroductionAuth,
+    IntroductionMacInput, IntroductionPoint, IntroductionPointId, IntroductionSection,
+    IsolationKey, IsolationPolicy, Keys, Logger, OnionService, OnionServiceConfig, OnionSkin,
+    Payload, PayloadType, Reassembler, RelayDescriptor, RelayFlag, RelayId, RendezvousCookieId,
+    ReplayCache, RttStats, StreamClosedError, StreamId, StreamInfo, StreamStatus, UserId,
+    UserState, DEFAULT_CIRCUIT_LENGTH, INTRODUCTION_POINT_CHECK_INTERVAL, INTRODUCTION_TIMEOUT,
+    MAX_BUILD_ATTEMPTS, MAX_DATA_LENGTH, MAX_PENDING_INTRODUCTIONS, MAX_PENDING_RENDEZVOUS,
+    ONION_SERVICE_POLL_INTERVAL,
 };
 use anyhow::{Context, Result};
 use openssl::bn::BigNum;
 use openssl::dh::Dh;
+use openssl::pkey::Private;
 use openssl::rsa::Rsa;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the padding carried by DROP cells, that of a Tor relay cell body.
+pub const PADDING_LENGTH: usize = 509;
+
+/// How long `listen_for_event` waits for a relay to answer.
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a stream waiting for CONNECTED checks whether it arrived.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the last hop of `circuit` is an exit whose policy allows
+/// `destination`. Any circuit will do without a destination.
+fn exit_allows(circuit: &[RelayId], destination: Option<(&str, u16)>) -> bool {
+    let Some((host, port)) = destination else {
+        return true;
+    };
+    circuit
+        .last()
+        .and_then(|relay_id| Directory::get_relay(*relay_id))
+        .is_some_and(|relay| {
+            relay.has_flag(RelayFlag::Exit) && relay.exit_policy.allows_destination(host, port)
+        })
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct UserDescriptor {
@@ -23,6 +65,14 @@ pub struct UserDescriptor {
     pub nickname: String,
     pub rsa_public: Vec<u8>,
     pub introduction_points: HashMap<IntroductionPointId, RelayId>,
+    /// Keys the INTRODUCE1s for each of `introduction_points` are
+    /// authenticated with.
+    #[serde(default)]
+    pub introduction_keys: HashMap<IntroductionPointId, Vec<u8>>,
+    /// Introduction points of a service restricted to authorized clients,
+    /// published instead of `introduction_points`.
+    #[serde(default)]
+    pub encrypted_introduction_points: Option<EncryptedIntroductionPoints>,
 }
 pub struct ConnectedUser {
     pub rendezvous_cookie: RendezvousCookieId,
@@ -32,26 +82,219 @@ pub struct ConnectedUser {
 pub struct InternalState {
     keys: Keys,
     handshakes: HashMap<RelayId, Handshake>,
-    events_sender: Sender<Event>,
     circuits: HashMap<CircuitId, Vec<RelayId>>,
     connected_users: HashMap<RendezvousCookieId, Handshake>,
     rendezvous_cookies: HashMap<RendezvousCookieId, RelayId>,
     stream_ids: HashMap<StreamId, RelayId>,
+    streams: HashMap<StreamId, StreamInfo>,
+    guards: GuardSet,
+    guard_state_path: Option<PathBuf>,
+    circuit_pool: CircuitPool,
+    build_timeout: CircuitBuildTimeout,
+    isolation_policy: IsolationPolicy,
+    /// Isolation key of the streams carried by each circuit opened for streams.
+    circuit_isolation: HashMap<CircuitId, IsolationKey>,
+    /// Time each unanswered PING was sent, by nonce.
+    pending_pings: HashMap<u64, Instant>,
+    /// Round trip times of answered PINGs, until the sender picks them up.
+    ping_results: HashMap<u64, Duration>,
+    circuit_latency: HashMap<CircuitId, CircuitLatency>,
+    relay_latency: HashMap<RelayId, RttStats>,
+    /// Flow control windows of the circuits carrying streams.
+    circuit_windows: HashMap<CircuitId, FlowWindow>,
+    /// Addresses of RESOLVE requests, None until RESOLVED comes back.
+    resolutions: HashMap<StreamId, Option<Vec<IpAddr>>>,
+    /// Fragments of messages from connected users, until complete.
+    reassembler: Reassembler,
+    /// Messages received from connected users, until read.
+    messages: HashMap<RendezvousCookieId, Vec<Vec<u8>>>,
+    /// Circuit joined at a rendezvous point to each connected user.
+    session_circuits: HashMap<RendezvousCookieId, CircuitId>,
+    /// Sessions with connected users ended by either side or by the loss of
+    /// their circuit.
+    ended_sessions: HashSet<RendezvousCookieId>,
+    hosting_onion_service: bool,
+    /// INTRODUCE2s received while hosting an onion service, until accepted.
+    introductions: VecDeque<Introduction>,
+    /// Introduction points published in the descriptor of this user.
+    introduction_points: HashMap<IntroductionPointId, RelayId>,
+    /// Keys of the introduction points established by this user.
+    introduction_keys: HashMap<IntroductionPointId, Vec<u8>>,
+    /// MACs of the INTRODUCE2s received.
+    introduction_replay_cache: ReplayCache,
+    /// Set once a client is authorized. From then on the introduction points
+    /// are only published encrypted, to no one when no client is left.
+    restricted: bool,
+    /// PEM encoded public keys of the clients the descriptor is encrypted
+    /// to, by name.
+    authorized_clients: HashMap<String, Vec<u8>>,
+    /// Set when a client was removed, until the onion service replaced the
+    /// introduction points that client knows.
+    rotate_introduction_points: bool,
+    /// Keys reading the descriptors of restricted services, by address.
+    client_credentials: HashMap<UserId, Rsa<Private>>,
+}
+
+impl InternalState {
+    fn save_guards(&self, nickname: &str) {
+        if let Some(path) = &self.guard_state_path {
+            if let Err(e) = self.guards.save(path) {
+                Logger::error(nickname, format!("Failed to save guard state: {}", e));
+            }
+        }
+    }
+
+    /// Forgets `circuit_id` and closes its streams. Returns its relays.
+    fn remove_circuit(&mut self, nickname: &str, circuit_id: CircuitId) -> Option<Vec<RelayId>> {
+        let circuit = self.circuits.remove(&circuit_id);
+        self.circuit_latency.remove(&circuit_id);
+        self.circuit_windows.remove(&circuit_id);
+        self.close_streams(nickname, circuit_id, 0, EndReason::Destroy);
+        for (rendezvous_cookie, session_circuit_id) in self.session_circuits.iter() {
+            if *session_circuit_id == circuit_id {
+                self.ended_sessions.insert(*rendezvous_cookie);
+                self.connected_users.remove(rendezvous_cookie);
+            }
+        }
+        circuit
+    }
+
+    /// Forgets `circuit_id` and sends a DESTROY to its first hop, which
+    /// propagates it along the circuit.
+    fn destroy_circuit(
+        &mut self,
+        nickname: &str,
+        user_id: UserId,
+        circuit_id: CircuitId,
+        reason: DestroyReason,
+    ) -> Result<()> {
+        let circuit = self
+            .remove_circuit(nickname, circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        let relay_id = *circuit
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("Circuit is empty"))?;
+        Logger::info(
+            nickname,
+            format!(
+                "Sending DESTROY for circuit {} to relay {}",
+                circuit_id, relay_id
+            ),
+        );
+        let destroy_payload = Payload::Destroy(DestroyPayload { reason });
+        let relay_cell = RelayCell {
+            circuit_id,
+            payload: serde_json::to_vec(&destroy_payload)
+                .context("Failed to serialize destroy payload")?,
+        };
+        Communication::send(user_id, relay_id, relay_cell)
+            .context("Failed to send communication")?;
+        Logger::info(
+            nickname,
+            format!("Destroyed circuit {} with reason {:?}", circuit_id, reason),
+        );
+        Ok(())
+    }
+
+    /// Sends `payload` to `hop` of `circuit_id`, encrypted for the hops up
+    /// to it. Returns the first hop.
+    fn send_to_hop(
+        &self,
+        user_id: UserId,
+        circuit_id: CircuitId,
+        hop: usize,
+        payload: &Payload,
+    ) -> Result<RelayId> {
+        let circuit = self
+            .circuits
+            .get(&circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        if hop >= circuit.len() {
+            return Err(anyhow::anyhow!("Circuit {} has no hop {}", circuit_id, hop));
+        }
+        let relay_id = circuit[0];
+        let mut buffer = serde_json::to_vec(payload).context("Failed to serialize payload")?;
+        for relay in circuit[..=hop].iter().rev() {
+            let handshake = self
+                .handshakes
+                .get(relay)
+                .ok_or_else(|| anyhow::anyhow!("Handshake not found for relay"))?;
+            buffer =
+                encrypt_buffer_with_aes(handshake, &buffer).context("Failed to encrypt buffer")?;
+        }
+        let relay_cell = RelayCell {
+            circuit_id,
+            payload: buffer,
+        };
+        Communication::send(user_id, relay_id, relay_cell)
+            .context("Failed to send communication")?;
+        Ok(relay_id)
+    }
+
+    /// Sends the stream SENDMEs owed for data of `stream_id` that was read,
+    /// so the exit only sends more once the reader keeps up.
+    fn acknowledge_stream(&mut self, nickname: &str, user_id: UserId, stream_id: StreamId) {
+        let Some(stream) = self
+            .streams
+            .get_mut(&stream_id)
+            .filter(|stream| !stream.is_closed())
+        else {
+            return;
+        };
+        let (circuit_id, hop) = (stream.circuit_id, stream.hop);
+        let mut sendmes = 0;
+        while stream.window.take_sendme() {
+            sendmes += 1;
+        }
+        let sendme_payload = Payload::Sendme(SendmePayload {
+            stream_id: Some(stream_id),
+        });
+        for _ in 0..sendmes {
+            if let Err(e) = self.send_to_hop(user_id, circuit_id, hop, &sendme_payload) {
+                Logger::warn(
+                    nickname,
+                    format!("Failed to send SENDME for stream {}: {}", stream_id, e),
+                );
+            }
+        }
+    }
+
+    /// Closes the streams of `circuit_id` opened at `from_hop` or further,
+    /// which can no longer be reached.
+    fn close_streams(
+        &mut self,
+        nickname: &str,
+        circuit_id: CircuitId,
+        from_hop: usize,
+        reason: EndReason,
+    ) {
+        for (stream_id, stream) in self.streams.iter_mut() {
+            if stream.circuit_id == circuit_id && stream.hop >= from_hop && stream.close(reason) {
+                Logger::info(
+                    nickname,
+                    format!("Stream {} was closed with reason {:?}", stream_id, reason),
+                );
+            }
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct User {
     nickname: String,
     id: UserId,
     rsa_public: Vec<u8>,
-    pub events_receiver: Receiver<Event>,
     pub user_descriptor: UserDescriptor,
+    events: Arc<EventQueue>,
+    /// Notified with `internal_state` whenever a cell was handled, for
+    /// readers waiting on streams.
+    stream_updates: Arc<Condvar>,
     internal_state: Arc<Mutex<InternalState>>,
 }
 
 impl User {
     pub fn new(nickname: String) -> Self {
         Logger::info(&nickname, "Creating new user");
-        let (events_sender, events_receiver) = mpsc::channel();
         let rsa = Rsa::generate(2048).unwrap();
         let id = UserId::new_v4();
         Logger::info(&nickname, format!("User ID: {:?}", id));
@@ -59,12 +302,15 @@ impl User {
             nickname: nickname.clone(),
             id,
             rsa_public: rsa.public_key_to_pem().unwrap(),
-            events_receiver,
+            events: Arc::new(EventQueue::new()),
+            stream_updates: Arc::new(Condvar::new()),
             user_descriptor: UserDescriptor {
                 nickname,
                 id,
                 rsa_public: rsa.public_key_to_pem().unwrap(),
                 introduction_points: HashMap::new(),
+                introduction_keys: HashMap::new(),
+                encrypted_introduction_points: None,
             },
             internal_state: Arc::new(Mutex::new(InternalState {
                 keys: Keys {
@@ -73,10 +319,35 @@ impl User {
                 },
                 rendezvous_cookies: HashMap::new(),
                 handshakes: HashMap::new(),
-                events_sender,
                 circuits: HashMap::new(),
                 connected_users: HashMap::new(),
                 stream_ids: HashMap::new(),
+                streams: HashMap::new(),
+                guards: GuardSet::default(),
+                guard_state_path: None,
+                circuit_pool: CircuitPool::default(),
+                build_timeout: CircuitBuildTimeout::default(),
+                isolation_policy: IsolationPolicy::default(),
+                circuit_isolation: HashMap::new(),
+                pending_pings: HashMap::new(),
+                ping_results: HashMap::new(),
+                circuit_latency: HashMap::new(),
+                relay_latency: HashMap::new(),
+                circuit_windows: HashMap::new(),
+                resolutions: HashMap::new(),
+                reassembler: Reassembler::default(),
+                messages: HashMap::new(),
+                session_circuits: HashMap::new(),
+                ended_sessions: HashSet::new(),
+                hosting_onion_service: false,
+                introductions: VecDeque::new(),
+                introduction_points: HashMap::new(),
+                introduction_keys: HashMap::new(),
+                introduction_replay_cache: ReplayCache::default(),
+                restricted: false,
+                authorized_clients: HashMap::new(),
+                rotate_introduction_points: false,
+                client_credentials: HashMap::new(),
             })),
         }
     }
@@ -87,14 +358,52 @@ impl User {
             id: self.id,
             nickname: self.nickname.clone(),
             rsa_public_key: self.rsa_public.clone(),
-            introduction_points: Directory::get_user(self.id).unwrap().introduction_points,
+            introduction_points: internal_state_lock.introduction_points.clone(),
+            restricted: internal_state_lock.restricted,
+            authorized_clients: internal_state_lock
+                .authorized_clients
+                .keys()
+                .cloned()
+                .collect(),
+            client_credentials: internal_state_lock
+                .client_credentials
+                .keys()
+                .copied()
+                .collect(),
+            replayed_introductions: internal_state_lock.introduction_replay_cache.replays(),
             circuits: internal_state_lock.circuits.clone(),
             handshakes: internal_state_lock.handshakes.clone(),
             connected_users: internal_state_lock.connected_users.clone(),
             streams: internal_state_lock.stream_ids.clone().into_iter().collect(),
+            stream_info: internal_state_lock.streams.clone(),
             logs: Logger::get_logs(self.nickname.clone()),
             rendezvous_cookies: internal_state_lock.rendezvous_cookies.clone(),
+            guards: internal_state_lock.guards.clone(),
+            circuit_pool: internal_state_lock.circuit_pool.ready_circuits(),
+            circuit_build: internal_state_lock.build_timeout.stats(),
+            circuit_isolation: internal_state_lock.circuit_isolation.clone(),
+            circuit_latency: internal_state_lock.circuit_latency.clone(),
+            relay_latency: internal_state_lock.relay_latency.clone(),
+            circuit_windows: internal_state_lock.circuit_windows.clone(),
+        }
+    }
+
+    /// Persists the guard state at `path`, loading it first if the file exists.
+    pub fn set_guard_state_path(&self, path: PathBuf) -> Result<()> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        if path.exists() {
+            internal_state_lock.guards = GuardSet::load(&path)?;
+            Logger::info(
+                &self.nickname,
+                format!("Loaded guard state from {}", path.display()),
+            );
         }
+        internal_state_lock.guard_state_path = Some(path);
+        internal_state_lock.save_guards(&self.nickname);
+        Ok(())
     }
 
     pub fn start(&self) {
@@ -108,6 +417,8 @@ impl User {
             nickname: nickname.clone(),
             rsa_public,
             introduction_points: HashMap::new(),
+            introduction_keys: HashMap::new(),
+            encrypted_introduction_points: None,
         });
         Logger::info(&nickname, "Registered successfully");
 
@@ -119,6 +430,8 @@ impl User {
         );
 
         let internal_state = self.internal_state.clone();
+        let events = self.events.clone();
+        let stream_updates = self.stream_updates.clone();
         thread::spawn(move || loop {
             match receiver.recv() {
                 Ok((sender_id, relay_cell)) => {
@@ -130,16 +443,37 @@ impl User {
                         ),
                     );
                     let mut internal_state_lock = internal_state.lock().unwrap();
-                    let payload: Payload = if let Some(circuit) =
-                        internal_state_lock.circuits.get(&relay_cell.circuit_id)
+                    // hop of the circuit the payload came from, found by peeling
+                    // one layer at a time until the payload is recognized
+                    let mut origin_hop = None;
+                    let payload: Payload = if let Ok(payload @ Payload::Destroy(_)) =
+                        serde_json::from_slice::<Payload>(&relay_cell.payload)
                     {
-                        let mut vec_handshakes = vec![];
-                        for relay in circuit {
-                            vec_handshakes
-                                .push(internal_state_lock.handshakes.get(relay).unwrap().clone());
+                        // DESTROY cells are sent in the clear, so only the first hop
+                        // may tear down the circuit
+                        if internal_state_lock
+                            .circuits
+                            .get(&relay_cell.circuit_id)
+                            .and_then(|circuit| circuit.first())
+                            != Some(&sender_id)
+                        {
+                            Logger::warn(
+                                &nickname,
+                                format!(
+                                    "Ignored DESTROY for circuit {} from {}",
+                                    relay_cell.circuit_id, sender_id
+                                ),
+                            );
+                            continue;
                         }
+                        payload
+                    } else if let Some(circuit) =
+                        internal_state_lock.circuits.get(&relay_cell.circuit_id)
+                    {
                         let mut buffer = relay_cell.payload.clone();
-                        for handshake in vec_handshakes.iter() {
+                        let mut recognized = None;
+                        for (hop, relay) in circuit.iter().enumerate() {
+                            let handshake = internal_state_lock.handshakes.get(relay).unwrap();
                             Logger::info(
                                 &nickname,
                                 format!(
@@ -148,14 +482,40 @@ impl User {
                                 ),
                             );
                             buffer = decrypt_buffer_with_aes(handshake, &buffer).unwrap();
+                            if let Ok(payload) = serde_json::from_slice::<Payload>(&buffer) {
+                                origin_hop = Some(hop);
+                                recognized = Some(payload);
+                                break;
+                            }
+                        }
+                        match recognized {
+                            Some(payload) => payload,
+                            None => {
+                                Logger::error(
+                                    &nickname,
+                                    format!(
+                                        "Unrecognized relay cell on circuit {}",
+                                        relay_cell.circuit_id
+                                    ),
+                                );
+                                continue;
+                            }
                         }
-                        serde_json::from_slice(&buffer).unwrap()
                     } else {
-                        serde_json::from_slice(&relay_cell.payload).unwrap()
+                        match serde_json::from_slice(&relay_cell.payload) {
+                            Ok(payload) => payload,
+                            Err(e) => {
+                                Logger::error(
+                                    &nickname,
+                                    format!("Failed to parse relay cell payload: {}", e),
+                                );
+                                continue;
+                            }
+                        }
                     };
                     Logger::info(
                         &nickname,
-                        &format!("Received payload: {:?}", payload.get_type()),
+                        format!("Received payload: {:?}", payload.get_type()),
                     );
                     let payload_type = payload.get_type();
                     match payload {
@@ -167,10 +527,7 @@ impl User {
                                 .unwrap();
                             Logger::info(
                                 &nickname,
-                                &format!(
-                                    "Handshake Successful: {}",
-                                    hex::encode(&handshake[0..32])
-                                ),
+                                format!("Handshake Successful: {}", hex::encode(&handshake[0..32])),
                             );
                             internal_state_lock.handshakes.insert(sender_id, handshake);
                             internal_state_lock
@@ -189,10 +546,7 @@ impl User {
                                 .unwrap();
                             Logger::info(
                                 &nickname,
-                                &format!(
-                                    "Handshake Successful: {}",
-                                    hex::encode(&handshake[0..32])
-                                ),
+                                format!("Handshake Successful: {}", hex::encode(&handshake[0..32])),
                             );
                             internal_state_lock
                                 .handshakes
@@ -211,6 +565,53 @@ impl User {
                             );
                         }
                         Payload::Introduce2(introduce2_payload) => {
+                            let introduction_id = introduce2_payload.introduction_id;
+                            if internal_state_lock.introduction_keys.is_empty() {
+                                Logger::warn(
+                                    &nickname,
+                                    "Ignored INTRODUCE2, no onion service is hosted",
+                                );
+                                continue;
+                            }
+                            let authentic = internal_state_lock
+                                .introduction_keys
+                                .get(&introduction_id)
+                                .is_some_and(|introduction_key| {
+                                    let input = IntroductionMacInput {
+                                        introduction_id,
+                                        rendezvous_cookie: introduce2_payload.rendezvous_cookie,
+                                        rendezvous_point: introduce2_payload.rendezvous_point,
+                                        onion_skin: &introduce2_payload.onion_skin,
+                                    };
+                                    verify_introduction_mac(
+                                        introduction_key,
+                                        &input,
+                                        &introduce2_payload.mac,
+                                    )
+                                });
+                            if !authentic {
+                                Logger::warn(
+                                    &nickname,
+                                    format!(
+                                        "Dropped INTRODUCE2 with an invalid MAC for introduction point {}",
+                                        introduction_id
+                                    ),
+                                );
+                                continue;
+                            }
+                            if !internal_state_lock
+                                .introduction_replay_cache
+                                .check(&introduce2_payload.mac)
+                            {
+                                Logger::warn(
+                                    &nickname,
+                                    format!(
+                                        "Dropped replayed INTRODUCE2 for introduction point {}",
+                                        introduction_id
+                                    ),
+                                );
+                                continue;
+                            }
                             let handshake = get_handshake_from_onion_skin(
                                 introduce2_payload.onion_skin,
                                 &internal_state_lock.keys.dh,
@@ -224,6 +625,18 @@ impl User {
                             internal_state_lock
                                 .connected_users
                                 .insert(introduce2_payload.rendezvous_cookie, handshake);
+                            if internal_state_lock.hosting_onion_service {
+                                let state = &mut *internal_state_lock;
+                                if state.introductions.len() >= MAX_PENDING_INTRODUCTIONS {
+                                    if let Some(dropped) = state.introductions.pop_front() {
+                                        state.connected_users.remove(&dropped.rendezvous_cookie);
+                                    }
+                                }
+                                state.introductions.push_back(Introduction {
+                                    rendezvous_cookie: introduce2_payload.rendezvous_cookie,
+                                    rendezvous_point: introduce2_payload.rendezvous_point,
+                                });
+                            }
                         }
                         Payload::Rendezvous2(rendezvous2_payload) => {
                             let handshake = internal_state_lock
@@ -235,44 +648,357 @@ impl User {
                                 .unwrap();
                             Logger::info(
                                 &nickname,
-                                &format!(
-                                    "Handshake Successful: {}",
-                                    hex::encode(&handshake[0..32])
-                                ),
+                                format!("Handshake Successful: {}", hex::encode(&handshake[0..32])),
                             );
                             internal_state_lock
                                 .connected_users
                                 .insert(rendezvous2_payload.rendezvous_cookie, handshake);
+                            internal_state_lock.session_circuits.insert(
+                                rendezvous2_payload.rendezvous_cookie,
+                                relay_cell.circuit_id,
+                            );
+                        }
+                        Payload::Data(DataPayload {
+                            stream_id: Some(stream_id),
+                            data,
+                            ..
+                        }) => {
+                            let circuit_id = relay_cell.circuit_id;
+                            let state = &mut *internal_state_lock;
+                            let Some(stream) = state.streams.get_mut(&stream_id).filter(|stream| {
+                                stream.circuit_id == circuit_id && !stream.is_closed()
+                            }) else {
+                                Logger::warn(
+                                    &nickname,
+                                    format!("Dropped data for unknown stream {}", stream_id),
+                                );
+                                continue;
+                            };
+                            let hop = stream.hop;
+                            let circuit_window = state
+                                .circuit_windows
+                                .entry(circuit_id)
+                                .or_insert_with(FlowWindow::circuit);
+                            let delivered = circuit_window
+                                .record_delivered()
+                                .and_then(|_| stream.window.record_delivered());
+                            if let Err(e) = delivered {
+                                Logger::warn(
+                                    &nickname,
+                                    format!("Closing circuit {}: {}", circuit_id, e),
+                                );
+                                if let Err(e) = internal_state_lock.destroy_circuit(
+                                    &nickname,
+                                    id,
+                                    circuit_id,
+                                    DestroyReason::Protocol,
+                                ) {
+                                    Logger::error(
+                                        &nickname,
+                                        format!("Failed to destroy circuit: {}", e),
+                                    );
+                                }
+                                stream_updates.notify_all();
+                                continue;
+                            }
+                            stream.bytes_received += data.len() as u64;
+                            stream.buffer.extend(data);
+                            // stream SENDMEs wait until the data is read
+                            if circuit_window.take_sendme() {
+                                let sendme_payload =
+                                    Payload::Sendme(SendmePayload { stream_id: None });
+                                if let Err(e) = internal_state_lock.send_to_hop(
+                                    id,
+                                    circuit_id,
+                                    hop,
+                                    &sendme_payload,
+                                ) {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!(
+                                            "Failed to send SENDME for circuit {}: {}",
+                                            circuit_id, e
+                                        ),
+                                    );
+                                }
+                            }
                         }
-                        Payload::Data(data_payload) => {
+                        Payload::Data(DataPayload {
+                            rendezvous_cookie: Some(rendezvous_cookie),
+                            data,
+                            fragment,
+                            ..
+                        }) => {
                             Logger::info(
                                 &nickname,
                                 format!("Received data from relay {}", sender_id),
                             );
-                            let user_handshake = internal_state_lock
-                                .connected_users
-                                .get(&data_payload.rendezvous_cookie)
-                                .unwrap();
-                            let decrypted_data =
-                                decrypt_buffer_with_aes(user_handshake, &data_payload.data)
-                                    .unwrap();
+                            let data = match fragment {
+                                Some(fragment) => {
+                                    match internal_state_lock.reassembler.add(fragment, data) {
+                                        Ok(Some(message)) => message,
+                                        Ok(None) => continue,
+                                        Err(e) => {
+                                            Logger::warn(
+                                                &nickname,
+                                                format!(
+                                                    "Dropped message {} from user with rendezvous cookie {}: {}",
+                                                    fragment.message_id, rendezvous_cookie, e
+                                                ),
+                                            );
+                                            continue;
+                                        }
+                                    }
+                                }
+                                None => data,
+                            };
+                            let Some(user_handshake) =
+                                internal_state_lock.connected_users.get(&rendezvous_cookie)
+                            else {
+                                Logger::warn(
+                                    &nickname,
+                                    format!(
+                                        "Dropped data for unknown rendezvous cookie {}",
+                                        rendezvous_cookie
+                                    ),
+                                );
+                                continue;
+                            };
+                            let decrypted_data = match decrypt_buffer_with_aes(
+                                user_handshake,
+                                &data,
+                            ) {
+                                Ok(decrypted_data) => decrypted_data,
+                                Err(e) => {
+                                    Logger::warn(
+                                            &nickname,
+                                            format!(
+                                                "Failed to decrypt data from user with rendezvous cookie {}: {}",
+                                                rendezvous_cookie, e
+                                            ),
+                                        );
+                                    continue;
+                                }
+                            };
+                            Logger::info(
+                                &nickname,
+                                format!(
+                                    "Received {} bytes from user with rendezvous cookie {}",
+                                    decrypted_data.len(),
+                                    rendezvous_cookie
+                                ),
+                            );
+                            internal_state_lock
+                                .messages
+                                .entry(rendezvous_cookie)
+                                .or_default()
+                                .push(decrypted_data);
+                        }
+                        Payload::Destroy(destroy_payload) => {
+                            internal_state_lock.remove_circuit(&nickname, relay_cell.circuit_id);
+                            Logger::info(
+                                &nickname,
+                                format!(
+                                    "Circuit {} was destroyed with reason {:?}",
+                                    relay_cell.circuit_id, destroy_payload.reason
+                                ),
+                            );
+                        }
+                        Payload::Truncated(truncated_payload) => {
+                            if let (Some(hop), Some(circuit)) = (
+                                origin_hop,
+                                internal_state_lock.circuits.get_mut(&relay_cell.circuit_id),
+                            ) {
+                                circuit.truncate(hop + 1);
+                                internal_state_lock
+                                    .circuit_windows
+                                    .remove(&relay_cell.circuit_id);
+                                internal_state_lock.close_streams(
+                                    &nickname,
+                                    relay_cell.circuit_id,
+                                    hop + 1,
+                                    EndReason::Destroy,
+                                );
+                                if let Some(latency) = internal_state_lock
+                                    .circuit_latency
+                                    .get_mut(&relay_cell.circuit_id)
+                                {
+                                    latency.hops.truncate(hop + 1);
+                                }
+                            }
                             Logger::info(
                                 &nickname,
                                 format!(
-                                    "Received String from user with rendezvous cookie {}: {:?}",
-                                    data_payload.rendezvous_cookie,
-                                    String::from_utf8(decrypted_data.clone()).unwrap()
+                                    "Circuit {} was truncated to {} hops with reason {:?}",
+                                    relay_cell.circuit_id,
+                                    origin_hop.map_or(0, |hop| hop + 1),
+                                    truncated_payload.reason
                                 ),
                             );
                         }
+                        Payload::Drop(_) => {
+                            Logger::info(&nickname, "Dropped a padding cell");
+                        }
+                        Payload::Pong(pong_payload) => {
+                            let sent_at = internal_state_lock
+                                .pending_pings
+                                .remove(&pong_payload.nonce);
+                            let relay_id = origin_hop.and_then(|hop| {
+                                internal_state_lock.circuits[&relay_cell.circuit_id]
+                                    .get(hop)
+                                    .copied()
+                            });
+                            if let (Some(sent_at), Some(hop), Some(relay_id)) =
+                                (sent_at, origin_hop, relay_id)
+                            {
+                                let rtt = sent_at.elapsed();
+                                let edge_ms = internal_state_lock
+                                    .circuit_latency
+                                    .entry(relay_cell.circuit_id)
+                                    .or_default()
+                                    .record(hop, rtt);
+                                if let Some(edge_ms) = edge_ms {
+                                    internal_state_lock
+                                        .relay_latency
+                                        .entry(relay_id)
+                                        .or_default()
+                                        .record(edge_ms);
+                                }
+                                internal_state_lock
+                                    .ping_results
+                                    .insert(pong_payload.nonce, rtt);
+                                Logger::info(
+                                    &nickname,
+                                    format!(
+                                        "Round trip to hop {} of circuit {} took {:?}",
+                                        hop, relay_cell.circuit_id, rtt
+                                    ),
+                                );
+                            } else {
+                                Logger::warn(&nickname, "Received an unexpected PONG");
+                            }
+                        }
                         Payload::EstablishedIntroduction(_) => {
                             Logger::info(&nickname, "Established an introduction point");
                         }
                         Payload::EstablishedRendezvous(_) => {
                             Logger::info(&nickname, "Established a rendezvous point");
                         }
-                        Payload::Connected(_) => {
-                            Logger::info(&nickname, "Connected to a relay");
+                        Payload::Connected(connected_payload) => {
+                            let stream_id = connected_payload.stream_id;
+                            match internal_state_lock
+                                .streams
+                                .get_mut(&stream_id)
+                                .filter(|stream| {
+                                    stream.circuit_id == relay_cell.circuit_id
+                                        && stream.status == StreamStatus::Connecting
+                                }) {
+                                Some(stream) => {
+                                    stream.status = StreamStatus::Open;
+                                    Logger::info(
+                                        &nickname,
+                                        format!("Stream {} is open", stream_id),
+                                    );
+                                }
+                                None => {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!("Unexpected CONNECTED for stream {}", stream_id),
+                                    );
+                                }
+                            }
+                        }
+                        Payload::End(end_payload) => {
+                            let stream_id = end_payload.stream_id;
+                            if let Some(stream) = internal_state_lock
+                                .streams
+                                .get_mut(&stream_id)
+                                .filter(|stream| stream.circuit_id == relay_cell.circuit_id)
+                            {
+                                if stream.close(end_payload.reason) {
+                                    Logger::info(
+                                        &nickname,
+                                        format!(
+                                            "Stream {} was closed with reason {:?}",
+                                            stream_id, end_payload.reason
+                                        ),
+                                    );
+                                }
+                            } else if internal_state_lock.session_circuits.get(&stream_id)
+                                == Some(&relay_cell.circuit_id)
+                            {
+                                // sessions are ended with their rendezvous cookie
+                                internal_state_lock.ended_sessions.insert(stream_id);
+                                internal_state_lock.connected_users.remove(&stream_id);
+                                Logger::info(
+                                    &nickname,
+                                    format!("Session {} was ended by the other user", stream_id),
+                                );
+                            } else {
+                                Logger::warn(
+                                    &nickname,
+                                    format!("END for unknown stream {}", stream_id),
+                                );
+                            }
+                        }
+                        Payload::Sendme(SendmePayload { stream_id }) => {
+                            let circuit_id = relay_cell.circuit_id;
+                            let state = &mut *internal_state_lock;
+                            let window = match stream_id {
+                                Some(stream_id) => state
+                                    .streams
+                                    .get_mut(&stream_id)
+                                    .filter(|stream| stream.circuit_id == circuit_id)
+                                    .map(|stream| &mut stream.window),
+                                None => Some(
+                                    state
+                                        .circuit_windows
+                                        .entry(circuit_id)
+                                        .or_insert_with(FlowWindow::circuit),
+                                ),
+                            };
+                            match window.map(|window| window.record_sendme()) {
+                                Some(Ok(())) => {}
+                                Some(Err(e)) => {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!("Closing circuit {}: {}", circuit_id, e),
+                                    );
+                                    if let Err(e) = internal_state_lock.destroy_circuit(
+                                        &nickname,
+                                        id,
+                                        circuit_id,
+                                        DestroyReason::Protocol,
+                                    ) {
+                                        Logger::error(
+                                            &nickname,
+                                            format!("Failed to destroy circuit: {}", e),
+                                        );
+                                    }
+                                }
+                                None => {
+                                    Logger::warn(&nickname, "SENDME for unknown stream");
+                                }
+                            }
+                        }
+                        Payload::Resolved(resolved_payload) => {
+                            match internal_state_lock
+                                .resolutions
+                                .get_mut(&resolved_payload.stream_id)
+                            {
+                                Some(resolution @ None) => {
+                                    *resolution = Some(resolved_payload.addresses);
+                                }
+                                _ => {
+                                    Logger::warn(
+                                        &nickname,
+                                        format!(
+                                            "Unexpected RESOLVED for request {}",
+                                            resolved_payload.stream_id
+                                        ),
+                                    );
+                                }
+                            }
                         }
                         Payload::IntroduceAck(_) => {
                             Logger::info(
@@ -285,13 +1011,8 @@ impl User {
                         }
                     }
 
-                    // send and if it fails, log the error
-                    internal_state_lock
-                        .events_sender
-                        .send(Event(payload_type, sender_id))
-                        .unwrap_or_else(|e| {
-                            Logger::warn(&nickname, format!("events sender: {}", e))
-                        });
+                    stream_updates.notify_all();
+                    events.push(Event(payload_type, sender_id, relay_cell.circuit_id));
                 }
                 Err(e) => {
                     Logger::error(&nickname, format!("Failed to receive event: {}", e));
@@ -301,21 +1022,57 @@ impl User {
     }
 
     pub fn listen_for_event(&self, event: Event) -> Result<()> {
-        loop {
-            let received_event = self.events_receiver.recv().unwrap();
-            if received_event == event {
-                return Ok(());
-            }
+        self.listen_for_event_with_timeout(event, EVENT_TIMEOUT)
+    }
+
+    pub fn listen_for_event_with_timeout(&self, event: Event, timeout: Duration) -> Result<()> {
+        if !self.events.wait_for(&event, timeout) {
+            return Err(anyhow::anyhow!(
+                "Timed out waiting for {:?} from relay {} on circuit {}",
+                event.0,
+                event.1,
+                event.2
+            ));
         }
+        Ok(())
+    }
+
+    /// Uses `timeout` for every circuit build instead of learning it from
+    /// observed build times, or goes back to learning it with None.
+    pub fn set_circuit_build_timeout(&self, timeout: Option<Duration>) {
+        self.internal_state
+            .lock()
+            .unwrap()
+            .build_timeout
+            .set_fixed_timeout(timeout);
+    }
+
+    fn circuit_build_timeout(&self) -> Duration {
+        self.internal_state.lock().unwrap().build_timeout.timeout()
     }
 
     pub fn send_create(&self, relay_id: RelayId, circuit_id: CircuitId) -> Result<()> {
-        let internal_state_lock = self
+        self.send_create_with_timeout(relay_id, circuit_id, self.circuit_build_timeout())
+    }
+
+    fn send_create_with_timeout(
+        &self,
+        relay_id: RelayId,
+        circuit_id: CircuitId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut internal_state_lock = self
             .internal_state
             .lock()
             .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
         let relay_descriptor =
             Directory::get_relay(relay_id).context("Failed to get relay from directory")?;
+        if !relay_descriptor.has_flag(RelayFlag::Guard) {
+            return Err(anyhow::anyhow!(
+                "Relay {} is not a guard and cannot be the first hop",
+                relay_descriptor.nickname
+            ));
+        }
         Logger::info(
             &self.nickname,
             format!("Sending CREATE payload to: {}", relay_descriptor.nickname),
@@ -323,7 +1080,11 @@ impl User {
 
         let rsa_public = Rsa::public_key_from_pem(&relay_descriptor.rsa_public)
             .context("Failed to parse RSA public key")?;
-        let half_dh_bytes: Vec<u8> = internal_state_lock.keys.dh.public_key().to_vec();
+        let half_dh_bytes: Vec<u8> = internal_state_lock
+            .keys
+            .dh
+            .public_key()
+            .to_vec_padded(256)?;
         let aes = generate_random_aes_key();
         let onion_skin = OnionSkin::new(rsa_public, aes, half_dh_bytes.try_into().unwrap())
             .context("Failed to create onion skin")?;
@@ -333,13 +1094,33 @@ impl User {
             payload: serde_json::to_vec(&create_payload)
                 .context("Failed to serialize create payload")?,
         };
-        Communication::send(self.user_descriptor.id, relay_descriptor.id, relay_cell)?;
+        if let Err(e) =
+            Communication::send(self.user_descriptor.id, relay_descriptor.id, relay_cell)
+        {
+            internal_state_lock.guards.record_failure(relay_id);
+            internal_state_lock.save_guards(&self.nickname);
+            return Err(e);
+        }
         Logger::info(
             &self.nickname,
             format!("Sent CREATE payload to: {}", relay_descriptor.nickname),
         );
         drop(internal_state_lock);
-        self.listen_for_event(Event(PayloadType::Created, relay_id))?;
+        let created = self.listen_for_event_with_timeout(
+            Event(PayloadType::Created, relay_id, circuit_id),
+            timeout,
+        );
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        if let Err(e) = created {
+            internal_state_lock.guards.record_failure(relay_id);
+            internal_state_lock.save_guards(&self.nickname);
+            return Err(e);
+        }
+        internal_state_lock.guards.record_success(relay_id);
+        internal_state_lock.save_guards(&self.nickname);
         Ok(())
     }
 
@@ -348,6 +1129,21 @@ impl User {
         relay_id: RelayId,
         relay_id_2: RelayId,
         circuit_id: CircuitId,
+    ) -> Result<()> {
+        self.send_extend_with_timeout(
+            relay_id,
+            relay_id_2,
+            circuit_id,
+            self.circuit_build_timeout(),
+        )
+    }
+
+    fn send_extend_with_timeout(
+        &self,
+        relay_id: RelayId,
+        relay_id_2: RelayId,
+        circuit_id: CircuitId,
+        timeout: Duration,
     ) -> Result<()> {
         let internal_state_lock = self
             .internal_state
@@ -362,9 +1158,23 @@ impl User {
         );
         let relay_descriptor =
             Directory::get_relay(relay_id_2).context("Failed to get relay from directory")?;
+        let circuit = internal_state_lock
+            .circuits
+            .get(&circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        let mut path = circuit
+            .iter()
+            .map(|relay| Directory::get_relay(*relay).context("Failed to get relay from directory"))
+            .collect::<Result<Vec<_>>>()?;
+        path.push(relay_descriptor.clone());
+        validate_path(&path).context("Refusing to extend circuit")?;
         let rsa_public = Rsa::public_key_from_pem(&relay_descriptor.rsa_public)
             .context("Failed to parse RSA public key")?;
-        let half_dh_bytes: Vec<u8> = internal_state_lock.keys.dh.public_key().to_vec();
+        let half_dh_bytes: Vec<u8> = internal_state_lock
+            .keys
+            .dh
+            .public_key()
+            .to_vec_padded(256)?;
         let aes = generate_random_aes_key();
         let onion_skin = OnionSkin::new(rsa_public, aes, half_dh_bytes.try_into().unwrap())
             .context("Failed to create onion skin")?;
@@ -372,10 +1182,6 @@ impl User {
             onion_skin,
             extend_to: relay_descriptor.id,
         });
-        let circuit = internal_state_lock
-            .circuits
-            .get(&circuit_id)
-            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
         let mut handshakes = vec![];
         for relay in circuit {
             handshakes.push(
@@ -417,8 +1223,27 @@ impl User {
             format!("Sent EXTEND payload to relay {}", relay_descriptor.nickname),
         );
         drop(internal_state_lock);
-        self.listen_for_event(Event(PayloadType::Extended, relay_id))?;
-        Ok(())
+        // the last hop answers with TRUNCATED if it cannot reach the new relay
+        let event = self.events.wait_for_any(
+            &[
+                Event(PayloadType::Extended, relay_id, circuit_id),
+                Event(PayloadType::Truncated, relay_id, circuit_id),
+            ],
+            timeout,
+        );
+        match event {
+            Some(Event(PayloadType::Extended, ..)) => Ok(()),
+            Some(_) => Err(anyhow::anyhow!(
+                "Failed to extend circuit {} to relay {}",
+                circuit_id,
+                relay_descriptor.nickname
+            )),
+            None => Err(anyhow::anyhow!(
+                "Timed out extending circuit {} to relay {}",
+                circuit_id,
+                relay_descriptor.nickname
+            )),
+        }
     }
 
     pub fn send_establish_rendezvous(
@@ -478,121 +1303,1082 @@ impl User {
             .rendezvous_cookies
             .insert(rendezvous_cookie, relay_id);
         drop(internal_state_lock);
-        self.listen_for_event(Event(PayloadType::EstablishedRendezvous, relay_id))?;
+        self.listen_for_event(Event(
+            PayloadType::EstablishedRendezvous,
+            relay_id,
+            circuit_id,
+        ))?;
         Ok(())
     }
 
-    pub fn establish_circuit(
-        &self,
-        circuit_id: CircuitId,
-        relay_id_1: RelayId,
-        relay_id_2: RelayId,
-        relay_id_3: RelayId,
-    ) -> Result<()> {
-        self.send_create(relay_id_1, circuit_id)
-            .context("Failed to send CREATE")?;
-        self.send_extend(relay_id_1, relay_id_2, circuit_id)
-            .context("Failed to send first EXTEND")?;
-        self.send_extend(relay_id_1, relay_id_3, circuit_id)
-            .context("Failed to send second EXTEND")?;
+    /// Builds a circuit through `path`, in order, with a CREATE to the first
+    /// relay followed by one EXTEND per additional hop. Each hop only gets
+    /// what is left of the circuit build timeout; a circuit that fails or
+    /// times out is torn down.
+    pub fn establish_circuit(&self, circuit_id: CircuitId, path: Vec<RelayId>) -> Result<()> {
+        let descriptors = validate_circuit_path(&path).context("Refusing to establish circuit")?;
+        let timeout = self.circuit_build_timeout();
+        let started = Instant::now();
+        let remaining = || timeout.saturating_sub(started.elapsed());
+        let result = self
+            .send_create_with_timeout(path[0], circuit_id, remaining())
+            .context("Failed to send CREATE")
+            .and_then(|_| {
+                for (hop, relay_id) in path.iter().enumerate().skip(1) {
+                    self.send_extend_with_timeout(path[0], *relay_id, circuit_id, remaining())
+                        .with_context(|| format!("Failed to send EXTEND for hop {}", hop))?;
+                }
+                Ok(())
+            });
+        let build_time = started.elapsed();
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        if let Err(e) = result {
+            let timed_out = build_time >= timeout;
+            let reason = if timed_out {
+                internal_state_lock.build_timeout.record_timeout();
+                DestroyReason::Timeout
+            } else {
+                internal_state_lock.build_timeout.record_failure();
+                DestroyReason::ConnectFailed
+            };
+            let partially_built = internal_state_lock.circuits.contains_key(&circuit_id);
+            drop(internal_state_lock);
+            if partially_built {
+                self.destroy_circuit(circuit_id, reason)?;
+            }
+            return Err(e);
+        }
+        internal_state_lock.build_timeout.record_success(build_time);
+        drop(internal_state_lock);
         Logger::info(
             &self.nickname,
             format!(
-                "Established a circuit with 3 relays, {} {} {}",
-                relay_id_1, relay_id_2, relay_id_3
+                "Established a circuit with {} relays, {}",
+                descriptors.len(),
+                descriptors
+                    .iter()
+                    .map(|relay| relay.nickname.clone())
+                    .collect::<Vec<_>>()
+                    .join(" ")
             ),
         );
         Ok(())
     }
 
-    pub fn send_data(
+    /// Forgets `circuit_id` and sends a DESTROY to its first hop, which
+    /// propagates it along the circuit.
+    pub fn destroy_circuit(&self, circuit_id: CircuitId, reason: DestroyReason) -> Result<()> {
+        self.internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .destroy_circuit(&self.nickname, self.id, circuit_id, reason)
+    }
+
+    /// Sends `payload` to `hop` of `circuit_id`, counted from 0 at the guard.
+    /// It is only encrypted for the hops up to `hop`, so that relay recognizes
+    /// it and the rest of the circuit never sees it. Returns the first hop,
+    /// which answers are received from.
+    pub fn send_to_hop(
         &self,
-        relay_id: RelayId,
-        rendezvous_cookie: RendezvousCookieId,
         circuit_id: CircuitId,
-        data: Vec<u8>,
-    ) -> Result<()> {
-        Logger::info(
-            &self.nickname,
-            format!("Sending DATA to relay at address: {}", relay_id),
-        );
-        let internal_state_lock = self
+        hop: usize,
+        payload: &Payload,
+    ) -> Result<RelayId> {
+        let relay_id = self
             .internal_state
             .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
-        let user_handshake = internal_state_lock
-            .connected_users
-            .get(&rendezvous_cookie)
-            .ok_or_else(|| anyhow::anyhow!("User handshake not found"))?;
-        let encrypted_data =
-            encrypt_buffer_with_aes(user_handshake, &data).context("Failed to encrypt data")?;
-        let data_payload: Payload = Payload::Data(crate::DataPayload {
-            data: encrypted_data,
-            rendezvous_cookie,
-        });
-        let circuit = internal_state_lock
-            .circuits
-            .get(&circuit_id)
-            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
-        let mut handshakes = vec![];
-        for relay in circuit {
-            handshakes.push(
-                internal_state_lock
-                    .handshakes
-                    .get(relay)
-                    .ok_or_else(|| anyhow::anyhow!("Handshake not found for relay"))?
-                    .clone(),
-            );
-        }
-        let mut buffer =
-            serde_json::to_vec(&data_payload).context("Failed to serialize data payload")?;
-        for handshake in handshakes.iter().rev() {
-            Logger::info(
-                &self.nickname,
-                format!(
-                    "Encoding with handshake: {}",
-                    hex::encode(&handshake[0..32])
-                ),
-            );
-            buffer =
-                encrypt_buffer_with_aes(handshake, &buffer).context("Failed to encrypt buffer")?;
-        }
-        let relay_cell = RelayCell {
-            circuit_id,
-            payload: buffer,
-        };
-        Communication::send(self.user_descriptor.id, relay_id, relay_cell)
-            .context("Failed to send communication")?;
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .send_to_hop(self.id, circuit_id, hop, payload)?;
         Logger::info(
             &self.nickname,
-            format!("Sent DATA payload to relay {}", relay_id),
+            format!(
+                "Sent {:?} payload to hop {} of circuit {}",
+                payload.get_type(),
+                hop,
+                circuit_id
+            ),
         );
+        Ok(relay_id)
+    }
+
+    /// Sends a padding cell that `hop` of `circuit_id` discards.
+    pub fn send_drop(&self, circuit_id: CircuitId, hop: usize) -> Result<()> {
+        let padding = (0..PADDING_LENGTH).map(|_| rand::random()).collect();
+        self.send_to_hop(circuit_id, hop, &Payload::Drop(DropPayload { padding }))?;
         Ok(())
     }
 
-    pub fn send_establish_introduction(
-        &self,
-        relay_id: RelayId,
-        introduction_id: IntroductionPointId,
-        circuit_id: CircuitId,
-    ) -> Result<()> {
-        let internal_state_lock = self
+    /// Measures the round trip time to `hop` of `circuit_id` with a PING.
+    pub fn ping(&self, circuit_id: CircuitId, hop: usize) -> Result<Duration> {
+        let nonce: u64 = rand::random();
+        self.internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .pending_pings
+            .insert(nonce, Instant::now());
+        let ping_payload = Payload::Ping(PingPayload { nonce });
+        let sent = self.send_to_hop(circuit_id, hop, &ping_payload);
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        let result = sent.and_then(|relay_id| loop {
+            // PONGs on the same circuit are told apart by their nonce
+            if let Some(rtt) = self
+                .internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .ping_results
+                .remove(&nonce)
+            {
+                return Ok(rtt);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !self
+                .events
+                .wait_for(&Event(PayloadType::Pong, relay_id, circuit_id), remaining)
+            {
+                return Err(anyhow::anyhow!(
+                    "Timed out waiting for PONG from hop {} of circuit {}",
+                    hop,
+                    circuit_id
+                ));
+            }
+        });
+        if result.is_err() {
+            self.internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .pending_pings
+                .remove(&nonce);
+        }
+        result
+    }
+
+    /// Pings every hop of `circuit_id`, returning the round trip times in
+    /// hop order.
+    pub fn ping_circuit(&self, circuit_id: CircuitId) -> Result<Vec<Duration>> {
+        let length = self
             .internal_state
             .lock()
-            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
-        Logger::info(
-            &self.nickname,
-            format!("Sending ESTABLISH_INTRODUCTION to relay {}", relay_id),
-        );
-        let establish_intro_payload =
-            Payload::EstablishIntroduction(EstablishIntroductionPayload {
-                introduction_id,
-                rsa_publickey: self.user_descriptor.rsa_public.clone(),
-            });
-        let circuit = internal_state_lock
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .circuits
+            .get(&circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?
+            .len();
+        (0..length).map(|hop| self.ping(circuit_id, hop)).collect()
+    }
+
+    /// Cuts `circuit_id` back so that `hop`, counted from 0 at the guard,
+    /// becomes its last hop. The circuit can then be extended again with
+    /// `send_extend`.
+    pub fn truncate_circuit(&self, circuit_id: CircuitId, hop: usize) -> Result<()> {
+        let circuit_length = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .circuits
+            .get(&circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?
+            .len();
+        if hop + 1 >= circuit_length {
+            return Err(anyhow::anyhow!(
+                "Circuit {} has no hops after hop {}",
+                circuit_id,
+                hop
+            ));
+        }
+        let relay_id = self.send_to_hop(circuit_id, hop, &Payload::Truncate(TruncatePayload {}))?;
+        self.listen_for_event(Event(PayloadType::Truncated, relay_id, circuit_id))?;
+        Ok(())
+    }
+
+    /// Picks a bandwidth-weighted path for `purpose` from the directory and
+    /// builds a circuit through it. A failed build is retried on a new path,
+    /// up to `MAX_BUILD_ATTEMPTS` paths in total.
+    pub fn build_circuit(&self, purpose: CircuitPurpose) -> Result<(CircuitId, Vec<RelayId>)> {
+        self.build_circuit_with_length(purpose, DEFAULT_CIRCUIT_LENGTH)
+    }
+
+    pub fn build_circuit_with_length(
+        &self,
+        purpose: CircuitPurpose,
+        length: usize,
+    ) -> Result<(CircuitId, Vec<RelayId>)> {
+        self.build_circuit_to(purpose, length, None)
+    }
+
+    /// Builds a circuit whose exit allows `destination`, if given, by its exit
+    /// policy.
+    fn build_circuit_to(
+        &self,
+        purpose: CircuitPurpose,
+        length: usize,
+        destination: Option<(&str, u16)>,
+    ) -> Result<(CircuitId, Vec<RelayId>)> {
+        self.build_circuit_where(purpose, length, &|relay| {
+            destination.is_none_or(|(host, port)| relay.exit_policy.allows_destination(host, port))
+        })
+    }
+
+    /// Builds a circuit whose last hop is accepted by `is_suitable_last_hop`.
+    fn build_circuit_where(
+        &self,
+        purpose: CircuitPurpose,
+        length: usize,
+        is_suitable_last_hop: &dyn Fn(&RelayDescriptor) -> bool,
+    ) -> Result<(CircuitId, Vec<RelayId>)> {
+        let mut attempt = 1;
+        loop {
+            match self.try_build_circuit(purpose, length, is_suitable_last_hop) {
+                Ok(circuit) => return Ok(circuit),
+                Err(e) if attempt < MAX_BUILD_ATTEMPTS => {
+                    Logger::warn(
+                        &self.nickname,
+                        format!(
+                            "Failed to build a {:?} circuit, retrying on a new path: {:#}",
+                            purpose, e
+                        ),
+                    );
+                    self.internal_state
+                        .lock()
+                        .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                        .build_timeout
+                        .record_retry();
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "Failed to build a {:?} circuit after {} attempts",
+                        purpose, attempt
+                    )))
+                }
+            }
+        }
+    }
+
+    fn try_build_circuit(
+        &self,
+        purpose: CircuitPurpose,
+        length: usize,
+        is_suitable_last_hop: &dyn Fn(&RelayDescriptor) -> bool,
+    ) -> Result<(CircuitId, Vec<RelayId>)> {
+        let relays = Directory::get_relays();
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        // a circuit that can only end at one relay, such as a rendezvous
+        // point, starts at another guard, like Tor choosing the exit first
+        let last_hops: Vec<RelayId> = relays
+            .iter()
+            .filter(|relay| is_suitable_last_hop(relay))
+            .map(|relay| relay.id)
+            .collect();
+        let excluded = if last_hops.len() == 1 {
+            last_hops
+        } else {
+            vec![]
+        };
+        let guard_id = internal_state_lock
+            .guards
+            .choose_guard_excluding(&relays, &excluded)?;
+        internal_state_lock.save_guards(&self.nickname);
+        let relays = exclude_slow_relays(&relays, &internal_state_lock.relay_latency, &[guard_id]);
+        drop(internal_state_lock);
+        let guard = relays
+            .iter()
+            .find(|relay| relay.id == guard_id)
+            .ok_or_else(|| anyhow::anyhow!("Guard not found in directory"))?;
+        let path =
+            select_path_with_exit(&relays, purpose, length, Some(guard), is_suitable_last_hop)
+                .context("Failed to select a path")?;
+        let path: Vec<RelayId> = path.iter().map(|relay| relay.id).collect();
+        Logger::info(
+            &self.nickname,
+            format!("Selected path {:?} for a {:?} circuit", path, purpose),
+        );
+        let circuit_id = CircuitId::new_v4();
+        self.establish_circuit(circuit_id, path.clone())?;
+        Ok((circuit_id, path))
+    }
+
+    /// Starts a background thread that keeps `config.targets` ready circuits
+    /// for each purpose, replacing circuits that failed or expired.
+    pub fn start_circuit_pool(&self, config: CircuitPoolConfig) {
+        let mut internal_state_lock = self.internal_state.lock().unwrap();
+        let already_running = internal_state_lock.circuit_pool.is_running();
+        internal_state_lock.circuit_pool.config = Some(config);
+        drop(internal_state_lock);
+        if already_running {
+            return;
+        }
+        Logger::info(&self.nickname, "Starting circuit pool");
+        let user = self.clone();
+        thread::spawn(move || loop {
+            let check_interval = match &user.internal_state.lock().unwrap().circuit_pool.config {
+                Some(config) => config.check_interval,
+                None => break,
+            };
+            user.maintain_circuit_pool();
+            thread::sleep(check_interval);
+        });
+    }
+
+    pub fn stop_circuit_pool(&self) {
+        Logger::info(&self.nickname, "Stopping circuit pool");
+        self.internal_state.lock().unwrap().circuit_pool.config = None;
+    }
+
+    fn maintain_circuit_pool(&self) {
+        let mut internal_state_lock = self.internal_state.lock().unwrap();
+        let circuits = internal_state_lock.circuits.clone();
+        let expired = internal_state_lock
+            .circuit_pool
+            .prune(|circuit_id| circuits.contains_key(circuit_id));
+        let missing = internal_state_lock.circuit_pool.missing();
+        drop(internal_state_lock);
+        for circuit_id in expired {
+            Logger::info(
+                &self.nickname,
+                format!("Pooled circuit {} expired", circuit_id),
+            );
+            if let Err(e) = self.destroy_circuit(circuit_id, DestroyReason::Finished) {
+                Logger::warn(
+                    &self.nickname,
+                    format!("Failed to destroy expired circuit {}: {}", circuit_id, e),
+                );
+            }
+        }
+
+        for (purpose, count) in missing {
+            for _ in 0..count {
+                match self.build_circuit(purpose) {
+                    Ok((circuit_id, _)) => {
+                        let mut internal_state_lock = self.internal_state.lock().unwrap();
+                        if internal_state_lock.circuit_pool.is_running() {
+                            internal_state_lock.circuit_pool.add(purpose, circuit_id);
+                        }
+                    }
+                    Err(e) => Logger::warn(
+                        &self.nickname,
+                        format!(
+                            "Failed to build a {:?} circuit for the pool: {}",
+                            purpose, e
+                        ),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Returns a ready circuit for `purpose` from the pool, or builds one if
+    /// none is available.
+    pub fn take_circuit(&self, purpose: CircuitPurpose) -> Result<CircuitId> {
+        self.take_circuit_to(purpose, None)
+    }
+
+    fn take_circuit_to(
+        &self,
+        purpose: CircuitPurpose,
+        destination: Option<(&str, u16)>,
+    ) -> Result<CircuitId> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let InternalState {
+            circuits,
+            circuit_pool,
+            ..
+        } = &mut *internal_state_lock;
+        let pooled = circuit_pool.take_matching(purpose, |circuit_id| {
+            circuits
+                .get(circuit_id)
+                .is_some_and(|circuit| exit_allows(circuit, destination))
+        });
+        drop(internal_state_lock);
+        if let Some(circuit_id) = pooled {
+            Logger::info(
+                &self.nickname,
+                format!("Using pooled {:?} circuit {}", purpose, circuit_id),
+            );
+            return Ok(circuit_id);
+        }
+        let (circuit_id, _) =
+            self.build_circuit_to(purpose, DEFAULT_CIRCUIT_LENGTH, destination)?;
+        Ok(circuit_id)
+    }
+
+    /// Returns a ready circuit for `purpose` from the pool whose last hop is
+    /// suitable, or builds one if there is none.
+    fn take_circuit_where(
+        &self,
+        purpose: CircuitPurpose,
+        is_suitable_last_hop: &dyn Fn(&RelayDescriptor) -> bool,
+    ) -> Result<(CircuitId, Vec<RelayId>)> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let InternalState {
+            circuits,
+            circuit_pool,
+            ..
+        } = &mut *internal_state_lock;
+        let pooled = circuit_pool
+            .take_matching(purpose, |circuit_id| {
+                circuits
+                    .get(circuit_id)
+                    .and_then(|circuit| circuit.last())
+                    .and_then(|relay_id| Directory::get_relay(*relay_id))
+                    .is_some_and(|relay| is_suitable_last_hop(&relay))
+            })
+            .map(|circuit_id| (circuit_id, circuits[&circuit_id].clone()));
+        drop(internal_state_lock);
+        if let Some((circuit_id, path)) = pooled {
+            Logger::info(
+                &self.nickname,
+                format!("Using pooled {:?} circuit {}", purpose, circuit_id),
+            );
+            return Ok((circuit_id, path));
+        }
+        self.build_circuit_where(purpose, DEFAULT_CIRCUIT_LENGTH, is_suitable_last_hop)
+    }
+
+    pub fn send_data(
+        &self,
+        relay_id: RelayId,
+        rendezvous_cookie: RendezvousCookieId,
+        circuit_id: CircuitId,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        Logger::info(
+            &self.nickname,
+            format!("Sending DATA to relay at address: {}", relay_id),
+        );
+        let internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let user_handshake = internal_state_lock
+            .connected_users
+            .get(&rendezvous_cookie)
+            .ok_or_else(|| anyhow::anyhow!("User handshake not found"))?;
+        let encrypted_data =
+            encrypt_buffer_with_aes(user_handshake, &data).context("Failed to encrypt data")?;
+        let circuit = internal_state_lock
+            .circuits
+            .get(&circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        let mut handshakes = vec![];
+        for relay in circuit {
+            handshakes.push(
+                internal_state_lock
+                    .handshakes
+                    .get(relay)
+                    .ok_or_else(|| anyhow::anyhow!("Handshake not found for relay"))?
+                    .clone(),
+            );
+        }
+        let fragments = fragment_message(&encrypted_data);
+        let cells = fragments.len();
+        for (fragment, data) in fragments {
+            let data_payload: Payload = Payload::Data(DataPayload {
+                data,
+                stream_id: None,
+                rendezvous_cookie: Some(rendezvous_cookie),
+                fragment: Some(fragment),
+            });
+            let mut buffer =
+                serde_json::to_vec(&data_payload).context("Failed to serialize data payload")?;
+            for handshake in handshakes.iter().rev() {
+                buffer = encrypt_buffer_with_aes(handshake, &buffer)
+                    .context("Failed to encrypt buffer")?;
+            }
+            let relay_cell = RelayCell {
+                circuit_id,
+                payload: buffer,
+            };
+            Communication::send(self.user_descriptor.id, relay_id, relay_cell)
+                .context("Failed to send communication")?;
+        }
+        Logger::info(
+            &self.nickname,
+            format!("Sent DATA payload to relay {} in {} cells", relay_id, cells),
+        );
+        Ok(())
+    }
+
+    /// Hosts an onion service at the address of this user. Establishes
+    /// `config.introduction_points` introduction points, publishes them in the
+    /// descriptor of the user, then builds a circuit to the rendezvous point of
+    /// each INTRODUCE2, yielding one connection per client. Introduction
+    /// points whose circuit fails are replaced, and each is moved to another
+    /// relay after `config.rotation_period`, republishing the descriptor.
+    /// Blocks until the introduction points are established.
+    pub fn host_onion_service(&self, config: OnionServiceConfig) -> Result<OnionService> {
+        {
+            let mut internal_state_lock = self
+                .internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+            if internal_state_lock.hosting_onion_service {
+                return Err(anyhow::anyhow!("Already hosting an onion service"));
+            }
+            internal_state_lock.hosting_onion_service = true;
+            internal_state_lock.rotate_introduction_points = false;
+        }
+        let mut introduction_points = vec![];
+        for _ in 0..config.introduction_points {
+            match self.establish_introduction_point(&introduction_points) {
+                Ok(introduction_point) => introduction_points.push(introduction_point),
+                Err(e) => {
+                    self.stop_onion_service(&introduction_points);
+                    return Err(e.context("Failed to establish an introduction point"));
+                }
+            }
+        }
+        self.publish_introduction_points(&introduction_points);
+        Logger::info(
+            &self.nickname,
+            format!(
+                "Hosting an onion service with {} introduction points",
+                introduction_points.len()
+            ),
+        );
+
+        let (connections_sender, connections) = tokio::sync::mpsc::unbounded_channel();
+        let user = self.clone();
+        thread::spawn(move || {
+            let pending_rendezvous = Arc::new(AtomicUsize::new(0));
+            let mut last_check = Instant::now();
+            // stops once the service was dropped
+            while !connections_sender.is_closed() {
+                let rotation_requested = user
+                    .internal_state
+                    .lock()
+                    .unwrap()
+                    .rotate_introduction_points;
+                if rotation_requested || last_check.elapsed() >= INTRODUCTION_POINT_CHECK_INTERVAL {
+                    user.maintain_introduction_points(&mut introduction_points, &config);
+                    last_check = Instant::now();
+                }
+                let free = MAX_PENDING_RENDEZVOUS
+                    .saturating_sub(pending_rendezvous.load(Ordering::SeqCst));
+                let introductions =
+                    match user.wait_for_introductions(ONION_SERVICE_POLL_INTERVAL, free) {
+                        Ok(introductions) => introductions,
+                        Err(e) => {
+                            Logger::error(&user.nickname, format!("Onion service failed: {}", e));
+                            break;
+                        }
+                    };
+                for introduction in introductions {
+                    let user = user.clone();
+                    let connections_sender = connections_sender.clone();
+                    let pending_rendezvous = pending_rendezvous.clone();
+                    pending_rendezvous.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn(move || {
+                        match user.accept_introduction(introduction) {
+                            Ok(connection) => {
+                                let _ = connections_sender.send(connection);
+                            }
+                            Err(e) => Logger::warn(
+                                &user.nickname,
+                                format!(
+                                    "Failed to meet client at rendezvous point {}: {:#}",
+                                    introduction.rendezvous_point, e
+                                ),
+                            ),
+                        }
+                        pending_rendezvous.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            }
+            user.stop_onion_service(&introduction_points);
+        });
+        Ok(OnionService::new(self.id, connections))
+    }
+
+    /// Connects to the onion service at `address`. Builds a circuit to a
+    /// rendezvous point, then introduces itself through the introduction
+    /// points in the descriptor of the service, in random order, until the
+    /// service comes to the rendezvous point. Blocks until connected.
+    pub fn connect_onion(&self, address: UserId) -> Result<DataStream> {
+        let descriptor = Directory::get_user(address)
+            .ok_or_else(|| anyhow::anyhow!("Onion service {} not found", address))?;
+        let introduction_section = self.read_introduction_points(&descriptor)?;
+        let mut introduction_points: Vec<(IntroductionPointId, RelayId)> = introduction_section
+            .introduction_points
+            .iter()
+            .map(|(introduction_id, relay_id)| (*introduction_id, *relay_id))
+            .collect();
+        if introduction_points.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Onion service {} has no introduction points",
+                address
+            ));
+        }
+        introduction_points.shuffle(&mut rand::thread_rng());
+        let introduction_relays: Vec<RelayId> = introduction_points
+            .iter()
+            .map(|(_, relay_id)| *relay_id)
+            .collect();
+        let (circuit_id, path) = self.take_circuit_where(CircuitPurpose::Rendezvous, &|relay| {
+            !introduction_relays.contains(&relay.id)
+        })?;
+        let rendezvous_cookie = RendezvousCookieId::new_v4();
+        let result = self
+            .send_establish_rendezvous(path[0], rendezvous_cookie, circuit_id)
+            .and_then(|_| {
+                for (introduction_id, relay_id) in introduction_points.iter() {
+                    let introduced = introduction_section
+                        .introduction_keys
+                        .get(introduction_id)
+                        .ok_or_else(|| anyhow::anyhow!("No key for the introduction point"))
+                        .and_then(|introduction_key| {
+                            let introduction = IntroductionAuth {
+                                introduction_id: *introduction_id,
+                                introduction_key: introduction_key.clone(),
+                            };
+                            self.introduce(
+                                path[0],
+                                circuit_id,
+                                *relay_id,
+                                introduction,
+                                rendezvous_cookie,
+                                &descriptor.rsa_public,
+                            )
+                        });
+                    match introduced {
+                        Ok(()) => return Ok(()),
+                        Err(e) => Logger::warn(
+                            &self.nickname,
+                            format!("Introduction through relay {} failed: {:#}", relay_id, e),
+                        ),
+                    }
+                }
+                Err(anyhow::anyhow!(
+                    "No introduction point of onion service {} answered",
+                    address
+                ))
+            });
+        if let Err(e) = result {
+            let _ = self.destroy_circuit(circuit_id, DestroyReason::Finished);
+            return Err(e);
+        }
+        Logger::info(
+            &self.nickname,
+            format!("Connected to onion service {}", address),
+        );
+        self.open_session_stream(rendezvous_cookie)
+    }
+
+    /// Introduction points in `descriptor`, decrypted with the credentials
+    /// for the service if it is restricted to authorized clients.
+    fn read_introduction_points(&self, descriptor: &UserDescriptor) -> Result<IntroductionSection> {
+        let Some(encrypted) = &descriptor.encrypted_introduction_points else {
+            return Ok(IntroductionSection {
+                introduction_points: descriptor.introduction_points.clone(),
+                introduction_keys: descriptor.introduction_keys.clone(),
+            });
+        };
+        let internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let client_key = internal_state_lock
+            .client_credentials
+            .get(&descriptor.id)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Onion service {} requires client authorization",
+                    descriptor.id
+                )
+            })?;
+        decrypt_introduction_points(encrypted, client_key).with_context(|| {
+            format!(
+                "Failed to read the introduction points of onion service {}",
+                descriptor.id
+            )
+        })
+    }
+
+    /// Sends INTRODUCE1 to `introduction` at `relay_id` over a stream from
+    /// the rendezvous point of `circuit_id`, then waits for the service to
+    /// join the circuit there.
+    fn introduce(
+        &self,
+        first_hop: RelayId,
+        circuit_id: CircuitId,
+        relay_id: RelayId,
+        introduction: IntroductionAuth,
+        rendezvous_cookie: RendezvousCookieId,
+        rsa_public: &[u8],
+    ) -> Result<()> {
+        let stream_id = StreamId::new_v4();
+        self.send_begin(first_hop, circuit_id, stream_id, relay_id)?;
+        let result = self
+            .wait_for_stream(stream_id, first_hop)
+            .and_then(|_| {
+                self.send_introduce1(
+                    first_hop,
+                    introduction,
+                    stream_id,
+                    rendezvous_cookie,
+                    rsa_public.to_vec(),
+                    circuit_id,
+                )
+            })
+            .and_then(|_| {
+                self.listen_for_event_with_timeout(
+                    Event(PayloadType::Rendezvous2, first_hop, circuit_id),
+                    INTRODUCTION_TIMEOUT,
+                )
+            });
+        // only needed to reach the introduction point
+        let _ = self.end_stream(stream_id, EndReason::Done);
+        result
+    }
+
+    /// Establishes an introduction point at the last hop of a new circuit,
+    /// at a relay other than those of `existing`.
+    fn establish_introduction_point(
+        &self,
+        existing: &[IntroductionPoint],
+    ) -> Result<IntroductionPoint> {
+        let (circuit_id, path) = self
+            .take_circuit_where(CircuitPurpose::Introduction, &|relay| {
+                existing.iter().all(|point| point.relay_id != relay.id)
+            })?;
+        let introduction_id = IntroductionPointId::new_v4();
+        if let Err(e) = self.send_establish_introduction(path[0], introduction_id, circuit_id) {
+            let _ = self.destroy_circuit(circuit_id, DestroyReason::Finished);
+            return Err(e);
+        }
+        Ok(IntroductionPoint {
+            introduction_id,
+            relay_id: path[path.len() - 1],
+            circuit_id,
+            established_at: Instant::now(),
+        })
+    }
+
+    /// Whether the circuit of `introduction_point` still ends at its relay.
+    fn introduction_point_alive(&self, introduction_point: &IntroductionPoint) -> bool {
+        let internal_state_lock = self.internal_state.lock().unwrap();
+        internal_state_lock
+            .circuits
+            .get(&introduction_point.circuit_id)
+            .and_then(|circuit| circuit.last())
+            == Some(&introduction_point.relay_id)
+    }
+
+    /// Replaces the introduction points whose circuit failed and those older
+    /// than `config.rotation_period`, then republishes the descriptor if any
+    /// changed. Old introduction points are kept while no replacement can be
+    /// established, unless a client was removed: then all of them are
+    /// replaced, since the client knows them.
+    fn maintain_introduction_points(
+        &self,
+        introduction_points: &mut Vec<IntroductionPoint>,
+        config: &OnionServiceConfig,
+    ) {
+        let revoked = std::mem::take(
+            &mut self
+                .internal_state
+                .lock()
+                .unwrap()
+                .rotate_introduction_points,
+        );
+        let (alive, failed): (Vec<IntroductionPoint>, Vec<IntroductionPoint>) = introduction_points
+            .iter()
+            .partition(|point| self.introduction_point_alive(point));
+        for introduction_point in failed.iter() {
+            Logger::warn(
+                &self.nickname,
+                format!(
+                    "Introduction point at relay {} failed",
+                    introduction_point.relay_id
+                ),
+            );
+            // a truncated circuit is still there
+            let _ = self.destroy_circuit(introduction_point.circuit_id, DestroyReason::Finished);
+        }
+        let (mut expired, mut kept): (Vec<IntroductionPoint>, Vec<IntroductionPoint>) = alive
+            .into_iter()
+            .partition(|point| revoked || point.established_at.elapsed() >= config.rotation_period);
+        let mut established = 0;
+        while kept.len() < config.introduction_points {
+            let excluded: Vec<IntroductionPoint> =
+                kept.iter().chain(expired.iter()).copied().collect();
+            match self.establish_introduction_point(&excluded) {
+                Ok(introduction_point) => {
+                    kept.push(introduction_point);
+                    established += 1;
+                }
+                Err(e) => {
+                    Logger::warn(
+                        &self.nickname,
+                        format!("Failed to establish an introduction point: {:#}", e),
+                    );
+                    break;
+                }
+            }
+        }
+        while !revoked && kept.len() < config.introduction_points {
+            match expired.pop() {
+                Some(introduction_point) => kept.push(introduction_point),
+                None => break,
+            }
+        }
+        if failed.is_empty() && expired.is_empty() && established == 0 {
+            *introduction_points = kept;
+            return;
+        }
+        self.publish_introduction_points(&kept);
+        for introduction_point in expired.iter() {
+            Logger::info(
+                &self.nickname,
+                format!(
+                    "Rotated introduction point away from relay {}",
+                    introduction_point.relay_id
+                ),
+            );
+            let _ = self.destroy_circuit(introduction_point.circuit_id, DestroyReason::Finished);
+        }
+        Logger::info(
+            &self.nickname,
+            format!(
+                "Republished descriptor with {} introduction points",
+                kept.len()
+            ),
+        );
+        *introduction_points = kept;
+    }
+
+    /// Publishes the descriptor of this user with `introduction_points` as
+    /// its only introduction points.
+    fn publish_introduction_points(&self, introduction_points: &[IntroductionPoint]) {
+        let mut internal_state_lock = self.internal_state.lock().unwrap();
+        internal_state_lock.introduction_points = introduction_points
+            .iter()
+            .map(|point| (point.introduction_id, point.relay_id))
+            .collect();
+        let state = &mut *internal_state_lock;
+        state
+            .introduction_keys
+            .retain(|introduction_id, _| state.introduction_points.contains_key(introduction_id));
+        if let Err(e) = self.publish_descriptor(&internal_state_lock) {
+            Logger::error(
+                &self.nickname,
+                format!("Failed to publish descriptor: {:#}", e),
+            );
+        }
+    }
+
+    /// Publishes the descriptor of this user, its introduction points
+    /// encrypted to the authorized clients if the service is restricted.
+    fn publish_descriptor(&self, internal_state: &InternalState) -> Result<()> {
+        let mut descriptor = self.user_descriptor.clone();
+        let introduction_section = IntroductionSection {
+            introduction_points: internal_state.introduction_points.clone(),
+            introduction_keys: internal_state
+                .introduction_keys
+                .iter()
+                .filter(|(introduction_id, _)| {
+                    internal_state
+                        .introduction_points
+                        .contains_key(introduction_id)
+                })
+                .map(|(introduction_id, key)| (*introduction_id, key.clone()))
+                .collect(),
+        };
+        if !internal_state.restricted {
+            descriptor.introduction_points = introduction_section.introduction_points;
+            descriptor.introduction_keys = introduction_section.introduction_keys;
+        } else {
+            let client_public_keys: Vec<&[u8]> = internal_state
+                .authorized_clients
+                .values()
+                .map(|public_key| public_key.as_slice())
+                .collect();
+            descriptor.encrypted_introduction_points = Some(encrypt_introduction_points(
+                &introduction_section,
+                &client_public_keys,
+            )?);
+        }
+        Directory::publish_user(descriptor);
+        Ok(())
+    }
+
+    /// Restricts the onion service of this user to authorized clients, adding
+    /// the client with the PEM encoded RSA `public_key` as `name`. The service
+    /// stays restricted once its clients are removed.
+    pub fn add_authorized_client(&self, name: String, public_key: Vec<u8>) -> Result<()> {
+        Rsa::public_key_from_pem(&public_key).context("Failed to parse client public key")?;
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        internal_state_lock.restricted = true;
+        internal_state_lock
+            .authorized_clients
+            .insert(name.clone(), public_key);
+        self.publish_descriptor(&internal_state_lock)?;
+        Logger::info(&self.nickname, format!("Authorized client {}", name));
+        Ok(())
+    }
+
+    /// Removes the authorized client `name`. The onion service then moves its
+    /// introduction points, with new keys, so that the client cannot use
+    /// those it learnt before. Without authorized clients the service is
+    /// unreachable.
+    pub fn remove_authorized_client(&self, name: &str) -> Result<()> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        internal_state_lock
+            .authorized_clients
+            .remove(name)
+            .ok_or_else(|| anyhow::anyhow!("Client {} is not authorized", name))?;
+        internal_state_lock.rotate_introduction_points = true;
+        self.publish_descriptor(&internal_state_lock)?;
+        Logger::info(
+            &self.nickname,
+            format!("Removed authorized client {}", name),
+        );
+        Ok(())
+    }
+
+    /// Generates the key this user reads the descriptor of the restricted
+    /// service at `address` with. Returns the PEM encoded public key, which
+    /// the service has to authorize.
+    pub fn create_client_credentials(&self, address: UserId) -> Result<Vec<u8>> {
+        let client_key = Rsa::generate(2048).context("Failed to generate client key")?;
+        let public_key = client_key
+            .public_key_to_pem()
+            .context("Failed to encode client public key")?;
+        self.internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .client_credentials
+            .insert(address, client_key);
+        Logger::info(
+            &self.nickname,
+            format!("Created client credentials for onion service {}", address),
+        );
+        Ok(public_key)
+    }
+
+    pub fn remove_client_credentials(&self, address: UserId) -> Result<()> {
+        self.internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .client_credentials
+            .remove(&address)
+            .ok_or_else(|| anyhow::anyhow!("No client credentials for {}", address))?;
+        Logger::info(
+            &self.nickname,
+            format!("Removed client credentials for onion service {}", address),
+        );
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for INTRODUCE2s and takes them.
+    fn wait_for_introductions(&self, timeout: Duration, max: usize) -> Result<Vec<Introduction>> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        if internal_state_lock.introductions.is_empty() || max == 0 {
+            internal_state_lock = self
+                .stream_updates
+                .wait_timeout(internal_state_lock, timeout)
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .0;
+        }
+        let count = internal_state_lock.introductions.len().min(max);
+        Ok(internal_state_lock.introductions.drain(..count).collect())
+    }
+
+    /// Builds a circuit to the rendezvous point of `introduction` and joins
+    /// the client there.
+    fn accept_introduction(&self, introduction: Introduction) -> Result<DataStream> {
+        let joined = self
+            .take_circuit_where(CircuitPurpose::Rendezvous, &|relay| {
+                relay.id == introduction.rendezvous_point
+            })
+            .and_then(|(circuit_id, path)| {
+                self.send_rendezvous1(path[0], introduction.rendezvous_cookie, circuit_id)
+            });
+        if let Err(e) = joined {
+            // the client is never met, so its key is not needed
+            self.internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .connected_users
+                .remove(&introduction.rendezvous_cookie);
+            return Err(e);
+        }
+        Logger::info(
+            &self.nickname,
+            format!(
+                "Joined client at rendezvous point {}",
+                introduction.rendezvous_point
+            ),
+        );
+        self.open_session_stream(introduction.rendezvous_cookie)
+    }
+
+    /// Tears down the introduction points of the onion service and removes
+    /// them from the descriptor.
+    fn stop_onion_service(&self, introduction_points: &[IntroductionPoint]) {
+        for introduction_point in introduction_points {
+            let _ = self.destroy_circuit(introduction_point.circuit_id, DestroyReason::Finished);
+        }
+        self.publish_introduction_points(&[]);
+        let mut internal_state_lock = self.internal_state.lock().unwrap();
+        internal_state_lock.hosting_onion_service = false;
+        let state = &mut *internal_state_lock;
+        for introduction in state.introductions.drain(..) {
+            state
+                .connected_users
+                .remove(&introduction.rendezvous_cookie);
+        }
+        Logger::info(&self.nickname, "Stopped onion service");
+    }
+
+    pub fn send_establish_introduction(
+        &self,
+        relay_id: RelayId,
+        introduction_id: IntroductionPointId,
+        circuit_id: CircuitId,
+    ) -> Result<()> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        Logger::info(
+            &self.nickname,
+            format!("Sending ESTABLISH_INTRODUCTION to relay {}", relay_id),
+        );
+        let introduction_key = generate_introduction_key();
+        let establish_intro_payload =
+            Payload::EstablishIntroduction(EstablishIntroductionPayload {
+                introduction_id,
+                rsa_publickey: self.user_descriptor.rsa_public.clone(),
+                introduction_key: introduction_key.clone(),
+            });
+        let circuit = internal_state_lock
             .circuits
             .get(&circuit_id)
             .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        let introduction_relay = *circuit
+            .last()
+            .ok_or_else(|| anyhow::anyhow!("Circuit is empty"))?;
         let mut handshakes = vec![];
         for relay in circuit {
             handshakes.push(
@@ -622,20 +2408,617 @@ impl User {
         };
         Communication::send(self.user_descriptor.id, relay_id, relay_cell)
             .context("Failed to send communication")?;
-        Directory::add_user_introduction_point(
-            self.id,
-            introduction_id,
-            *circuit
-                .last()
-                .ok_or_else(|| anyhow::anyhow!("Circuit is empty"))?,
-        )
-        .context("Failed to add user introduction point to directory")?;
+        internal_state_lock
+            .introduction_points
+            .insert(introduction_id, introduction_relay);
+        internal_state_lock
+            .introduction_keys
+            .insert(introduction_id, introduction_key);
+        self.publish_descriptor(&internal_state_lock)
+            .context("Failed to publish introduction point")?;
         Logger::info(
             &self.nickname,
             format!("Sent ESTABLISH_INTRODUCTION payload to relay {}", relay_id),
         );
         drop(internal_state_lock);
-        self.listen_for_event(Event(PayloadType::EstablishedIntroduction, relay_id))?;
+        self.listen_for_event(Event(
+            PayloadType::EstablishedIntroduction,
+            relay_id,
+            circuit_id,
+        ))?;
+        Ok(())
+    }
+
+    pub fn set_isolation_policy(&self, policy: IsolationPolicy) {
+        self.internal_state.lock().unwrap().isolation_policy = policy;
+    }
+
+    /// Returns a circuit that only carries streams with the same isolated key
+    /// as `isolation`, taking a fresh circuit from the pool (or building one)
+    /// if there is none yet.
+    pub fn circuit_for_stream(&self, isolation: &IsolationKey) -> Result<CircuitId> {
+        self.circuit_for_stream_to(isolation, None)
+    }
+
+    /// Like `circuit_for_stream`, but only returns a circuit whose exit
+    /// allows `destination` by its exit policy.
+    fn circuit_for_stream_to(
+        &self,
+        isolation: &IsolationKey,
+        destination: Option<(&str, u16)>,
+    ) -> Result<CircuitId> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let isolation = isolation.isolated(&internal_state_lock.isolation_policy);
+        let InternalState {
+            circuits,
+            circuit_isolation,
+            ..
+        } = &mut *internal_state_lock;
+        circuit_isolation.retain(|circuit_id, _| circuits.contains_key(circuit_id));
+        if let Some((circuit_id, _)) = circuit_isolation.iter().find(|(circuit_id, key)| {
+            **key == isolation && exit_allows(&circuits[*circuit_id], destination)
+        }) {
+            return Ok(*circuit_id);
+        }
+        drop(internal_state_lock);
+
+        let circuit_id = self.take_circuit_to(CircuitPurpose::General, destination)?;
+        Logger::info(
+            &self.nickname,
+            format!(
+                "Using circuit {} for streams isolated by {:?}",
+                circuit_id, isolation
+            ),
+        );
+        self.internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .circuit_isolation
+            .insert(circuit_id, isolation);
+        Ok(circuit_id)
+    }
+
+    /// Opens a stream to `begin_relay_id` on a circuit chosen by
+    /// `circuit_for_stream`. The destination defaults to `begin_relay_id`.
+    pub fn begin_stream(
+        &self,
+        isolation: IsolationKey,
+        begin_relay_id: RelayId,
+    ) -> Result<(StreamId, CircuitId)> {
+        let relay_descriptor =
+            Directory::get_relay(begin_relay_id).context("Failed to get relay from directory")?;
+        self.begin_stream_to(isolation, BeginTarget::Relay(relay_descriptor))
+    }
+
+    /// Opens a stream to `host:port` through the exit relay of a circuit
+    /// chosen by `circuit_for_stream`. The destination defaults to
+    /// `host:port`.
+    pub fn connect(
+        &self,
+        isolation: IsolationKey,
+        host: &str,
+        port: u16,
+    ) -> Result<(StreamId, CircuitId)> {
+        let target = BeginTarget::Host {
+            host: host.to_string(),
+            port,
+        };
+        self.begin_stream_to(isolation, target)
+    }
+
+    fn begin_stream_to(
+        &self,
+        mut isolation: IsolationKey,
+        target: BeginTarget,
+    ) -> Result<(StreamId, CircuitId)> {
+        isolation
+            .destination
+            .get_or_insert_with(|| target.destination());
+        let destination = match &target {
+            BeginTarget::Host { host, port } => Some((host.as_str(), *port)),
+            BeginTarget::Relay(_) => None,
+        };
+        let circuit_id = self.circuit_for_stream_to(&isolation, destination)?;
+        let stream_id = self.begin_stream_on(circuit_id, target)?;
+        Ok((stream_id, circuit_id))
+    }
+
+    /// Resolves `hostname` at the exit of a circuit chosen by
+    /// `circuit_for_stream`, so the name is never looked up locally.
+    pub fn resolve(&self, isolation: IsolationKey, hostname: &str) -> Result<Vec<IpAddr>> {
+        let circuit_id = self.circuit_for_stream(&isolation)?;
+        self.resolve_on(circuit_id, hostname)
+    }
+
+    /// Resolves `hostname` at the last hop of `circuit_id`. Fails with a
+    /// `StreamClosedError` if the name could not be resolved.
+    pub fn resolve_on(&self, circuit_id: CircuitId, hostname: &str) -> Result<Vec<IpAddr>> {
+        let stream_id = StreamId::new_v4();
+        let hop = {
+            let mut internal_state_lock = self
+                .internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+            let circuit = internal_state_lock
+                .circuits
+                .get(&circuit_id)
+                .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+            let hop = circuit.len() - 1;
+            internal_state_lock.resolutions.insert(stream_id, None);
+            hop
+        };
+        let resolve_payload = Payload::Resolve(ResolvePayload {
+            stream_id,
+            hostname: hostname.to_string(),
+        });
+        if let Err(e) = self.send_to_hop(circuit_id, hop, &resolve_payload) {
+            self.internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .resolutions
+                .remove(&stream_id);
+            return Err(e);
+        }
+
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let reason = loop {
+            if let Some(Some(_)) = internal_state_lock.resolutions.get(&stream_id) {
+                break None;
+            }
+            if !internal_state_lock.circuits.contains_key(&circuit_id) {
+                break Some(EndReason::Destroy);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Some(EndReason::Timeout);
+            }
+            internal_state_lock = self
+                .stream_updates
+                .wait_timeout(internal_state_lock, remaining)
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .0;
+        };
+        let addresses = internal_state_lock
+            .resolutions
+            .remove(&stream_id)
+            .flatten()
+            .unwrap_or_default();
+        drop(internal_state_lock);
+        let reason = reason.or_else(|| addresses.is_empty().then_some(EndReason::ResolveFailed));
+        if let Some(reason) = reason {
+            return Err(StreamClosedError { stream_id, reason })
+                .with_context(|| format!("Failed to resolve {}", hostname));
+        }
+        Logger::info(
+            &self.nickname,
+            format!("Resolved {} to {:?}", hostname, addresses),
+        );
+        Ok(addresses)
+    }
+
+    /// Opens a stream to `target` at the last hop of `circuit_id` and waits
+    /// until it is connected.
+    pub fn begin_stream_on(&self, circuit_id: CircuitId, target: BeginTarget) -> Result<StreamId> {
+        let relay_id = *self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+            .circuits
+            .get(&circuit_id)
+            .and_then(|circuit| circuit.first())
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        let stream_id = StreamId::new_v4();
+        self.send_begin_to(relay_id, circuit_id, stream_id, target)?;
+        self.wait_for_stream(stream_id, relay_id)?;
+        Ok(stream_id)
+    }
+
+    /// Waits for the answer to the BEGIN of `stream_id`, received from
+    /// `relay_id`, the first hop of its circuit. The stream is ended if no
+    /// answer comes back in time.
+    fn wait_for_stream(&self, stream_id: StreamId, relay_id: RelayId) -> Result<()> {
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        loop {
+            let stream = self
+                .internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .streams
+                .get(&stream_id)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Stream not found"))?;
+            match stream.status {
+                StreamStatus::Open => return Ok(()),
+                StreamStatus::Closed(reason) => {
+                    return Err(StreamClosedError { stream_id, reason }.into())
+                }
+                StreamStatus::Connecting => {}
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.end_stream(stream_id, EndReason::Timeout)?;
+                return Err(StreamClosedError {
+                    stream_id,
+                    reason: EndReason::Timeout,
+                }
+                .into());
+            }
+            // streams of the same circuit wait for the same events, so one
+            // may consume the answer meant for another: check again regularly
+            let circuit_id = stream.circuit_id;
+            self.events.wait_for_any(
+                &[
+                    Event(PayloadType::Connected, relay_id, circuit_id),
+                    Event(PayloadType::End, relay_id, circuit_id),
+                    Event(PayloadType::Destroy, relay_id, circuit_id),
+                ],
+                remaining.min(STREAM_POLL_INTERVAL),
+            );
+        }
+    }
+
+    /// Sends `data` on an open stream in cells of up to `MAX_DATA_LENGTH`
+    /// bytes, waiting for a SENDME first whenever the stream or its circuit
+    /// has no room left in its window.
+    pub fn send_stream_data(&self, stream_id: StreamId, data: Vec<u8>) -> Result<()> {
+        for chunk in data.chunks(MAX_DATA_LENGTH) {
+            self.send_stream_cell(stream_id, chunk.to_vec())?;
+        }
+        Ok(())
+    }
+
+    fn send_stream_cell(&self, stream_id: StreamId, data: Vec<u8>) -> Result<()> {
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        loop {
+            let state = &mut *internal_state_lock;
+            let stream = state
+                .streams
+                .get_mut(&stream_id)
+                .ok_or_else(|| anyhow::anyhow!("Stream not found"))?;
+            if stream.status != StreamStatus::Open {
+                return Err(anyhow::anyhow!(
+                    "Stream {} is not open: {:?}",
+                    stream_id,
+                    stream.status
+                ));
+            }
+            let circuit_window = state
+                .circuit_windows
+                .entry(stream.circuit_id)
+                .or_insert_with(FlowWindow::circuit);
+            if package_data_cell(circuit_window, &mut stream.window) {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(anyhow::anyhow!(
+                    "Timed out waiting for a SENDME on stream {}",
+                    stream_id
+                ));
+            }
+            internal_state_lock = self
+                .stream_updates
+                .wait_timeout(internal_state_lock, remaining)
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .0;
+        }
+        let stream = &internal_state_lock.streams[&stream_id];
+        let (circuit_id, hop) = (stream.circuit_id, stream.hop);
+        let length = data.len() as u64;
+        let data_payload = Payload::Data(DataPayload {
+            data,
+            stream_id: Some(stream_id),
+            rendezvous_cookie: None,
+            fragment: None,
+        });
+        internal_state_lock.send_to_hop(self.id, circuit_id, hop, &data_payload)?;
+        if let Some(stream) = internal_state_lock.streams.get_mut(&stream_id) {
+            stream.bytes_sent += length;
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as one message to the user joined by `rendezvous_cookie`,
+    /// over the circuit of the session.
+    pub fn send_message(&self, rendezvous_cookie: RendezvousCookieId, data: Vec<u8>) -> Result<()> {
+        let (relay_id, circuit_id) = {
+            let internal_state_lock = self
+                .internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+            if internal_state_lock
+                .ended_sessions
+                .contains(&rendezvous_cookie)
+            {
+                return Err(anyhow::anyhow!("Session {} was ended", rendezvous_cookie));
+            }
+            let circuit_id = *internal_state_lock
+                .session_circuits
+                .get(&rendezvous_cookie)
+                .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+            let relay_id = *internal_state_lock
+                .circuits
+                .get(&circuit_id)
+                .and_then(|circuit| circuit.first())
+                .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+            (relay_id, circuit_id)
+        };
+        self.send_data(relay_id, rendezvous_cookie, circuit_id, data)
+    }
+
+    /// Waits up to `timeout` for messages from the user joined by
+    /// `rendezvous_cookie`. Returns None once the session was ended and all
+    /// its messages were read, and no messages if nothing came in time.
+    pub fn recv_messages(
+        &self,
+        rendezvous_cookie: RendezvousCookieId,
+        timeout: Duration,
+    ) -> Result<Option<Vec<Vec<u8>>>> {
+        let deadline = Instant::now() + timeout;
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        loop {
+            if let Some(messages) = internal_state_lock.messages.remove(&rendezvous_cookie) {
+                return Ok(Some(messages));
+            }
+            if internal_state_lock
+                .ended_sessions
+                .contains(&rendezvous_cookie)
+            {
+                return Ok(None);
+            }
+            if !internal_state_lock
+                .session_circuits
+                .contains_key(&rendezvous_cookie)
+            {
+                return Err(anyhow::anyhow!("Session not found"));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(Some(vec![]));
+            }
+            internal_state_lock = self
+                .stream_updates
+                .wait_timeout(internal_state_lock, remaining)
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .0;
+        }
+    }
+
+    /// Ends the session with the user joined by `rendezvous_cookie` with an
+    /// END carrying the cookie, which the rendezvous point passes on.
+    pub fn end_session(&self, rendezvous_cookie: RendezvousCookieId) -> Result<()> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let circuit_id = *internal_state_lock
+            .session_circuits
+            .get(&rendezvous_cookie)
+            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
+        if !internal_state_lock.ended_sessions.insert(rendezvous_cookie) {
+            return Err(anyhow::anyhow!(
+                "Session {} was already ended",
+                rendezvous_cookie
+            ));
+        }
+        internal_state_lock
+            .connected_users
+            .remove(&rendezvous_cookie);
+        let hop = internal_state_lock
+            .circuits
+            .get(&circuit_id)
+            .map_or(0, |circuit| circuit.len().saturating_sub(1));
+        internal_state_lock.send_to_hop(
+            self.id,
+            circuit_id,
+            hop,
+            &Payload::End(EndPayload {
+                stream_id: rendezvous_cookie,
+                reason: EndReason::Done,
+            }),
+        )?;
+        self.stream_updates.notify_all();
+        Logger::info(
+            &self.nickname,
+            format!("Ended session {}", rendezvous_cookie),
+        );
+        Ok(())
+    }
+
+    /// Connects to `host:port` through an exit like `connect`, returning the
+    /// stream as a byte stream for async code. Blocks until the stream is
+    /// open.
+    pub fn open_stream(
+        &self,
+        isolation: IsolationKey,
+        host: &str,
+        port: u16,
+    ) -> Result<DataStream> {
+        let (stream_id, _) = self.connect(isolation, host, port)?;
+        Ok(DataStream::new(
+            self.clone(),
+            DataStreamEndpoint::Exit(stream_id),
+        ))
+    }
+
+    /// Returns the session with the user joined by `rendezvous_cookie` as a
+    /// byte stream for async code.
+    pub fn open_session_stream(&self, rendezvous_cookie: RendezvousCookieId) -> Result<DataStream> {
+        let internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        if !internal_state_lock
+            .session_circuits
+            .contains_key(&rendezvous_cookie)
+        {
+            return Err(anyhow::anyhow!("Session not found"));
+        }
+        drop(internal_state_lock);
+        Ok(DataStream::new(
+            self.clone(),
+            DataStreamEndpoint::Session(rendezvous_cookie),
+        ))
+    }
+
+    /// Returns the messages received from the user joined by
+    /// `rendezvous_cookie` since the last read.
+    pub fn read_messages(&self, rendezvous_cookie: RendezvousCookieId) -> Result<Vec<Vec<u8>>> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        Ok(internal_state_lock
+            .messages
+            .remove(&rendezvous_cookie)
+            .unwrap_or_default())
+    }
+
+    /// Returns the data received on `stream_id` since the last read. Data
+    /// received before the stream was closed can still be read.
+    pub fn read_stream(&self, stream_id: StreamId) -> Result<Vec<u8>> {
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let stream = internal_state_lock
+            .streams
+            .get_mut(&stream_id)
+            .ok_or_else(|| anyhow::anyhow!("Stream not found"))?;
+        let data = std::mem::take(&mut stream.buffer);
+        internal_state_lock.acknowledge_stream(&self.nickname, self.id, stream_id);
+        Ok(data)
+    }
+
+    /// Waits up to `timeout` for data on `stream_id`. Returns None once the
+    /// stream is closed and everything it received was read, and no data if
+    /// nothing came in time.
+    pub fn recv_stream(&self, stream_id: StreamId, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        let mut internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        loop {
+            let stream = internal_state_lock
+                .streams
+                .get_mut(&stream_id)
+                .ok_or_else(|| anyhow::anyhow!("Stream not found"))?;
+            if !stream.buffer.is_empty() {
+                let data = std::mem::take(&mut stream.buffer);
+                internal_state_lock.acknowledge_stream(&self.nickname, self.id, stream_id);
+                return Ok(Some(data));
+            }
+            if stream.is_closed() {
+                return Ok(None);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(Some(vec![]));
+            }
+            internal_state_lock = self
+                .stream_updates
+                .wait_timeout(internal_state_lock, remaining)
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?
+                .0;
+        }
+    }
+
+    /// Accepts SOCKS5 clients on `address`, carrying each of their
+    /// connections over a stream. Returns the address listened on.
+    pub fn start_socks_proxy(&self, address: SocketAddr) -> Result<SocketAddr> {
+        self.start_proxy(address, "SOCKS", serve_socks_client)
+    }
+
+    /// Accepts HTTP CONNECT clients on `address`, carrying each of their
+    /// tunnels over a stream. Returns the address listened on.
+    pub fn start_http_proxy(&self, address: SocketAddr) -> Result<SocketAddr> {
+        self.start_proxy(address, "HTTP", serve_http_client)
+    }
+
+    /// Listens on `address` and serves every client on its own thread.
+    fn start_proxy(
+        &self,
+        address: SocketAddr,
+        protocol: &'static str,
+        serve: fn(User, TcpStream) -> Result<()>,
+    ) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("Failed to bind {} proxy", protocol))?;
+        let address = listener
+            .local_addr()
+            .with_context(|| format!("Failed to get {} proxy address", protocol))?;
+        Logger::info(
+            &self.nickname,
+            format!("{} proxy listening on {}", protocol, address),
+        );
+        let user = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        Logger::warn(
+                            &user.nickname,
+                            format!("Failed to accept {} client: {}", protocol, e),
+                        );
+                        continue;
+                    }
+                };
+                let user = user.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(user.clone(), client) {
+                        Logger::warn(
+                            &user.nickname,
+                            format!("{} client failed: {:#}", protocol, e),
+                        );
+                    }
+                });
+            }
+        });
+        Ok(address)
+    }
+
+    /// Closes `stream_id` and sends END with `reason` to the hop it was
+    /// opened at. The circuit stays open for its other streams.
+    pub fn end_stream(&self, stream_id: StreamId, reason: EndReason) -> Result<()> {
+        let (circuit_id, hop) = {
+            let mut internal_state_lock = self
+                .internal_state
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+            let stream = internal_state_lock
+                .streams
+                .get_mut(&stream_id)
+                .ok_or_else(|| anyhow::anyhow!("Stream not found"))?;
+            if !stream.close(reason) {
+                return Err(anyhow::anyhow!("Stream {} is already closed", stream_id));
+            }
+            (stream.circuit_id, stream.hop)
+        };
+        self.send_to_hop(
+            circuit_id,
+            hop,
+            &Payload::End(EndPayload { stream_id, reason }),
+        )?;
+        Logger::info(
+            &self.nickname,
+            format!("Ended stream {} with reason {:?}", stream_id, reason),
+        );
         Ok(())
     }
 
@@ -645,6 +3028,25 @@ impl User {
         circuit_id: CircuitId,
         stream_id: StreamId,
         begin_relay_id: RelayId,
+    ) -> Result<()> {
+        let relay_descriptor =
+            Directory::get_relay(begin_relay_id).context("Failed to get relay from directory")?;
+        self.send_begin_to(
+            relay_id,
+            circuit_id,
+            stream_id,
+            BeginTarget::Relay(relay_descriptor),
+        )
+    }
+
+    /// Sends a BEGIN for `target` to the last hop of `circuit_id`, without
+    /// waiting for the answer.
+    pub fn send_begin_to(
+        &self,
+        relay_id: RelayId,
+        circuit_id: CircuitId,
+        stream_id: StreamId,
+        target: BeginTarget,
     ) -> Result<()> {
         let mut internal_state_lock = self
             .internal_state
@@ -654,18 +3056,18 @@ impl User {
             &self.nickname,
             format!("Sending BEGIN to relay {}", relay_id),
         );
-        let relay_descriptor =
-            Directory::get_relay(begin_relay_id).context("Failed to get relay from directory")?;
-        let begin_payload = Payload::Begin(crate::BeginPayload {
-            stream_id,
-            relay_descriptor,
-        });
+        let begin_relay_id = match &target {
+            BeginTarget::Relay(relay_descriptor) => Some(relay_descriptor.id),
+            BeginTarget::Host { .. } => None,
+        };
+        let begin_payload = Payload::Begin(crate::BeginPayload { stream_id, target });
         let circuit = internal_state_lock
             .circuits
             .get(&circuit_id)
-            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?
+            .clone();
         let mut handshakes = vec![];
-        for relay in circuit {
+        for relay in circuit.iter() {
             handshakes.push(
                 internal_state_lock
                     .handshakes
@@ -691,11 +3093,19 @@ impl User {
             circuit_id,
             payload: buffer,
         };
-        Communication::send(self.user_descriptor.id, relay_id, relay_cell)
-            .context("Failed to send communication")?;
+        // registered first, CONNECTED could come back before we get to it
         internal_state_lock
-            .stream_ids
-            .insert(stream_id, begin_relay_id);
+            .streams
+            .insert(stream_id, StreamInfo::new(circuit_id, circuit.len() - 1));
+        if let Err(e) = Communication::send(self.user_descriptor.id, relay_id, relay_cell) {
+            internal_state_lock.streams.remove(&stream_id);
+            return Err(e).context("Failed to send communication");
+        }
+        if let Some(begin_relay_id) = begin_relay_id {
+            internal_state_lock
+                .stream_ids
+                .insert(stream_id, begin_relay_id);
+        }
         Logger::info(
             &self.nickname,
             format!("Sent BEGIN payload to relay at address: {}", relay_id),
@@ -703,15 +3113,80 @@ impl User {
         Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn send_introduce1(
+    pub fn send_introduce1(
+        &self,
+        relay_id: RelayId,
+        introduction: IntroductionAuth,
+        stream_id: StreamId,
+        rendezvous_cookie: RendezvousCookieId,
+        introduction_rsa_public: Vec<u8>,
+        circuit_id: CircuitId,
+    ) -> Result<()> {
+        let introduce1_payload = self.introduce1_payload(
+            &introduction,
+            stream_id,
+            rendezvous_cookie,
+            &introduction_rsa_public,
+            circuit_id,
+        )?;
+        self.send_introduce1_payload(relay_id, circuit_id, introduce1_payload)
+    }
+
+    /// INTRODUCE1 asking the service with `introduction_rsa_public` to meet
+    /// at the last hop of `circuit_id`, authenticated with the key of the
+    /// introduction point.
+    fn introduce1_payload(
+        &self,
+        introduction: &IntroductionAuth,
+        stream_id: StreamId,
+        rendezvous_cookie: RendezvousCookieId,
+        introduction_rsa_public: &[u8],
+        circuit_id: CircuitId,
+    ) -> Result<Introduce1Payload> {
+        let internal_state_lock = self
+            .internal_state
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
+        let rsa_public = Rsa::public_key_from_pem(introduction_rsa_public)
+            .context("Failed to parse RSA public key")?;
+        let half_dh_bytes: Vec<u8> = internal_state_lock
+            .keys
+            .dh
+            .public_key()
+            .to_vec_padded(256)?;
+        let aes = generate_random_aes_key();
+        let onion_skin = OnionSkin::new(rsa_public, aes, half_dh_bytes.try_into().unwrap())
+            .context("Failed to create onion skin")?;
+        // the rendezvous point is the last hop of the circuit
+        let rendezvous_point = *internal_state_lock
+            .circuits
+            .get(&circuit_id)
+            .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?
+            .last()
+            .ok_or_else(|| anyhow::anyhow!("Circuit is empty"))?;
+        let input = IntroductionMacInput {
+            introduction_id: introduction.introduction_id,
+            rendezvous_cookie,
+            rendezvous_point,
+            onion_skin: &onion_skin,
+        };
+        let mac = introduction_mac(&introduction.introduction_key, &input)
+            .context("Failed to authenticate introduce1 payload")?;
+        Ok(Introduce1Payload {
+            stream_id,
+            introduction_id: introduction.introduction_id,
+            rendezvous_cookie,
+            rendezvous_point,
+            onion_skin,
+            mac,
+        })
+    }
+
+    fn send_introduce1_payload(
         &self,
         relay_id: RelayId,
-        introduction_id: IntroductionPointId,
-        stream_id: StreamId,
-        rendezvous_cookie: RendezvousCookieId,
-        introduction_rsa_public: Vec<u8>,
         circuit_id: CircuitId,
+        introduce1_payload: Introduce1Payload,
     ) -> Result<()> {
         let internal_state_lock = self
             .internal_state
@@ -721,23 +3196,11 @@ impl User {
             &self.nickname,
             format!("Sending INTRODUCE1 to relay {}", relay_id),
         );
-        let rsa_public = Rsa::public_key_from_pem(&introduction_rsa_public)
-            .context("Failed to parse RSA public key")?;
-        let half_dh_bytes: Vec<u8> = internal_state_lock.keys.dh.public_key().to_vec();
-        let aes = generate_random_aes_key();
-        let onion_skin = OnionSkin::new(rsa_public, aes, half_dh_bytes.try_into().unwrap())
-            .context("Failed to create onion skin")?;
-        let introduce1_payload = Introduce1Payload {
-            stream_id,
-            introduction_id,
-            rendezvous_cookie,
-            onion_skin,
-        };
-        let introduce1_payload = Payload::Introduce1(introduce1_payload);
         let circuit = internal_state_lock
             .circuits
             .get(&circuit_id)
             .ok_or_else(|| anyhow::anyhow!("Circuit not found"))?;
+        let introduce1_payload = Payload::Introduce1(introduce1_payload);
         let mut handshakes = vec![];
         for relay in circuit {
             handshakes.push(
@@ -772,7 +3235,7 @@ impl User {
             format!("Sent INTRODUCE1 payload to relay {}", relay_id),
         );
         drop(internal_state_lock);
-        self.listen_for_event(Event(PayloadType::IntroduceAck, relay_id))?;
+        self.listen_for_event(Event(PayloadType::IntroduceAck, relay_id, circuit_id))?;
         Ok(())
     }
 
@@ -782,7 +3245,7 @@ impl User {
         rendezvous_cookie: RendezvousCookieId,
         circuit_id: CircuitId,
     ) -> Result<()> {
-        let internal_state_lock = self
+        let mut internal_state_lock = self
             .internal_state
             .lock()
             .map_err(|e| anyhow::anyhow!("Failed to lock internal state: {}", e))?;
@@ -831,6 +3294,1384 @@ impl User {
             &self.nickname,
             format!("Sent RENDEZVOUS1 payload to relay {}", relay_id),
         );
+        internal_state_lock
+            .session_circuits
+            .insert(rendezvous_cookie, circuit_id);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        echo, relay_ids, start_relays, start_tcp_server, start_user, test_resolver,
+    };
+    use crate::{ExitPolicy, GuardEntry, GuardReachability, Relay, CIRCUIT_WINDOW_START};
+    use std::net::Ipv4Addr;
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..600 {
+            if condition() {
+                return;
+            }
+            thread::sleep(std::time::Duration::from_millis(50));
+        }
+        panic!("condition not met in time");
+    }
+
+    #[test]
+    fn test_establish_variable_length_circuits() {
+        let relays = relay_ids(&start_relays(5));
+        let user = start_user("TestUser");
+
+        let one_hop = CircuitId::new_v4();
+        user.establish_circuit(one_hop, relays[..1].to_vec())
+            .unwrap();
+        let five_hops = CircuitId::new_v4();
+        user.establish_circuit(five_hops, relays.clone()).unwrap();
+
+        let state = user.get_state();
+        assert_eq!(state.circuits[&one_hop], relays[..1].to_vec());
+        assert_eq!(state.circuits[&five_hops], relays);
+        assert_eq!(state.circuit_build.succeeded, 2);
+        assert_eq!(state.circuit_build.observed_build_times, 2);
+    }
+
+    #[test]
+    fn test_establish_circuit_rejects_invalid_path() {
+        let relays = relay_ids(&start_relays(2));
+        let user = start_user("TestUser");
+        assert!(user.establish_circuit(CircuitId::new_v4(), vec![]).is_err());
+        assert!(user
+            .establish_circuit(CircuitId::new_v4(), vec![relays[0], relays[1], relays[0]])
+            .is_err());
+    }
+
+    #[test]
+    fn test_circuit_pool_hands_out_ready_circuits() {
+        start_relays(3);
+        let user = start_user("TestUser");
+        user.start_circuit_pool(CircuitPoolConfig {
+            targets: HashMap::from([(CircuitPurpose::General, 1)]),
+            max_circuit_age: std::time::Duration::from_secs(60),
+            check_interval: std::time::Duration::from_millis(50),
+        });
+        let ready = |user: &User| {
+            user.get_state()
+                .circuit_pool
+                .get(&CircuitPurpose::General)
+                .cloned()
+                .unwrap_or_default()
+        };
+        wait_until(|| ready(&user).len() == 1);
+        let pooled = ready(&user)[0];
+        assert_eq!(user.take_circuit(CircuitPurpose::General).unwrap(), pooled);
+        wait_until(|| ready(&user).len() == 1);
+        assert_ne!(ready(&user)[0], pooled);
+        user.stop_circuit_pool();
+    }
+
+    #[test]
+    fn test_onion_service_circuits_taken_from_pool() {
+        start_relays(6);
+        let pool = |purpose| CircuitPoolConfig {
+            targets: HashMap::from([(purpose, 1)]),
+            max_circuit_age: std::time::Duration::from_secs(60),
+            check_interval: std::time::Duration::from_millis(50),
+        };
+        let ready = |user: &User, purpose| {
+            user.get_state()
+                .circuit_pool
+                .get(&purpose)
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        let service_user = start_user("TestOnionService");
+        service_user.start_circuit_pool(pool(CircuitPurpose::Introduction));
+        wait_until(|| ready(&service_user, CircuitPurpose::Introduction).len() == 1);
+        let pooled = ready(&service_user, CircuitPurpose::Introduction)[0];
+        let service = service_user
+            .host_onion_service(OnionServiceConfig {
+                introduction_points: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        let address = service.address();
+        let introduction_relay = *Directory::get_user(address)
+            .unwrap()
+            .introduction_points
+            .values()
+            .next()
+            .unwrap();
+        assert_eq!(
+            service_user.get_state().circuits[&pooled].last(),
+            Some(&introduction_relay)
+        );
+
+        let client = start_user("TestOnionClient");
+        client.start_circuit_pool(pool(CircuitPurpose::Rendezvous));
+        wait_until(|| ready(&client, CircuitPurpose::Rendezvous).len() == 1);
+        let pooled = ready(&client, CircuitPurpose::Rendezvous)[0];
+        // the rendezvous point may not be the introduction point
+        let suitable = client.get_state().circuits[&pooled].last() != Some(&introduction_relay);
+        let _connection = client.connect_onion(address).unwrap();
+        assert_eq!(
+            ready(&client, CircuitPurpose::Rendezvous).contains(&pooled),
+            !suitable
+        );
+        client.stop_circuit_pool();
+        service_user.stop_circuit_pool();
+    }
+
+    #[test]
+    fn test_destroy_circuit_from_user() {
+        let relays = start_relays(3);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relay_ids(&relays))
+            .unwrap();
+        assert!(relays.iter().all(|r| !r.get_state().circuits.is_empty()));
+
+        user.destroy_circuit(circuit_id, DestroyReason::Requested)
+            .unwrap();
+        assert!(!user.get_state().circuits.contains_key(&circuit_id));
+        wait_until(|| relays.iter().all(|r| r.get_state().circuits.is_empty()));
+    }
+
+    #[test]
+    fn test_destroy_circuit_from_middle_relay() {
+        let relays = start_relays(3);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relay_ids(&relays))
+            .unwrap();
+
+        let middle_circuit_id = *relays[1].get_state().circuits.keys().next().unwrap();
+        relays[1].destroy_circuit(middle_circuit_id, DestroyReason::Destroyed);
+        wait_until(|| relays.iter().all(|r| r.get_state().circuits.is_empty()));
+        wait_until(|| !user.get_state().circuits.contains_key(&circuit_id));
+    }
+
+    #[test]
+    fn test_destroy_from_outside_the_circuit_is_ignored() {
+        let relays = start_relays(3);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relay_ids(&relays))
+            .unwrap();
+
+        let stranger = UserId::new_v4();
+        let _receiver = Communication::register(stranger);
+        let destroy = |circuit_id| RelayCell {
+            circuit_id,
+            payload: serde_json::to_vec(&Payload::Destroy(DestroyPayload {
+                reason: DestroyReason::Requested,
+            }))
+            .unwrap(),
+        };
+        let middle_circuit_id = *relays[1].get_state().circuits.keys().next().unwrap();
+        let relay_id = relays[1].get_relay_descriptor().id;
+        Communication::send(stranger, relay_id, destroy(middle_circuit_id)).unwrap();
+        Communication::send(stranger, user.user_descriptor.id, destroy(circuit_id)).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(relays.iter().all(|r| !r.get_state().circuits.is_empty()));
+        assert!(user.get_state().circuits.contains_key(&circuit_id));
+        Communication::unregister(stranger);
+    }
+
+    #[test]
+    fn test_relay_survives_an_unreachable_user() {
+        let relays = start_relays(2);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relay_ids(&relays))
+            .unwrap();
+
+        // the reply to ESTABLISH_RENDEZVOUS can no longer be delivered
+        Communication::unregister(user.user_descriptor.id);
+        user.send_to_hop(
+            circuit_id,
+            1,
+            &Payload::EstablishRendezvous(EstablishRendezvousPayload {
+                rendezvous_cookie: RendezvousCookieId::new_v4(),
+            }),
+        )
+        .unwrap();
+        wait_until(|| relays.iter().all(|r| r.get_state().circuits.is_empty()));
+
+        let other = start_user("TestOtherUser");
+        other
+            .establish_circuit(CircuitId::new_v4(), relay_ids(&relays))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_truncate_and_extend_circuit() {
+        let relays = start_relays(4);
+        let ids = relay_ids(&relays);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, ids[..3].to_vec())
+            .unwrap();
+        assert!(user.truncate_circuit(circuit_id, 2).is_err());
+
+        user.truncate_circuit(circuit_id, 1).unwrap();
+        assert_eq!(user.get_state().circuits[&circuit_id], ids[..2].to_vec());
+        wait_until(|| relays[2].get_state().circuits.is_empty());
+
+        user.send_extend(ids[0], ids[3], circuit_id).unwrap();
+        assert_eq!(
+            user.get_state().circuits[&circuit_id],
+            vec![ids[0], ids[1], ids[3]]
+        );
+    }
+
+    #[test]
+    fn test_truncated_when_next_hop_fails() {
+        let mut relays = start_relays(3);
+        // never picked by path selection in other tests once it is stopped
+        let mut failing = Relay::new("TestFailingRelay".to_string());
+        failing.set_flags(vec![]);
+        failing.start();
+        relays.push(failing);
+        let ids = relay_ids(&relays);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, vec![ids[0], ids[1], ids[3]])
+            .unwrap();
+
+        relays[3].stop();
+        assert!(user.send_extend(ids[0], ids[2], circuit_id).is_err());
+        assert_eq!(user.get_state().circuits[&circuit_id], ids[..2].to_vec());
+
+        user.send_extend(ids[0], ids[2], circuit_id).unwrap();
+        assert_eq!(user.get_state().circuits[&circuit_id], ids[..3].to_vec());
+    }
+
+    #[test]
+    fn test_circuit_build_times_out() {
+        let relays = start_relays(2);
+        let mut silent = Relay::new("TestSilentRelay".to_string());
+        silent.set_flags(vec![]);
+        // published and reachable, but never answers
+        Directory::publish_relay(silent.get_relay_descriptor());
+        let _receiver = Communication::register(silent.get_relay_descriptor().id);
+        let mut path = relay_ids(&relays);
+        path.push(silent.get_relay_descriptor().id);
+
+        let user = start_user("TestUser");
+        user.set_circuit_build_timeout(Some(Duration::from_millis(500)));
+        let circuit_id = CircuitId::new_v4();
+        let started = Instant::now();
+        assert!(user.establish_circuit(circuit_id, path).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let state = user.get_state();
+        assert_eq!(state.circuit_build.timed_out, 1);
+        assert_eq!(state.circuit_build.timeout_ms, 500);
+        assert!(!state.circuits.contains_key(&circuit_id));
+        wait_until(|| relays.iter().all(|r| r.get_state().circuits.is_empty()));
+    }
+
+    #[test]
+    fn test_build_circuit_retries_on_a_new_path() {
+        start_relays(3);
+        // only ever picked as a guard, and fails fast once stopped
+        let mut unreachable = Relay::new("TestUnreachableGuard".to_string());
+        unreachable.set_flags(vec![RelayFlag::Guard]);
+        unreachable.start();
+        unreachable.stop();
+        let unreachable_id = unreachable.get_relay_descriptor().id;
+
+        let guards = GuardSet {
+            sampled: vec![GuardEntry {
+                relay_id: unreachable_id,
+                reachability: GuardReachability::Unknown,
+                failures: 0,
+                last_failure: None,
+            }],
+            confirmed: vec![],
+            primary: vec![unreachable_id],
+        };
+        let guard_state_path =
+            std::env::temp_dir().join(format!("guards-{}.json", uuid::Uuid::new_v4()));
+        guards.save(&guard_state_path).unwrap();
+        let user = start_user("TestUser");
+        user.set_guard_state_path(guard_state_path.clone()).unwrap();
+
+        let (_, path) = user.build_circuit(CircuitPurpose::General).unwrap();
+        std::fs::remove_file(&guard_state_path).unwrap();
+        assert_ne!(path[0], unreachable_id);
+        let state = user.get_state();
+        assert_eq!(state.circuit_build.retries, 1);
+        assert_eq!(state.circuit_build.failed, 1);
+        assert_eq!(state.circuit_build.succeeded, 1);
+        assert_eq!(
+            state.guards.get_guard(unreachable_id).unwrap().reachability,
+            GuardReachability::Unreachable
+        );
+    }
+
+    #[test]
+    fn test_streams_share_circuits_by_isolation_key() {
+        let relays = relay_ids(&start_relays(3));
+        let user = start_user("TestUser");
+        let persona = |session: &str| IsolationKey {
+            session: Some(session.to_string()),
+            ..Default::default()
+        };
+
+        let (_, alice) = user.begin_stream(persona("alice"), relays[0]).unwrap();
+        let (_, alice_again) = user.begin_stream(persona("alice"), relays[1]).unwrap();
+        let (_, bob) = user.begin_stream(persona("bob"), relays[0]).unwrap();
+        assert_eq!(alice, alice_again);
+        assert_ne!(alice, bob);
+
+        let grouped = IsolationKey {
+            group: Some("work".to_string()),
+            ..persona("alice")
+        };
+        assert_ne!(user.circuit_for_stream(&grouped).unwrap(), alice);
+
+        user.set_isolation_policy(IsolationPolicy {
+            destination: true,
+            ..Default::default()
+        });
+        let (_, alice_elsewhere) = user.begin_stream(persona("alice"), relays[2]).unwrap();
+        assert_ne!(alice_elsewhere, alice);
+        assert_eq!(user.get_state().circuit_isolation.len(), 4);
+    }
+
+    #[test]
+    fn test_destroyed_circuit_is_not_reused_for_streams() {
+        start_relays(3);
+        let user = start_user("TestUser");
+        let isolation = IsolationKey::default();
+        let circuit_id = user.circuit_for_stream(&isolation).unwrap();
+        user.destroy_circuit(circuit_id, DestroyReason::Finished)
+            .unwrap();
+        assert_ne!(user.circuit_for_stream(&isolation).unwrap(), circuit_id);
+    }
+
+    #[test]
+    fn test_cells_addressed_to_a_middle_hop() {
+        let relays: Vec<Relay> = (0..3)
+            .map(|i| {
+                let relay = Relay::new(format!("TestLeakyPipeRelay{}", i));
+                relay.start();
+                relay
+            })
+            .collect();
+        let ids = relay_ids(&relays);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, ids.clone()).unwrap();
+        assert!(user.send_drop(circuit_id, 3).is_err());
+
+        let stream_id = StreamId::new_v4();
+        let begin_payload = Payload::Begin(crate::BeginPayload {
+            stream_id,
+            target: BeginTarget::Relay(relays[2].get_relay_descriptor()),
+        });
+        let first_hop = user.send_to_hop(circuit_id, 1, &begin_payload).unwrap();
+        user.listen_for_event(Event(PayloadType::Connected, first_hop, circuit_id))
+            .unwrap();
+        assert!(relays[1].get_state().streams.contains_key(&stream_id));
+        assert!(relays[2].get_state().streams.is_empty());
+
+        user.send_drop(circuit_id, 1).unwrap();
+        let dropped = |relay: usize| {
+            Logger::get_logs(format!("TestLeakyPipeRelay{}", relay))
+                .iter()
+                .any(|log| log.contains("Dropped a padding cell"))
+        };
+        wait_until(|| dropped(1));
+        assert!(!dropped(2));
+        assert_eq!(user.get_state().circuits[&circuit_id], ids);
+    }
+
+    #[test]
+    fn test_streams_multiplexed_on_one_circuit() {
+        let relays = start_relays(3);
+        let ids = relay_ids(&relays);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, ids.clone()).unwrap();
+
+        let streams: Vec<StreamId> = (0..4).map(|_| StreamId::new_v4()).collect();
+        for stream_id in streams.iter() {
+            user.send_begin(ids[0], circuit_id, *stream_id, ids[0])
+                .unwrap();
+        }
+        for stream_id in streams.iter() {
+            user.wait_for_stream(*stream_id, ids[0]).unwrap();
+        }
+        let exit_circuits = relays[2].get_state().stream_circuits;
+        let exit_circuit = exit_circuits[&streams[0]];
+        assert!(streams
+            .iter()
+            .all(|stream_id| exit_circuits[stream_id] == exit_circuit));
+
+        for (i, stream_id) in streams.iter().enumerate() {
+            user.send_stream_data(*stream_id, vec![0; i + 1]).unwrap();
+        }
+        for (i, stream_id) in streams.iter().enumerate() {
+            let expected = format!("Received {} bytes on stream {}", i + 1, stream_id);
+            wait_until(|| {
+                Logger::get_logs("TestRelay2".to_string())
+                    .iter()
+                    .any(|log| log.contains(&expected))
+            });
+            assert_eq!(
+                user.get_state().stream_info[stream_id].bytes_sent,
+                i as u64 + 1
+            );
+        }
+
+        // a BEGIN reusing an open stream id is refused
+        user.send_begin(ids[0], circuit_id, streams[3], ids[0])
+            .unwrap();
+        assert!(user.wait_for_stream(streams[3], ids[0]).is_err());
+        assert_eq!(
+            user.get_state().stream_info[&streams[3]].status,
+            StreamStatus::Closed(EndReason::Protocol)
+        );
+
+        user.end_stream(streams[0], EndReason::Done).unwrap();
+        wait_until(|| !relays[2].get_state().streams.contains_key(&streams[0]));
+        assert!(user.send_stream_data(streams[0], vec![0]).is_err());
+        assert!(user.end_stream(streams[0], EndReason::Done).is_err());
+
+        relays[2].end_stream(streams[1], EndReason::ConnReset);
+        wait_until(|| {
+            user.get_state().stream_info[&streams[1]].status
+                == StreamStatus::Closed(EndReason::ConnReset)
+        });
+        assert!(relays[2].get_state().streams.contains_key(&streams[2]));
+
+        user.destroy_circuit(circuit_id, DestroyReason::Finished)
+            .unwrap();
+        assert_eq!(
+            user.get_state().stream_info[&streams[2]].status,
+            StreamStatus::Closed(EndReason::Destroy)
+        );
+        wait_until(|| relays[2].get_state().stream_circuits.is_empty());
+    }
+
+    #[test]
+    fn test_stream_through_exit_to_echo_server() {
+        let relays = start_relays(3);
+        let ids = relay_ids(&relays);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, ids.clone()).unwrap();
+        let port = start_tcp_server(echo);
+        let target = BeginTarget::Host {
+            host: "127.0.0.1".to_string(),
+            port,
+        };
+        let stream_id = user.begin_stream_on(circuit_id, target).unwrap();
+        assert_eq!(
+            relays[2].get_state().exit_streams[&stream_id],
+            format!("127.0.0.1:{}", port)
+        );
+
+        // bigger than a DATA cell, so it comes back in several
+        let message: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        user.send_stream_data(stream_id, message.clone()).unwrap();
+        let received = Mutex::new(vec![]);
+        wait_until(|| {
+            let mut received = received.lock().unwrap();
+            received.extend(user.read_stream(stream_id).unwrap());
+            received.len() >= message.len()
+        });
+        assert_eq!(received.into_inner().unwrap(), message);
+        let stream = &user.get_state().stream_info[&stream_id];
+        assert_eq!(stream.bytes_sent, 2000);
+        assert_eq!(stream.bytes_received, 2000);
+
+        user.end_stream(stream_id, EndReason::Done).unwrap();
+        wait_until(|| relays[2].get_state().exit_streams.is_empty());
+    }
+
+    #[test]
+    fn test_exit_stream_ends() {
+        let relays = relay_ids(&start_relays(3));
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relays.clone()).unwrap();
+        let host = |port| BeginTarget::Host {
+            host: "127.0.0.1".to_string(),
+            port,
+        };
+
+        let port = start_tcp_server(|mut socket| {
+            std::io::Write::write_all(&mut socket, b"bye").unwrap();
+        });
+        let stream_id = user.begin_stream_on(circuit_id, host(port)).unwrap();
+        wait_until(|| {
+            user.get_state().stream_info[&stream_id].status == StreamStatus::Closed(EndReason::Done)
+        });
+        assert_eq!(user.read_stream(stream_id).unwrap(), b"bye");
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_port = listener.local_addr().unwrap().port();
+        drop(listener);
+        assert!(user.begin_stream_on(circuit_id, host(closed_port)).is_err());
+        assert!(user
+            .get_state()
+            .stream_info
+            .values()
+            .any(|stream| stream.status == StreamStatus::Closed(EndReason::ConnectRefused)));
+
+        // not an exit, and without flags never picked by other tests
+        let mut non_exit = Relay::new("TestNonExitRelay".to_string());
+        non_exit.set_flags(vec![]);
+        non_exit.start();
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(
+            circuit_id,
+            vec![relays[0], relays[1], non_exit.get_relay_descriptor().id],
+        )
+        .unwrap();
+        let port = start_tcp_server(echo);
+        let error = user.begin_stream_on(circuit_id, host(port)).unwrap_err();
+        assert!(error.to_string().contains("ExitPolicy"));
+    }
+
+    #[test]
+    fn test_exit_policy_enforced_and_used_to_pick_exits() {
+        let relays = relay_ids(&start_relays(3));
+        let user = start_user("TestUser");
+        let port = start_tcp_server(echo);
+        let mut rejecting = Relay::new_exit(
+            "TestRejectingExit".to_string(),
+            ExitPolicy::parse(&["reject 127.0.0.0/8:*"]).unwrap(),
+        );
+        rejecting.set_resolver(test_resolver());
+        rejecting.set_flags(vec![RelayFlag::Exit]);
+        rejecting.start();
+        let rejecting_id = rejecting.get_relay_descriptor().id;
+
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, vec![relays[0], relays[1], rejecting_id])
+            .unwrap();
+        let target = BeginTarget::Host {
+            host: "127.0.0.1".to_string(),
+            port,
+        };
+        let error = user.begin_stream_on(circuit_id, target).unwrap_err();
+        assert!(error.to_string().contains("ExitPolicy"));
+
+        // the circuit is not reused for a destination its exit rejects
+        let isolation = IsolationKey {
+            session: Some("exit policy".to_string()),
+            ..Default::default()
+        };
+        user.internal_state
+            .lock()
+            .unwrap()
+            .circuit_isolation
+            .insert(circuit_id, isolation.isolated(&IsolationPolicy::default()));
+        let (stream_id, stream_circuit) = user.connect(isolation, "127.0.0.1", port).unwrap();
+        assert_ne!(stream_circuit, circuit_id);
+        assert_ne!(
+            user.get_state().circuits[&stream_circuit].last(),
+            Some(&rejecting_id)
+        );
+        user.send_stream_data(stream_id, b"ping".to_vec()).unwrap();
+        wait_until(|| user.get_state().stream_info[&stream_id].bytes_received == 4);
+    }
+
+    #[test]
+    fn test_resolve_at_exit() {
+        let relays = relay_ids(&start_relays(2));
+        let mut exit = Relay::new_exit("TestResolvingExit".to_string(), ExitPolicy::accept_all());
+        exit.set_resolver(test_resolver());
+        exit.set_flags(vec![RelayFlag::Exit]);
+        exit.start();
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(
+            circuit_id,
+            vec![relays[0], relays[1], exit.get_relay_descriptor().id],
+        )
+        .unwrap();
+
+        assert_eq!(
+            user.resolve_on(circuit_id, "service.test").unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+        );
+        let error = user.resolve_on(circuit_id, "unknown.test").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<StreamClosedError>().unwrap().reason,
+            EndReason::ResolveFailed
+        );
+
+        // streams to names are resolved the same way
+        let port = start_tcp_server(echo);
+        let stream_id = user
+            .begin_stream_on(
+                circuit_id,
+                BeginTarget::Host {
+                    host: "service.test".to_string(),
+                    port,
+                },
+            )
+            .unwrap();
+        user.send_stream_data(stream_id, b"hello".to_vec()).unwrap();
+        assert_eq!(
+            user.recv_stream(stream_id, Duration::from_secs(10))
+                .unwrap()
+                .unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_exit_waits_for_stream_sendmes() {
+        let relays = relay_ids(&start_relays(3));
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relays).unwrap();
+        let port = start_tcp_server(|mut socket| {
+            let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+            let _ = std::io::Write::write_all(&mut socket, &data);
+        });
+        let stream_id = user
+            .begin_stream_on(
+                circuit_id,
+                BeginTarget::Host {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                },
+            )
+            .unwrap();
+
+        // nothing is read, so the exit stops once the stream window is used up
+        wait_until(|| user.get_state().stream_info[&stream_id].window.deliver == 0);
+        let received = user.get_state().stream_info[&stream_id].bytes_received;
+        thread::sleep(Duration::from_millis(500));
+        let state = user.get_state();
+        assert_eq!(state.stream_info[&stream_id].bytes_received, received);
+        assert!(received < 300_000);
+        // circuit SENDMEs are not held back by the reader
+        assert_eq!(
+            state.circuit_windows[&circuit_id].deliver,
+            CIRCUIT_WINDOW_START
+        );
+
+        let mut data = vec![];
+        while let Some(chunk) = user
+            .recv_stream(stream_id, Duration::from_secs(10))
+            .unwrap()
+        {
+            data.extend(chunk);
+        }
+        assert_eq!(data.len(), 300_000);
+        assert!(data
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == (i % 251) as u8));
+    }
+
+    #[test]
+    fn test_exit_acknowledges_data_once_written() {
+        let relays = start_relays(3);
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relay_ids(&relays))
+            .unwrap();
+        // accepts but never reads
+        let port = start_tcp_server(|_socket| thread::sleep(Duration::from_secs(60)));
+        let stream_id = user
+            .begin_stream_on(
+                circuit_id,
+                BeginTarget::Host {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                },
+            )
+            .unwrap();
+        let sender = user.clone();
+        thread::spawn(move || sender.send_stream_data(stream_id, vec![0; 8_000_000]));
+
+        // once the socket buffers are full nothing more is acknowledged
+        wait_until(|| user.get_state().stream_info[&stream_id].window.package == 0);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(user.get_state().stream_info[&stream_id].window.package, 0);
+        assert!(user.get_state().stream_info[&stream_id].bytes_sent < 8_000_000);
+
+        // the exit keeps serving other circuits meanwhile
+        let other = start_user("TestOtherUser");
+        other
+            .establish_circuit(CircuitId::new_v4(), relay_ids(&relays))
+            .unwrap();
+        user.end_stream(stream_id, EndReason::Done).unwrap();
+        wait_until(|| relays[2].get_state().exit_streams.is_empty());
+    }
+
+    #[test]
+    fn test_unexpected_sendme_closes_circuit() {
+        let relays = relay_ids(&start_relays(3));
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relays).unwrap();
+        let port = start_tcp_server(echo);
+        let stream_id = user
+            .begin_stream_on(
+                circuit_id,
+                BeginTarget::Host {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                },
+            )
+            .unwrap();
+
+        // the exit never sent anything on the stream to acknowledge
+        let sendme_payload = Payload::Sendme(SendmePayload {
+            stream_id: Some(stream_id),
+        });
+        user.send_to_hop(circuit_id, 2, &sendme_payload).unwrap();
+        wait_until(|| !user.get_state().circuits.contains_key(&circuit_id));
+        assert_eq!(
+            user.get_state().stream_info[&stream_id].status,
+            StreamStatus::Closed(EndReason::Destroy)
+        );
+    }
+
+    #[test]
+    fn test_ping_every_hop() {
+        let relays = relay_ids(&start_relays(3));
+        let user = start_user("TestUser");
+        let circuit_id = CircuitId::new_v4();
+        user.establish_circuit(circuit_id, relays.clone()).unwrap();
+        assert!(user.ping(circuit_id, 3).is_err());
+
+        assert_eq!(user.ping_circuit(circuit_id).unwrap().len(), 3);
+        user.ping(circuit_id, 2).unwrap();
+        let state = user.get_state();
+        let latency = &state.circuit_latency[&circuit_id];
+        assert_eq!(latency.hops.len(), 3);
+        assert_eq!(latency.hops[2].as_ref().unwrap().samples, 2);
+        assert!(latency.edge_latencies_ms().iter().all(Option::is_some));
+        assert!(relays
+            .iter()
+            .all(|relay| state.relay_latency.contains_key(relay)));
+
+        user.destroy_circuit(circuit_id, DestroyReason::Finished)
+            .unwrap();
+        assert!(!user.get_state().circuit_latency.contains_key(&circuit_id));
+    }
+
+    /// Joins `client` to `service` at a rendezvous point, introduced by
+    /// an introduction point of the service. Returns the rendezvous cookie.
+    fn join_users(service: &User, client: &User) -> RendezvousCookieId {
+        let relays = relay_ids(&start_relays(6));
+        let introduction_id = IntroductionPointId::new_v4();
+        let rendezvous_cookie = RendezvousCookieId::new_v4();
+
+        let introduction_circuit = CircuitId::new_v4();
+        service
+            .establish_circuit(introduction_circuit, vec![relays[0], relays[1], relays[2]])
+            .unwrap();
+        service
+            .send_establish_introduction(relays[0], introduction_id, introduction_circuit)
+            .unwrap();
+        let rendezvous_circuit = CircuitId::new_v4();
+        service
+            .establish_circuit(rendezvous_circuit, vec![relays[2], relays[1], relays[5]])
+            .unwrap();
+
+        let client_circuit = CircuitId::new_v4();
+        let stream_id = StreamId::new_v4();
+        client
+            .establish_circuit(client_circuit, vec![relays[3], relays[4], relays[5]])
+            .unwrap();
+        client
+            .send_establish_rendezvous(relays[3], rendezvous_cookie, client_circuit)
+            .unwrap();
+        client
+            .send_begin(relays[3], client_circuit, stream_id, relays[2])
+            .unwrap();
+        client
+            .listen_for_event(Event(PayloadType::Connected, relays[3], client_circuit))
+            .unwrap();
+        client
+            .send_introduce1(
+                relays[3],
+                IntroductionAuth {
+                    introduction_id,
+                    introduction_key: Directory::get_introduction_key(introduction_id).unwrap(),
+                },
+                stream_id,
+                rendezvous_cookie,
+                service.user_descriptor.rsa_public.clone(),
+                client_circuit,
+            )
+            .unwrap();
+        service
+            .listen_for_event(Event(
+                PayloadType::Introduce2,
+                relays[0],
+                introduction_circuit,
+            ))
+            .unwrap();
+        service
+            .send_rendezvous1(relays[2], rendezvous_cookie, rendezvous_circuit)
+            .unwrap();
+        client
+            .listen_for_event(Event(PayloadType::Rendezvous2, relays[3], client_circuit))
+            .unwrap();
+        rendezvous_cookie
+    }
+
+    #[test]
+    fn test_large_message_between_users() {
+        let service = start_user("TestService");
+        let client = start_user("TestClient");
+        let rendezvous_cookie = join_users(&service, &client);
+
+        // far more than fits in one cell
+        let message: Vec<u8> = (0..100_000).map(|i| b'a' + (i % 26) as u8).collect();
+        client
+            .send_message(rendezvous_cookie, message.clone())
+            .unwrap();
+        client
+            .send_message(rendezvous_cookie, b"done".to_vec())
+            .unwrap();
+        let received = Mutex::new(vec![]);
+        wait_until(|| {
+            let mut received = received.lock().unwrap();
+            received.extend(service.read_messages(rendezvous_cookie).unwrap());
+            received.len() == 2
+        });
+        assert_eq!(
+            received.into_inner().unwrap(),
+            vec![message, b"done".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_binary_messages_between_users() {
+        let service = start_user("TestService");
+        let client = start_user("TestClient");
+        let rendezvous_cookie = join_users(&service, &client);
+
+        let message = vec![0xff, 0x00, 0xfe];
+        client
+            .send_message(rendezvous_cookie, message.clone())
+            .unwrap();
+        let received = Mutex::new(vec![]);
+        wait_until(|| {
+            let mut received = received.lock().unwrap();
+            received.extend(service.read_messages(rendezvous_cookie).unwrap());
+            !received.is_empty()
+        });
+        assert_eq!(received.into_inner().unwrap(), vec![message.clone()]);
+
+        // the session keeps working after a message that is not UTF-8
+        let reply: Vec<u8> = message.iter().rev().copied().collect();
+        service
+            .send_message(rendezvous_cookie, reply.clone())
+            .unwrap();
+        let received = Mutex::new(vec![]);
+        wait_until(|| {
+            let mut received = received.lock().unwrap();
+            received.extend(client.read_messages(rendezvous_cookie).unwrap());
+            !received.is_empty()
+        });
+        assert_eq!(received.into_inner().unwrap(), vec![reply]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_session_streams() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let service = start_user("TestService");
+        let client = start_user("TestClient");
+        let (joined_service, joined_client) = (service.clone(), client.clone());
+        let rendezvous_cookie =
+            tokio::task::spawn_blocking(move || join_users(&joined_service, &joined_client))
+                .await
+                .unwrap();
+        let mut service_stream = service.open_session_stream(rendezvous_cookie).unwrap();
+        let mut client_stream = client.open_session_stream(rendezvous_cookie).unwrap();
+
+        client_stream.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        service_stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"ping");
+
+        // the client reads until the END of the service
+        service_stream.write_all(b"pong").await.unwrap();
+        service_stream.shutdown().await.unwrap();
+        let mut response = vec![];
+        client_stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong");
+        assert!(client
+            .send_message(rendezvous_cookie, b"late".to_vec())
+            .is_err());
+
+        // neither side keeps the key of an ended session
+        assert!(!service
+            .get_state()
+            .connected_users
+            .contains_key(&rendezvous_cookie));
+        assert!(!client
+            .get_state()
+            .connected_users
+            .contains_key(&rendezvous_cookie));
+    }
+
+    #[test]
+    fn test_replayed_introductions_are_dropped() {
+        let relays = start_relays(6);
+        let ids = relay_ids(&relays);
+        let service = start_user("TestReplayService");
+        let client = start_user("TestReplayClient");
+        let introduction_id = IntroductionPointId::new_v4();
+        let introduction_circuit = CircuitId::new_v4();
+        service
+            .establish_circuit(introduction_circuit, vec![ids[0], ids[1], ids[2]])
+            .unwrap();
+        service
+            .send_establish_introduction(ids[0], introduction_id, introduction_circuit)
+            .unwrap();
+        let introduction_key = Directory::get_introduction_key(introduction_id).unwrap();
+
+        let client_circuit = CircuitId::new_v4();
+        let stream_id = StreamId::new_v4();
+        client
+            .establish_circuit(client_circuit, vec![ids[3], ids[4], ids[5]])
+            .unwrap();
+        client
+            .send_begin(ids[3], client_circuit, stream_id, ids[2])
+            .unwrap();
+        client
+            .listen_for_event(Event(PayloadType::Connected, ids[3], client_circuit))
+            .unwrap();
+        let rsa_public = service.user_descriptor.rsa_public.clone();
+        let forged_cookie = RendezvousCookieId::new_v4();
+        let forged = client
+            .introduce1_payload(
+                &IntroductionAuth {
+                    introduction_id,
+                    introduction_key: generate_introduction_key(),
+                },
+                stream_id,
+                forged_cookie,
+                &rsa_public,
+                client_circuit,
+            )
+            .unwrap();
+        let rendezvous_cookie = RendezvousCookieId::new_v4();
+        let genuine = client
+            .introduce1_payload(
+                &IntroductionAuth {
+                    introduction_id,
+                    introduction_key,
+                },
+                stream_id,
+                rendezvous_cookie,
+                &rsa_public,
+                client_circuit,
+            )
+            .unwrap();
+        for introduce1_payload in [forged, genuine.clone(), genuine] {
+            client
+                .send_introduce1_payload(ids[3], client_circuit, introduce1_payload)
+                .unwrap();
+        }
+
+        // the introduction point drops the forged INTRODUCE1 and the replay
+        wait_until(|| relays[2].get_state().replayed_introductions == 1);
+        wait_until(|| {
+            service
+                .get_state()
+                .connected_users
+                .contains_key(&rendezvous_cookie)
+        });
+        let state = service.get_state();
+        assert!(!state.connected_users.contains_key(&forged_cookie));
+        assert_eq!(state.replayed_introductions, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_host_onion_service() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        start_relays(6);
+        let service_user = start_user("TestOnionService");
+        let client = start_user("TestOnionClient");
+        let host = service_user.clone();
+        let mut service = tokio::task::spawn_blocking(move || {
+            host.host_onion_service(OnionServiceConfig {
+                introduction_points: 2,
+                ..Default::default()
+            })
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let address = service.address();
+        let descriptor = Directory::get_user(address).unwrap();
+        let introduction_relays: HashSet<RelayId> =
+            descriptor.introduction_points.values().copied().collect();
+        assert_eq!(introduction_relays.len(), 2);
+        assert!(service_user.host_onion_service(Default::default()).is_err());
+
+        // introduce the client by hand through one of the introduction points
+        let (introduction_id, introduction_relay) = descriptor
+            .introduction_points
+            .iter()
+            .map(|(id, relay)| (*id, *relay))
+            .next()
+            .unwrap();
+        let rendezvous_cookie = RendezvousCookieId::new_v4();
+        let introducer = client.clone();
+        tokio::task::spawn_blocking(move || {
+            let (circuit_id, path) = introducer
+                .build_circuit_where(
+                    CircuitPurpose::Rendezvous,
+                    DEFAULT_CIRCUIT_LENGTH,
+                    &|relay| relay.id != introduction_relay,
+                )
+                .unwrap();
+            introducer
+                .send_establish_rendezvous(path[0], rendezvous_cookie, circuit_id)
+                .unwrap();
+            let stream_id = StreamId::new_v4();
+            introducer
+                .send_begin(path[0], circuit_id, stream_id, introduction_relay)
+                .unwrap();
+            introducer.wait_for_stream(stream_id, path[0]).unwrap();
+            introducer
+                .send_introduce1(
+                    path[0],
+                    IntroductionAuth {
+                        introduction_id,
+                        introduction_key: descriptor.introduction_keys[&introduction_id].clone(),
+                    },
+                    stream_id,
+                    rendezvous_cookie,
+                    descriptor.rsa_public,
+                    circuit_id,
+                )
+                .unwrap();
+            introducer
+                .listen_for_event(Event(PayloadType::Rendezvous2, path[0], circuit_id))
+                .unwrap();
+        })
+        .await
+        .unwrap();
+
+        let mut connection = service.accept().await.unwrap();
+        let mut client_stream = client.open_session_stream(rendezvous_cookie).unwrap();
+        client_stream.write_all(b"hello").await.unwrap();
+        let mut request = [0; 5];
+        connection.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"hello");
+
+        // dropping the service takes its introduction points down
+        drop(service);
+        wait_until(|| {
+            Directory::get_user(address)
+                .unwrap()
+                .introduction_points
+                .is_empty()
+        });
+        assert!(service_user
+            .host_onion_service(OnionServiceConfig {
+                introduction_points: 1,
+                ..Default::default()
+            })
+            .is_ok());
+    }
+
+    #[test]
+    fn test_pending_introductions_are_bounded() {
+        let service = start_user("TestOnionService");
+        let introductions: Vec<Introduction> = (0..3)
+            .map(|_| Introduction {
+                rendezvous_cookie: RendezvousCookieId::new_v4(),
+                rendezvous_point: RelayId::new_v4(),
+            })
+            .collect();
+        {
+            let mut internal_state_lock = service.internal_state.lock().unwrap();
+            internal_state_lock.hosting_onion_service = true;
+            for introduction in introductions.iter() {
+                internal_state_lock
+                    .connected_users
+                    .insert(introduction.rendezvous_cookie, vec![0; 32]);
+                internal_state_lock.introductions.push_back(*introduction);
+            }
+        }
+
+        // none is taken while every rendezvous slot is busy
+        let timeout = Duration::from_millis(10);
+        assert!(service
+            .wait_for_introductions(timeout, 0)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            service.wait_for_introductions(timeout, 2).unwrap(),
+            introductions[..2]
+        );
+
+        // the key of a client that is never met is forgotten
+        assert!(service.accept_introduction(introductions[0]).is_err());
+        assert!(!service
+            .get_state()
+            .connected_users
+            .contains_key(&introductions[0].rendezvous_cookie));
+        // and so are those of the introductions still waiting
+        service.stop_onion_service(&[]);
+        assert!(!service
+            .get_state()
+            .connected_users
+            .contains_key(&introductions[2].rendezvous_cookie));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_onion() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        start_relays(6);
+        let service_user = start_user("TestOnionService");
+        let client = start_user("TestOnionClient");
+        let host = service_user.clone();
+        let mut service = tokio::task::spawn_blocking(move || {
+            host.host_onion_service(OnionServiceConfig {
+                introduction_points: 1,
+                ..Default::default()
+            })
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let address = service.address();
+        // introduction points at relays that are gone are skipped
+        let mut descriptor = Directory::get_user(address).unwrap();
+        for _ in 0..3 {
+            descriptor
+                .introduction_points
+                .insert(IntroductionPointId::new_v4(), RelayId::new_v4());
+        }
+        Directory::publish_user(descriptor);
+
+        let connector = client.clone();
+        let mut connection = tokio::task::spawn_blocking(move || connector.connect_onion(address))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut accepted = service.accept().await.unwrap();
+        connection.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        accepted.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"ping");
+        accepted.write_all(b"pong").await.unwrap();
+        accepted.shutdown().await.unwrap();
+        let mut response = vec![];
+        connection.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"pong");
+
+        drop(service);
+        wait_until(|| {
+            Directory::get_user(address)
+                .unwrap()
+                .introduction_points
+                .is_empty()
+        });
+        assert!(client.connect_onion(address).is_err());
+        assert!(client.connect_onion(UserId::new_v4()).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restricted_onion_service() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        start_relays(6);
+        let service_user = start_user("TestRestrictedService");
+        let alice = start_user("TestAuthorizedClient");
+        let bob = start_user("TestRevokedClient");
+        let mallory = start_user("TestUnauthorizedClient");
+        let address = service_user.user_descriptor.id;
+        for (name, client) in [("alice", &alice), ("bob", &bob)] {
+            service_user
+                .add_authorized_client(
+                    name.to_string(),
+                    client.create_client_credentials(address).unwrap(),
+                )
+                .unwrap();
+        }
+        mallory.create_client_credentials(address).unwrap();
+        assert!(service_user
+            .add_authorized_client("eve".to_string(), b"not a key".to_vec())
+            .is_err());
+        let host = service_user.clone();
+        let mut service = tokio::task::spawn_blocking(move || {
+            host.host_onion_service(OnionServiceConfig {
+                introduction_points: 1,
+                ..Default::default()
+            })
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        // only the encrypted introduction points are published
+        let descriptor = Directory::get_user(address).unwrap();
+        assert!(descriptor.introduction_points.is_empty());
+        assert!(descriptor.introduction_keys.is_empty());
+        assert_eq!(
+            descriptor
+                .encrypted_introduction_points
+                .unwrap()
+                .client_keys
+                .len(),
+            2
+        );
+        assert!(service_user.get_state().restricted);
+        assert!(mallory.connect_onion(address).is_err());
+
+        let connector = bob.clone();
+        let mut connection = tokio::task::spawn_blocking(move || connector.connect_onion(address))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut accepted = service.accept().await.unwrap();
+        connection.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        accepted.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"ping");
+
+        // revoking bob moves the introduction points he knows
+        let known: HashSet<IntroductionPointId> = service_user
+            .get_state()
+            .introduction_points
+            .into_keys()
+            .collect();
+        service_user.remove_authorized_client("bob").unwrap();
+        assert!(service_user.remove_authorized_client("bob").is_err());
+        wait_until(|| {
+            let introduction_points = service_user.get_state().introduction_points;
+            !introduction_points.is_empty()
+                && introduction_points.keys().all(|id| !known.contains(id))
+        });
+        let connector = bob.clone();
+        assert!(
+            tokio::task::spawn_blocking(move || connector.connect_onion(address))
+                .await
+                .unwrap()
+                .is_err()
+        );
+        let connector = alice.clone();
+        let mut connection = tokio::task::spawn_blocking(move || connector.connect_onion(address))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut accepted = service.accept().await.unwrap();
+        connection.write_all(b"pong").await.unwrap();
+        accepted.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"pong");
+
+        // without authorized clients the service is unreachable, not public
+        service_user.remove_authorized_client("alice").unwrap();
+        let descriptor = Directory::get_user(address).unwrap();
+        assert!(descriptor.introduction_points.is_empty());
+        assert!(descriptor
+            .encrypted_introduction_points
+            .unwrap()
+            .client_keys
+            .is_empty());
+        let connector = alice.clone();
+        assert!(
+            tokio::task::spawn_blocking(move || connector.connect_onion(address))
+                .await
+                .unwrap()
+                .is_err()
+        );
+        mallory.remove_client_credentials(address).unwrap();
+        assert!(mallory.remove_client_credentials(address).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_introduction_point_maintenance() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        start_relays(6);
+        let service_user = start_user("TestOnionService");
+        let client = start_user("TestOnionClient");
+        let host = service_user.clone();
+        let mut service = tokio::task::spawn_blocking(move || {
+            host.host_onion_service(OnionServiceConfig {
+                introduction_points: 2,
+                ..Default::default()
+            })
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let address = service.address();
+
+        // a failed introduction circuit is replaced in the descriptor
+        let (failed_id, failed_relay) = Directory::get_user(address)
+            .unwrap()
+            .introduction_points
+            .into_iter()
+            .next()
+            .unwrap();
+        let (circuit_id, _) = service_user
+            .get_state()
+            .circuits
+            .into_iter()
+            .find(|(_, circuit)| circuit.last() == Some(&failed_relay))
+            .unwrap();
+        service_user
+            .destroy_circuit(circuit_id, DestroyReason::ChannelClosed)
+            .unwrap();
+        wait_until(|| {
+            let introduction_points = Directory::get_user(address).unwrap().introduction_points;
+            introduction_points.len() == 2 && !introduction_points.contains_key(&failed_id)
+        });
+
+        let connector = client.clone();
+        let mut connection = tokio::task::spawn_blocking(move || connector.connect_onion(address))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut accepted = service.accept().await.unwrap();
+        connection.write_all(b"ping").await.unwrap();
+        let mut request = [0; 4];
+        accepted.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"ping");
+        drop(service);
+
+        // introduction points are moved once they are older than the rotation
+        // period
+        let rotating_user = start_user("TestRotatingService");
+        let host = rotating_user.clone();
+        let service = tokio::task::spawn_blocking(move || {
+            host.host_onion_service(OnionServiceConfig {
+                introduction_points: 1,
+                rotation_period: Duration::from_secs(1),
+            })
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let address = service.address();
+        let original = Directory::get_user(address).unwrap().introduction_points;
+        wait_until(|| {
+            let introduction_points = Directory::get_user(address).unwrap().introduction_points;
+            introduction_points.len() == 1
+                && introduction_points
+                    .keys()
+                    .all(|id| !original.contains_key(id))
+        });
+    }
+}